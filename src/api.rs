@@ -0,0 +1,243 @@
+//! HELPER: Local HTTP status and control API
+//!
+//! Off by default, enable with `[api]` in the config or `--api`.  Every response is JSON.
+//! - `GET /status`: HV state, mute state, active event folder, transport connection, and queue depths
+//! - `GET /health`: the health of every running module, see `health`
+//! - `GET /topics`: the latest value received on each topic
+//! - `GET /metrics`: daemon internals for Prometheus, see `metrics`
+//! - `POST /upload/logger`, `/upload/video`, `/upload/serial`: upload to Scylla, like `SEND_LOGGER_DATA` etc.
+//!   Refused while HV is on, as the files are still being written, or while another upload runs.
+
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::{StatusCode, header},
+    routing::{get, post},
+};
+use serde::Serialize;
+use serde_json::{Value, json};
+use tokio::{net::TcpListener, sync::broadcast, task::JoinHandle};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, info, warn};
+
+use crate::{
+    HVTransition,
+    health::HealthBoard,
+    metrics::METRICS,
+    module::{ModuleChannels, QueueDepths},
+    playback_data::PlaybackData,
+    uploader::upload_files,
+};
+
+/// The latest value of a topic
+#[derive(Debug, Clone, Serialize)]
+struct TopicValue {
+    values: Vec<f32>,
+    unit: String,
+    time_us: u64,
+}
+
+type LatestValues = Arc<Mutex<BTreeMap<String, TopicValue>>>;
+
+#[derive(Clone)]
+struct ApiState {
+    channels: ModuleChannels,
+    health: HealthBoard,
+    latest: LatestValues,
+    scylla_url: Option<String>,
+    output_folder: PathBuf,
+    /// The last upload started, so only one runs at a time
+    upload: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+#[derive(Serialize)]
+struct Status {
+    hv_on: bool,
+    /// The folder of the current event, None while HV is off
+    event_folder: Option<String>,
+    muted: bool,
+    transport_connected: bool,
+    queues: QueueDepths,
+}
+
+#[derive(Serialize)]
+struct HealthStatus {
+    state: &'static str,
+    /// None if the module never did useful work
+    last_work_us: Option<u64>,
+    errors: u32,
+}
+
+/// Serve the API until cancelled
+pub async fn api_server(
+    task_tracker: TaskTracker,
+    cancel_token: CancellationToken,
+    address: String,
+    channels: ModuleChannels,
+    health: HealthBoard,
+    scylla_url: Option<String>,
+    output_folder: PathBuf,
+) {
+    let listener = match TcpListener::bind(&address).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            warn!("Could not bind API to {}: {}", address, err);
+            return;
+        }
+    };
+
+    let latest = LatestValues::default();
+    task_tracker.spawn(track_latest(
+        cancel_token.clone(),
+        channels.received(),
+        latest.clone(),
+    ));
+
+    let app = Router::new()
+        .route("/status", get(get_status))
+        .route("/health", get(get_health))
+        .route("/topics", get(get_topics))
+        .route("/metrics", get(get_metrics))
+        .route("/upload/{kind}", post(post_upload))
+        .with_state(ApiState {
+            channels,
+            health,
+            latest,
+            scylla_url,
+            output_folder,
+            upload: Arc::default(),
+        });
+
+    info!("Serving API on {}", address);
+    if let Err(err) = axum::serve(listener, app)
+        .with_graceful_shutdown(cancel_token.cancelled_owned())
+        .await
+    {
+        warn!("API server stopped: {}", err);
+    }
+    debug!("Shutting down API");
+}
+
+/// Keep the latest value of every received topic
+async fn track_latest(
+    cancel_token: CancellationToken,
+    mut recv: broadcast::Receiver<PlaybackData>,
+    latest: LatestValues,
+) {
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            msg = recv.recv() => match msg {
+                // a late value is not the latest
+                Ok(msg) if msg.backfilled => {}
+                Ok(msg) => {
+                    latest.lock().unwrap().insert(msg.topic, TopicValue {
+                        values: msg.values,
+                        unit: msg.unit,
+                        time_us: msg.time_us,
+                    });
+                }
+                Err(broadcast::error::RecvError::Lagged(cnt)) => {
+                    debug!("API lagged behind {} messages", cnt);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+async fn get_status(State(state): State<ApiState>) -> Json<Status> {
+    let event_folder = match &*state.channels.hv().borrow() {
+        HVTransition::TransitionOn(session) => Some(session.dir().display().to_string()),
+        HVTransition::TransitionOff => None,
+    };
+    Json(Status {
+        hv_on: event_folder.is_some(),
+        event_folder,
+        muted: *state.channels.mute().borrow(),
+        transport_connected: *state.channels.connected().borrow(),
+        queues: state.channels.queue_depths(),
+    })
+}
+
+async fn get_health(State(state): State<ApiState>) -> Json<BTreeMap<&'static str, HealthStatus>> {
+    Json(
+        state
+            .health
+            .modules()
+            .into_iter()
+            .map(|(name, health)| {
+                (
+                    name,
+                    HealthStatus {
+                        state: health.state().as_str(),
+                        last_work_us: health.last_work_us(),
+                        errors: health.errors(),
+                    },
+                )
+            })
+            .collect(),
+    )
+}
+
+async fn get_topics(State(state): State<ApiState>) -> Json<BTreeMap<String, TopicValue>> {
+    Json(state.latest.lock().unwrap().clone())
+}
+
+async fn get_metrics(
+    State(state): State<ApiState>,
+) -> ([(header::HeaderName, &'static str); 1], String) {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.render(&state.channels.queue_depths()),
+    )
+}
+
+async fn post_upload(
+    State(state): State<ApiState>,
+    Path(kind): Path<String>,
+) -> (StatusCode, Json<Value>) {
+    let (logs, video, serial) = match kind.as_str() {
+        "logger" => (true, false, false),
+        "video" => (false, true, false),
+        "serial" => (false, false, true),
+        _ => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(
+                    json!({"error": format!("Unknown upload {kind}, use logger, video, or serial")}),
+                ),
+            );
+        }
+    };
+    let Some(url) = &state.scylla_url else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({"error": "No Scylla URL configured"})),
+        );
+    };
+    if matches!(*state.channels.hv().borrow(), HVTransition::TransitionOn(_)) {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({"error": "Cannot upload while HV is on"})),
+        );
+    }
+
+    let mut upload = state.upload.lock().unwrap();
+    if upload.as_ref().is_some_and(|handle| !handle.is_finished()) {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({"error": "An upload is already running"})),
+        );
+    }
+
+    info!("Sending {} data from API", kind);
+    *upload = Some(upload_files(&state.output_folder, url, logs, video, serial));
+    (StatusCode::ACCEPTED, Json(json!({"uploading": kind})))
+}
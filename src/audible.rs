@@ -6,27 +6,76 @@
 
 use std::error::Error;
 
-use tokio::{process::Command, sync::watch::Receiver};
+use futures_util::future::BoxFuture;
+use tokio::sync::watch::Receiver;
 use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+use crate::{
+    health::{ModuleHealth, ModuleState},
+    module::{Module, ModuleContext, ModuleInputs, ModuleResult},
+    sim,
+};
+
+/// The audio module, see `audible_manager`
+pub struct AudibleModule;
+
+impl Module for AudibleModule {
+    fn name(&self) -> &'static str {
+        "Audible"
+    }
+
+    fn inputs(&self) -> ModuleInputs {
+        ModuleInputs {
+            mute: true,
+            ..Default::default()
+        }
+    }
+
+    fn run(&self, mut ctx: ModuleContext) -> BoxFuture<'static, ModuleResult> {
+        Box::pin(
+            async move { audible_manager(ctx.cancel_token.clone(), ctx.mute()?, ctx.health).await },
+        )
+    }
+
+    /// never leave the driver muted
+    fn shutdown(&self) -> BoxFuture<'static, ()> {
+        Box::pin(async {
+            let res = match sim::command("linphonecsh")
+                .args(["generic", "unmute"])
+                .spawn()
+            {
+                Ok(mut child) => child.wait().await.map(|_| ()),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = res {
+                warn!("Could not unmute on shutdown: {}", err);
+            }
+        })
+    }
+}
 
 /// runs the mute/unmute functionality
 pub async fn audible_manager(
     cancel_token: CancellationToken,
     mut mute_stat_recv: Receiver<bool>,
+    health: ModuleHealth,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
+    health.set_state(ModuleState::Running);
     loop {
         tokio::select! {
             _ = cancel_token.cancelled() => {
-                    Command::new("linphonecsh").args(["generic", "unmute"]).spawn()?.wait().await?;
+                break Ok(());
             },
             new = mute_stat_recv.changed() => {
                 new?;
                 // to mute or not
                 if *mute_stat_recv.borrow_and_update() {
-                    Command::new("linphonecsh").args(["generic", "mute"]).spawn()?.wait().await?;
+                    sim::command("linphonecsh").args(["generic", "mute"]).spawn()?.wait().await?;
                 } else {
-                    Command::new("linphonecsh").args(["generic", "unmute"]).spawn()?.wait().await?;
+                    sim::command("linphonecsh").args(["generic", "unmute"]).spawn()?.wait().await?;
                 }
+                health.work();
             }
         }
     }
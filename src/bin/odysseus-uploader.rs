@@ -1,3 +1,5 @@
+use std::path::Path;
+
 use clap::Parser;
 use odysseus_daemon::uploader::upload_files;
 
@@ -31,7 +33,7 @@ async fn main() {
     let cli = UploaderArgs::parse();
 
     let thread = upload_files(
-        &cli.output_folder,
+        Path::new(&cli.output_folder),
         &cli.scylla_url,
         cli.send_logger,
         cli.send_video,
@@ -7,17 +7,67 @@ use std::process::Stdio;
 use std::time::UNIX_EPOCH;
 
 use crate::PublishableMessage;
-use tokio::{io::AsyncBufReadExt, sync::mpsc::Sender};
+use crate::health::ModuleHealth;
+use crate::module::{Module, ModuleContext, ModuleInputs, ModuleResult};
+use crate::publish::Publisher;
+use crate::sim;
+use futures_util::future::BoxFuture;
+use tokio::io::AsyncBufReadExt;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, trace, warn};
 
+/// The CAN statistics module, see `can_data_scraper`
+pub struct CanModule {
+    base_name: String,
+    can_iface: String,
+}
+
+impl CanModule {
+    pub fn new(base_name: String, can_iface: String) -> Self {
+        Self {
+            base_name,
+            can_iface,
+        }
+    }
+}
+
+impl Module for CanModule {
+    fn name(&self) -> &'static str {
+        "Can"
+    }
+
+    fn inputs(&self) -> ModuleInputs {
+        ModuleInputs {
+            publish: true,
+            ..Default::default()
+        }
+    }
+
+    fn run(&self, mut ctx: ModuleContext) -> BoxFuture<'static, ModuleResult> {
+        let base_name = self.base_name.clone();
+        let can_iface = self.can_iface.clone();
+        Box::pin(async move {
+            can_data_scraper(
+                ctx.cancel_token.clone(),
+                ctx.publisher()?,
+                base_name,
+                can_iface,
+                ctx.health,
+            )
+            .await;
+            Ok(())
+        })
+    }
+}
+
 pub async fn can_data_scraper(
     cancel_token: CancellationToken,
-    mqtt_sender_tx: Sender<PublishableMessage>,
+    mqtt_sender_tx: Publisher,
     base_name: String,
     can_iface: String,
+    health: ModuleHealth,
 ) {
-    let mut proc = tokio::process::Command::new("canbusload");
+    let mut proc = sim::command("canbusload");
     let proc = proc
         .args([format!("{can_iface}@500000")])
         .stdout(Stdio::piped());
@@ -52,6 +102,7 @@ pub async fn can_data_scraper(
                 if !line.is_empty() {
                     let sends = get_stats( line, &frames_topic, &bits_topic, &util_topic).await;
                     handle_sends(sends, &mqtt_sender_tx).await;
+                    health.work();
                 }
             }
         }
@@ -75,6 +126,7 @@ async fn get_stats(
             data: vec![fl],
             unit: "frames/s".to_string(),
             time: UNIX_EPOCH.elapsed().unwrap().as_micros() as u64,
+            options: None,
         });
     }
     if let Some(res) = parts.get(2)
@@ -85,6 +137,7 @@ async fn get_stats(
             data: vec![fl],
             unit: "bits/s".to_string(),
             time: UNIX_EPOCH.elapsed().unwrap().as_micros() as u64,
+            options: None,
         });
     }
     if let Some(res) = parts.last() {
@@ -95,6 +148,7 @@ async fn get_stats(
                 data: vec![fl / 100f32],
                 unit: "%".to_string(),
                 time: UNIX_EPOCH.elapsed().unwrap().as_micros() as u64,
+                options: None,
             });
         }
     }
@@ -102,10 +156,7 @@ async fn get_stats(
     ret
 }
 
-async fn handle_sends(
-    send_list: Vec<PublishableMessage>,
-    mqtt_sender_tx: &Sender<PublishableMessage>,
-) {
+async fn handle_sends(send_list: Vec<PublishableMessage>, mqtt_sender_tx: &Publisher) {
     for item in send_list.into_iter() {
         if let Err(err) = mqtt_sender_tx.send(item).await {
             warn!("Error putting message in MQTT queue for net: {} ", err);
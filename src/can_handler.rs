@@ -1,18 +1,25 @@
 //! HELPER: Sends CAN messages
-//! Unused
+//!
+//! The socket is opened on the first frame, so a host without CAN is fine until a module sends one.
 
-use tokio::sync::mpsc::Receiver;
+use std::time::Duration;
+
+use tokio::{sync::mpsc::Receiver, time::Instant};
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, warn};
 
 use socketcan::{CanFrame, tokio::CanSocket};
 
+/// How long to drop frames after the socket could not be opened, before trying again
+const REOPEN_DELAY: Duration = Duration::from_secs(5);
+
 pub async fn can_handler(
     cancel_token: CancellationToken,
     can_interface: String,
     mut can_recv: Receiver<CanFrame>,
 ) {
-    let socket = CanSocket::open(&can_interface).expect("Failed to open CAN socket");
+    let mut socket = None;
+    let mut next_open = Instant::now();
 
     loop {
         tokio::select! {
@@ -21,6 +28,18 @@ pub async fn can_handler(
                     break;
                 },
             Some(frame) = can_recv.recv() => {
+                if socket.is_none() && Instant::now() >= next_open {
+                    match CanSocket::open(&can_interface) {
+                        Ok(opened) => socket = Some(opened),
+                        Err(err) => {
+                            warn!("Could not open CAN socket {}, dropping frames: {}", can_interface, err);
+                            next_open = Instant::now() + REOPEN_DELAY;
+                        }
+                    }
+                }
+                let Some(socket) = &socket else {
+                    continue;
+                };
                 match socket.write_frame(frame).await {
                     Ok(_) => (),
                     Err(r) => warn!("Could not send CAN frame: {}", r),
@@ -17,6 +17,7 @@
 //! Adding a new follower (so the lights follow a specific pattern)
 //! 1. Add it to `FollowerItemSettings::from_idex`
 
+use futures_util::future::BoxFuture;
 use palette::{Hsv, IntoColor, LinSrgb, RgbHue, Srgb};
 use std::fmt::Debug;
 use std::time::Duration;
@@ -26,7 +27,13 @@ use tokio::sync::broadcast;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, info, trace, warn};
 
-use crate::playback_data::PlaybackData;
+use crate::{
+    health::ModuleHealth,
+    metrics::METRICS,
+    module::{Module, ModuleContext, ModuleInputs, ModuleResult},
+    playback_data::PlaybackData,
+    sim,
+};
 
 /// the number of leds
 const LED_BANK_SIZE_REAL: usize = 9;
@@ -79,7 +86,7 @@ struct FollowerItemSettings {
 }
 
 impl FollowerItemSettings {
-    // ADD NEW FOLLOWER HERE
+    // ADD NEW FOLLOWER HERE, and to RECEIVED_TOPICS
     fn from_idex(idex: usize) -> Self {
         match idex {
             0 => FollowerItemSettings {
@@ -170,7 +177,7 @@ impl WheelMode {
 }
 
 /// The registers to write to and how many 8 bit values they take up
-const LED_BANK_WRITE_LISTINGS: [(&str, usize); LED_BANK_SIZE_FUCKED] = [
+pub(crate) const LED_BANK_WRITE_LISTINGS: [(&str, usize); LED_BANK_SIZE_FUCKED] = [
     ("/sys/class/leds/l0:1/", 2),
     ("/sys/class/leds/l2:4/", 3),
     ("/sys/class/leds/l5:6/", 2),
@@ -346,6 +353,15 @@ fn calculate_settings(mode: &mut WheelMode, last_settings: &Settings) -> Option<
     }
 }
 
+/// Every topic followed in `FollowerItemSettings::from_idex` or handled in `handle_recv_msg`
+const RECEIVED_TOPICS: [&str; 5] = [
+    "BMS/Status/Temp_Internal",
+    "VCU/CarState/Speed",
+    "NERO/FlappyBirdScore",
+    "NERO/Control/LEDBrightness",
+    "NERO/Control/Mode",
+];
+
 /// Handle recieving a MQTT message, which could either do nothing or
 /// mutate the brightness or mode.
 ///
@@ -395,16 +411,43 @@ fn handle_recv_msg(msg: PlaybackData, brightness: &mut u8, mode: &mut WheelMode)
     }
 }
 
+/// The wheel LED module, see `color_controller`
+pub struct ColorModule;
+
+impl Module for ColorModule {
+    fn name(&self) -> &'static str {
+        "Color"
+    }
+
+    fn inputs(&self) -> ModuleInputs {
+        ModuleInputs {
+            received: true,
+            ..Default::default()
+        }
+    }
+
+    fn topics(&self) -> Vec<String> {
+        RECEIVED_TOPICS.map(String::from).to_vec()
+    }
+
+    fn run(&self, mut ctx: ModuleContext) -> BoxFuture<'static, ModuleResult> {
+        Box::pin(async move {
+            color_controller(ctx.cancel_token.clone(), ctx.received()?, ctx.health).await;
+            Ok(())
+        })
+    }
+}
+
 pub async fn color_controller(
     cancel_token: CancellationToken,
     mut mqtt_recv_rx: broadcast::Receiver<PlaybackData>,
+    health: ModuleHealth,
 ) {
     // cache the paths for quick reuse here, because building a Path is zero cost but also I am afraid
-    let path_cache: [PathBuf; LED_BANK_SIZE_FUCKED] = LED_BANK_WRITE_LISTINGS.map(|f| {
-        PathBuf::from_str(&format!("{}multi_intensity", f.0)).expect("Could not parse path")
-    });
-    let brightness_cache: [PathBuf; LED_BANK_SIZE_FUCKED] = LED_BANK_WRITE_LISTINGS
-        .map(|f| PathBuf::from_str(&format!("{}brightness", f.0)).expect("Could not parse path"));
+    let path_cache: [PathBuf; LED_BANK_SIZE_FUCKED] =
+        LED_BANK_WRITE_LISTINGS.map(|f| sim::sys_path(&format!("{}multi_intensity", f.0)));
+    let brightness_cache: [PathBuf; LED_BANK_SIZE_FUCKED] =
+        LED_BANK_WRITE_LISTINGS.map(|f| sim::sys_path(&format!("{}brightness", f.0)));
 
     // smaller than fastest human color change (about 13ms)
     let mut tick_size = tokio::time::interval(CALC_CYCLE_TIME);
@@ -450,8 +493,21 @@ pub async fn color_controller(
                         last_settings = settings;
                     }
                 }
+                health.work();
             },
-            Ok(msg) = mqtt_recv_rx.recv() => {
+            msg = mqtt_recv_rx.recv() => {
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(broadcast::error::RecvError::Lagged(cnt)) => {
+                        debug!("Color controller lagged behind {} messages", cnt);
+                        METRICS.lagged("Color", cnt);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        warn!("Received channel closed, stopping color controller");
+                        break;
+                    }
+                };
                 if handle_recv_msg(msg, &mut current_brightness, &mut current_mode) {
                     last_settings = [
                         Hsv::new(0.0, 0.0, 0.0),
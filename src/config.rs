@@ -0,0 +1,908 @@
+//! HELPER: Daemon configuration file
+//!
+//! The whole daemon can be configured from a single TOML or JSON5 file (passed with `--config`),
+//! which has one section per module.  Every module section has at least an `enable` flag.
+//! Values given on the command line or in the environment are applied on top of the file,
+//! and `--print-config` dumps the effective merged result.
+//!
+//! On SIGHUP the file is read again, and only the modules whose section changed are restarted.
+//! The general, log, MQTT, Zenoh, routing, publish queue, spool, and API settings cannot change without restarting the daemon.
+
+use std::{collections::BTreeMap, error::Error, path::Path, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{mqtt_handler, remap, topic};
+
+/// The full daemon configuration, one section per module
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    pub general: GeneralConfig,
+    pub log: LogConfig,
+    pub mqtt: MqttConfig,
+    pub zenoh: ZenohConfig,
+    pub routing: RoutingConfig,
+    pub api: ApiConfig,
+    pub publish: PublishConfig,
+    pub spool: SpoolConfig,
+    pub lockdown: LockdownConfig,
+    pub audible: AudibleConfig,
+    pub data: DataConfig,
+    pub daq: DaqConfig,
+    pub logger: LoggerConfig,
+    pub video: VideoConfig,
+    pub sys: SysConfig,
+    pub color: ColorConfig,
+    pub gps: GpsConfig,
+    pub net: NetConfig,
+    pub halow: HalowConfig,
+    pub can: CanConfig,
+    pub storage: StorageConfig,
+}
+
+/// Settings shared by the whole daemon
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeneralConfig {
+    /// The output folder of data (videos, audio, text logs, etc), no trailing slash
+    pub output_folder: String,
+    /// The base MQTT topic/node name (for net, halow, can), a single topic level
+    pub base_node: String,
+    /// Augment HV on
+    pub augment_hv: bool,
+    /// The Scylla URL
+    pub scylla_url: Option<String>,
+    /// The SocketCAN interface port
+    pub socketcan_iface: String,
+    /// Run every module against simulated hardware, see `sim`
+    pub sim: bool,
+}
+
+impl Default for GeneralConfig {
+    fn default() -> Self {
+        Self {
+            output_folder: String::new(),
+            base_node: "TPU".to_string(),
+            augment_hv: false,
+            scylla_url: None,
+            socketcan_iface: "vcan0".to_string(),
+            sim: false,
+        }
+    }
+}
+
+/// The daemon's own logs, see `daemon_log`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Log to stdout as JSON lines instead of text
+    pub json: bool,
+    /// Also log to `<output_folder>/logs/daemon.log` as JSON lines
+    pub file: bool,
+    /// The size the log file is rotated at
+    pub file_max_mb: u64,
+    /// How many rotated log files to keep
+    pub file_count: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            json: false,
+            file: false,
+            file_max_mb: 10,
+            file_count: 5,
+        }
+    }
+}
+
+/// MQTT/Siren connection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    /// Use MQTT, alongside Zenoh if both are enabled
+    pub enable: bool,
+    /// The MQTT/Siren URL, `host:port`, or `mqtt://` and `mqtts://` for TLS
+    pub url: String,
+    /// The CA certificate (PEM) to verify the broker with over TLS, else the system roots
+    pub ca: Option<PathBuf>,
+    /// The client certificate (PEM) to authenticate with over TLS, with `client_key`
+    pub client_cert: Option<PathBuf>,
+    /// The private key (PEM) of `client_cert`
+    pub client_key: Option<PathBuf>,
+    /// The username to log in with
+    pub username: Option<String>,
+    /// A file holding the password, ODYSSEUS_DAEMON_MQTT_PASSWORD takes precedence
+    pub password_file: Option<PathBuf>,
+    /// How topics are published, the first matching rule wins, else QoS 2 as before rules.
+    /// If none are given, filled in from the base node by `DaemonConfig::fill_defaults`
+    pub rules: Vec<MqttRule>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            url: "localhost:1883".to_string(),
+            ca: None,
+            client_cert: None,
+            client_key: None,
+            username: None,
+            password_file: None,
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl MqttConfig {
+    /// The rules of a base node when none are given
+    fn default_rules(base_node: &str) -> Vec<MqttRule> {
+        vec![
+            MqttRule {
+                filter: format!("{base_node}/Daemon/#"),
+                qos: Qos::ExactlyOnce,
+                retain: false,
+                expiry_secs: None,
+            },
+            // sensor values sent again soon anyway
+            MqttRule {
+                filter: format!("{base_node}/#"),
+                qos: Qos::AtMostOnce,
+                retain: false,
+                expiry_secs: None,
+            },
+        ]
+    }
+
+    /// How a topic is published
+    pub fn options(&self, topic: &str) -> PublishOptions {
+        self.rules
+            .iter()
+            .find(|rule| topic::matches(&rule.filter, topic))
+            .map(|rule| PublishOptions {
+                qos: rule.qos,
+                retain: rule.retain,
+                expiry_secs: rule.expiry_secs,
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// A MQTT quality of service level, 0, 1, or 2 in the config
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum Qos {
+    AtMostOnce,
+    AtLeastOnce,
+    #[default]
+    ExactlyOnce,
+}
+
+impl TryFrom<u8> for Qos {
+    type Error = String;
+
+    fn try_from(qos: u8) -> Result<Self, Self::Error> {
+        match qos {
+            0 => Ok(Qos::AtMostOnce),
+            1 => Ok(Qos::AtLeastOnce),
+            2 => Ok(Qos::ExactlyOnce),
+            qos => Err(format!("Invalid QoS {qos}, must be 0, 1, or 2")),
+        }
+    }
+}
+
+impl From<Qos> for u8 {
+    fn from(qos: Qos) -> Self {
+        qos as u8
+    }
+}
+
+/// How a message is published over MQTT
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PublishOptions {
+    pub qos: Qos,
+    pub retain: bool,
+    /// The MQTT v5 message expiry, never if none
+    pub expiry_secs: Option<u32>,
+}
+
+/// How topics matching a filter are published over MQTT
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttRule {
+    /// A MQTT style filter, with `+` and `#` wildcards
+    pub filter: String,
+    #[serde(default)]
+    pub qos: Qos,
+    #[serde(default)]
+    pub retain: bool,
+    /// The MQTT v5 message expiry, never if none
+    pub expiry_secs: Option<u32>,
+}
+
+/// Zenoh connection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ZenohConfig {
+    /// Use Zenoh, alongside MQTT if both are enabled
+    pub enable: bool,
+    /// The Zenoh configuration file
+    pub conf: PathBuf,
+    pub remap: RemapConfig,
+    /// Answer `get` on `<lvc_key>/**` with the latest value of every key put, see `lvc`
+    pub lvc: bool,
+    pub lvc_key: String,
+    /// How topics are published, the first matching rule wins, else at data priority.
+    /// If none are given, filled in from the base node by `DaemonConfig::fill_defaults`
+    pub rules: Vec<ZenohRule>,
+    /// Publish `<base_node>/Link/PeerAlive/<node>` as other daemons come and go
+    pub peer_alive: bool,
+}
+
+impl Default for ZenohConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            conf: PathBuf::from("./zenoh.json5"),
+            remap: RemapConfig::default(),
+            lvc: false,
+            lvc_key: "tpu/lvc".to_string(),
+            rules: Vec::new(),
+            peer_alive: false,
+        }
+    }
+}
+
+impl ZenohConfig {
+    /// The rules of a base node when none are given
+    fn default_rules(base_node: &str) -> Vec<ZenohRule> {
+        vec![
+            ZenohRule {
+                filter: crate::HV_EN_TOPIC.to_string(),
+                priority: Priority::RealTime,
+                congestion_control: CongestionControl::Block,
+                express: true,
+            },
+            ZenohRule {
+                filter: "+/Faults/#".to_string(),
+                priority: Priority::RealTime,
+                congestion_control: CongestionControl::Block,
+                express: true,
+            },
+            // bulk telemetry, which must not hold up the rest
+            ZenohRule {
+                filter: format!("{base_node}/OnBoard/#"),
+                priority: Priority::Background,
+                congestion_control: CongestionControl::Drop,
+                express: false,
+            },
+            ZenohRule {
+                filter: "SYS_tpu/#".to_string(),
+                priority: Priority::Background,
+                congestion_control: CongestionControl::Drop,
+                express: false,
+            },
+        ]
+    }
+
+    /// How a topic is published
+    pub fn options(&self, topic: &str) -> ZenohOptions {
+        self.rules
+            .iter()
+            .find(|rule| topic::matches(&rule.filter, topic))
+            .map(|rule| ZenohOptions {
+                priority: rule.priority,
+                congestion_control: rule.congestion_control,
+                express: rule.express,
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// A Zenoh priority, highest first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Priority {
+    RealTime,
+    InteractiveHigh,
+    InteractiveLow,
+    DataHigh,
+    #[default]
+    Data,
+    DataLow,
+    Background,
+}
+
+/// What Zenoh does with a message when the link is congested
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CongestionControl {
+    #[default]
+    Drop,
+    Block,
+}
+
+/// How a message is published over Zenoh
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ZenohOptions {
+    pub priority: Priority,
+    pub congestion_control: CongestionControl,
+    /// Sent at once rather than batched
+    pub express: bool,
+}
+
+/// How topics matching a filter are published over Zenoh
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZenohRule {
+    /// A MQTT style filter, with `+` and `#` wildcards
+    pub filter: String,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub congestion_control: CongestionControl,
+    #[serde(default)]
+    pub express: bool,
+}
+
+/// How topics map to Zenoh keys and back, see `remap`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemapConfig {
+    /// Only topics under this prefix go to Zenoh, without it, ending in `/`
+    pub strip_prefix: Option<String>,
+    /// Added to every Zenoh key, ending in `/`
+    pub add_prefix: Option<String>,
+    /// MQTT style filters of topics never sent to or taken from Zenoh
+    pub drop: Vec<String>,
+    /// Regex replacements on the topic, in order
+    pub rules: Vec<RewriteRule>,
+}
+
+/// A regex replacement on topics going one way, see `remap`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RewriteRule {
+    pub pattern: String,
+    /// The replacement, with `$1` or `${name}` for captures
+    pub replace: String,
+    pub to: RewriteTo,
+}
+
+/// Which way a rewrite rule applies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RewriteTo {
+    /// On MQTT topics before they become Zenoh keys
+    Zenoh,
+    /// On topics made from Zenoh keys
+    Mqtt,
+}
+
+/// Which transports a topic is published to, see `dual`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Route {
+    Mqtt,
+    Zenoh,
+    #[default]
+    Both,
+}
+
+/// A topic filter and where matching topics go
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteRule {
+    /// A MQTT style filter, with `+` and `#` wildcards
+    pub filter: String,
+    pub to: Route,
+}
+
+/// Routing between MQTT and Zenoh when both are enabled, see `dual`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutingConfig {
+    /// Where topics matching no rule go
+    pub default: Route,
+    /// The first matching rule decides
+    pub rules: Vec<RouteRule>,
+    /// Forward messages received on one transport to the other, if routed there
+    pub bridge: bool,
+    /// How long a message is remembered, to drop it when it arrives again on the other transport
+    pub dedup_ms: u64,
+}
+
+impl Default for RoutingConfig {
+    fn default() -> Self {
+        Self {
+            default: Route::Both,
+            rules: Vec::new(),
+            bridge: false,
+            dedup_ms: 2000,
+        }
+    }
+}
+
+impl RoutingConfig {
+    /// Where a topic goes
+    pub fn route(&self, topic: &str) -> Route {
+        self.rules
+            .iter()
+            .find(|rule| topic::matches(&rule.filter, topic))
+            .map(|rule| rule.to)
+            .unwrap_or(self.default)
+    }
+}
+
+/// See `api`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    pub enable: bool,
+    /// The address to listen on, the default only allows local connections
+    pub bind: String,
+    pub port: u16,
+    /// MQTT style filters of the topics whose latest value is served on `/topics`
+    pub topics: Vec<String>,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            bind: "127.0.0.1".to_string(),
+            port: 8080,
+            topics: vec!["#".to_string()],
+        }
+    }
+}
+
+/// What a module does when the publish queue is full, see `publish`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PublishPolicy {
+    /// Wait for room
+    #[default]
+    Block,
+    /// Drop the oldest queued message of the module
+    DropOldest,
+    /// Drop the message being sent
+    DropNewest,
+    /// Replace a queued message of the module on the same topic, else drop the oldest
+    Coalesce,
+}
+
+/// The outgoing publish queue, see `publish`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PublishConfig {
+    /// How many messages the queue holds
+    pub capacity: usize,
+    /// The policy of modules not in `sources`
+    pub default: PublishPolicy,
+    /// The policy of each module, by module name
+    pub sources: BTreeMap<String, PublishPolicy>,
+}
+
+impl Default for PublishConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1000,
+            default: PublishPolicy::Block,
+            sources: BTreeMap::from([
+                ("DAQ".to_string(), PublishPolicy::DropOldest),
+                ("GPS".to_string(), PublishPolicy::DropOldest),
+                ("Halow".to_string(), PublishPolicy::Coalesce),
+                ("Net".to_string(), PublishPolicy::Coalesce),
+            ]),
+        }
+    }
+}
+
+impl PublishConfig {
+    /// The policy of a module
+    pub fn policy(&self, source: &str) -> PublishPolicy {
+        self.sources.get(source).copied().unwrap_or(self.default)
+    }
+}
+
+/// Spooling of outgoing messages to disk while disconnected, see `spool`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpoolConfig {
+    pub enable: bool,
+    /// The most disk space the spool takes, dropping the oldest messages past it
+    pub max_mb: u64,
+    /// The most spooled messages replayed a second once connected
+    pub replay_rate: u32,
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            max_mb: 100,
+            replay_rate: 200,
+        }
+    }
+}
+
+/// When a stopped module is restarted, see `supervisor`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    /// Restart whenever the module returns or panics
+    #[default]
+    Always,
+    /// Restart only when the module returns an error or panics
+    OnFailure,
+    /// Never restart the module
+    Never,
+}
+
+/// Per module restart settings
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RestartConfig {
+    pub policy: RestartPolicy,
+    /// The first delay before a restart, doubled on every restart after
+    pub backoff_initial_ms: u64,
+    /// The longest delay before a restart
+    pub backoff_max_ms: u64,
+    /// The most restarts allowed within `restart_window_secs` before giving up
+    pub max_restarts: u32,
+    pub restart_window_secs: u64,
+}
+
+impl Default for RestartConfig {
+    fn default() -> Self {
+        Self {
+            policy: RestartPolicy::Always,
+            backoff_initial_ms: 500,
+            backoff_max_ms: 30_000,
+            max_restarts: 5,
+            restart_window_secs: 60,
+        }
+    }
+}
+
+/// See `lockdown`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockdownConfig {
+    pub enable: bool,
+    /// USB device ports (as seen on usbip list -l) to lock down
+    pub usbs: Vec<String>,
+    pub restart: RestartConfig,
+}
+
+/// See `audible`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudibleConfig {
+    pub enable: bool,
+    pub restart: RestartConfig,
+}
+
+/// See `numerical`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DataConfig {
+    pub enable: bool,
+    pub restart: RestartConfig,
+}
+
+/// See `daq`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaqConfig {
+    pub enable: bool,
+    /// Daq USB device
+    pub device: Option<String>,
+    pub restart: RestartConfig,
+}
+
+/// See `logger`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggerConfig {
+    pub enable: bool,
+    /// MQTT style filters of the topics to log
+    pub include: Vec<String>,
+    pub restart: RestartConfig,
+}
+
+impl Default for LoggerConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            include: vec!["#".to_string()],
+            restart: RestartConfig::default(),
+        }
+    }
+}
+
+/// See `visual`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VideoConfig {
+    pub enable: bool,
+    /// The input video file
+    pub uri: Option<String>,
+    pub restart: RestartConfig,
+}
+
+/// See `sys_parser`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SysConfig {
+    pub enable: bool,
+    pub restart: RestartConfig,
+}
+
+/// See `color`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ColorConfig {
+    pub enable: bool,
+    pub restart: RestartConfig,
+}
+
+/// See `gps`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GpsConfig {
+    pub enable: bool,
+    pub restart: RestartConfig,
+}
+
+/// See `net`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetConfig {
+    pub enable: bool,
+    /// The internet interfaces to gather statistics from
+    pub ifaces: Vec<String>,
+    pub restart: RestartConfig,
+}
+
+/// See `halow`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HalowConfig {
+    pub enable: bool,
+    /// The Halow interface to gather statistics from
+    pub iface: String,
+    pub restart: RestartConfig,
+}
+
+impl Default for HalowConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            iface: "wlan0".to_string(),
+            restart: RestartConfig::default(),
+        }
+    }
+}
+
+/// See `can`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CanConfig {
+    pub enable: bool,
+    pub restart: RestartConfig,
+}
+
+/// See `storage`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub enable: bool,
+    /// The free space to keep on the disk of the output folder, deleting events if needed
+    pub min_free_mb: u64,
+    /// Delete uploaded events older than this
+    pub max_age_hours: Option<u64>,
+    /// Also delete events past the max age which were never uploaded
+    pub max_age_unuploaded: bool,
+    /// How often to check the disk
+    pub check_secs: u64,
+    pub restart: RestartConfig,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            min_free_mb: 2048,
+            max_age_hours: None,
+            max_age_unuploaded: false,
+            check_secs: 60,
+            restart: RestartConfig::default(),
+        }
+    }
+}
+
+impl DaemonConfig {
+    /// Load a config file, the format is picked from the extension (`.toml` or `.json5`/`.json`)
+    pub fn load(path: &Path) -> Result<DaemonConfig, Box<dyn Error + Send + Sync>> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| format!("Could not read config file {}: {err}", path.display()))?;
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Ok(toml::from_str(&contents)
+                .map_err(|err| format!("Invalid config file {}: {err}", path.display()))?),
+            Some("json5") | Some("json") => Ok(json5::from_str(&contents)
+                .map_err(|err| format!("Invalid config file {}: {err}", path.display()))?),
+            _ => Err(format!(
+                "Unknown config file format for {}, use .toml or .json5",
+                path.display()
+            )
+            .into()),
+        }
+    }
+
+    /// Fill in the defaults derived from other settings, once the args are applied
+    pub fn fill_defaults(&mut self) {
+        if self.mqtt.rules.is_empty() {
+            self.mqtt.rules = MqttConfig::default_rules(&self.general.base_node);
+        }
+        if self.zenoh.rules.is_empty() {
+            self.zenoh.rules = ZenohConfig::default_rules(&self.general.base_node);
+        }
+    }
+
+    /// Check that the enabled modules have everything they need
+    pub fn validate(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.general.output_folder.is_empty() {
+            return Err("An output folder must be given (general.output_folder)".into());
+        }
+        // a single level, as it names the daemon in topics and Zenoh keys
+        if self.general.base_node.is_empty() || self.general.base_node.contains(['/', '+', '#']) {
+            return Err(
+                "The base node must be a single topic level, without / + # (general.base_node)"
+                    .into(),
+            );
+        }
+        if self.video.enable && self.video.uri.is_none() {
+            return Err("Must provide video URI if video is enabled (video.uri)".into());
+        }
+        if self.daq.enable && self.daq.device.is_none() {
+            return Err("Must provide DAQ device if DAQ is enabled (daq.device)".into());
+        }
+        if self.lockdown.enable && self.lockdown.usbs.is_empty() {
+            return Err("USBs required when enabling lockdown module (lockdown.usbs)".into());
+        }
+        if !self.mqtt.enable && !self.zenoh.enable {
+            return Err(
+                "At least one transport must be enabled (mqtt.enable, zenoh.enable)".into(),
+            );
+        }
+        if self.mqtt.enable {
+            let url = mqtt_handler::parse_url(&self.mqtt.url)?;
+            if self.mqtt.client_cert.is_some() != self.mqtt.client_key.is_some() {
+                return Err(
+                    "A client certificate and key must be given together (mqtt.client_cert, mqtt.client_key)"
+                        .into(),
+                );
+            }
+            if let Some(rule) = self
+                .mqtt
+                .rules
+                .iter()
+                .find(|rule| !topic::valid_filter(&rule.filter))
+            {
+                return Err(format!("Invalid MQTT rule filter {}", rule.filter).into());
+            }
+            if !url.tls && (self.mqtt.ca.is_some() || self.mqtt.client_cert.is_some()) {
+                return Err("Certificates need a mqtts:// URL (mqtt.url)".into());
+            }
+        }
+        if let Some(filter) = self
+            .logger
+            .include
+            .iter()
+            .chain(&self.api.topics)
+            .find(|filter| !topic::valid_filter(filter))
+        {
+            return Err(
+                format!("Invalid topic filter {filter} (logger.include, api.topics)").into(),
+            );
+        }
+        if self.zenoh.enable {
+            remap::Remap::new(&self.zenoh.remap)?;
+            if let Some(rule) = self
+                .zenoh
+                .rules
+                .iter()
+                .find(|rule| !topic::valid_filter(&rule.filter))
+            {
+                return Err(format!("Invalid Zenoh rule filter {}", rule.filter).into());
+            }
+            if self.zenoh.lvc && !remap::valid_key(&self.zenoh.lvc_key) {
+                return Err(format!(
+                    "Invalid LVC key {}, must be a Zenoh key without wildcards (zenoh.lvc_key)",
+                    self.zenoh.lvc_key
+                )
+                .into());
+            }
+        }
+        if self.spool.enable && self.spool.replay_rate == 0 {
+            return Err("The spool replay rate must be above 0 (spool.replay_rate)".into());
+        }
+        if let Some(rule) = self
+            .routing
+            .rules
+            .iter()
+            .find(|rule| !topic::valid_filter(&rule.filter))
+        {
+            return Err(format!("Invalid routing filter {}", rule.filter).into());
+        }
+        Ok(())
+    }
+
+    /// Turn a module on or off by its name, see `control`
+    pub fn set_enabled(&mut self, module: &str, enable: bool) -> Result<(), String> {
+        let flag = match module {
+            "lockdown" => &mut self.lockdown.enable,
+            "audible" => &mut self.audible.enable,
+            "data" => &mut self.data.enable,
+            "daq" => &mut self.daq.enable,
+            "logger" => &mut self.logger.enable,
+            "video" => &mut self.video.enable,
+            "sys" => &mut self.sys.enable,
+            "color" => &mut self.color.enable,
+            "gps" => &mut self.gps.enable,
+            "net" => &mut self.net.enable,
+            "halow" => &mut self.halow.enable,
+            "can" => &mut self.can.enable,
+            "storage" => &mut self.storage.enable,
+            _ => return Err(format!("Unknown module {module}")),
+        };
+        *flag = enable;
+        Ok(())
+    }
+
+    /// Keep the settings of the running config which cannot change live, warning about any dropped
+    pub fn keep_fixed(&mut self, running: &DaemonConfig) {
+        if self.general != running.general {
+            warn!("Changes to [general] need a daemon restart, ignoring them");
+            self.general = running.general.clone();
+        }
+        if self.log != running.log {
+            warn!("Changes to [log] need a daemon restart, ignoring them");
+            self.log = running.log.clone();
+        }
+        if self.mqtt != running.mqtt {
+            warn!("Changes to [mqtt] need a daemon restart, ignoring them");
+            self.mqtt = running.mqtt.clone();
+        }
+        if self.zenoh != running.zenoh {
+            warn!("Changes to [zenoh] need a daemon restart, ignoring them");
+            self.zenoh = running.zenoh.clone();
+        }
+        if self.routing != running.routing {
+            warn!("Changes to [routing] need a daemon restart, ignoring them");
+            self.routing = running.routing.clone();
+        }
+        if self.publish != running.publish {
+            warn!("Changes to [publish] need a daemon restart, ignoring them");
+            self.publish = running.publish.clone();
+        }
+        if self.spool != running.spool {
+            warn!("Changes to [spool] need a daemon restart, ignoring them");
+            self.spool = running.spool.clone();
+        }
+        if self.api != running.api {
+            warn!("Changes to [api] need a daemon restart, ignoring them");
+            self.api = running.api.clone();
+        }
+    }
+
+    /// Dump the config in TOML, for `--print-config`
+    pub fn to_toml(&self) -> Result<String, Box<dyn Error + Send + Sync>> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+}
@@ -0,0 +1,109 @@
+//! HELPER: Start and stop modules at runtime over a control topic
+//!
+//! Publish 1 to `<base_node>/Daemon/Control/<module>` to start a module, or 0 to stop it.
+//! The module is named as in its health topic, case insensitive (video, halow, net, ...).
+//! The change is applied like a config reload, so only that module is touched, and it lasts
+//! until the next SIGHUP.  Every request is answered on `<base_node>/Daemon/Control/<module>/Ack`
+//! with `[requested value, 1 if the module is now in that state else 0]`, always at QoS 2.
+
+use std::time::UNIX_EPOCH;
+
+use tracing::{info, warn};
+
+use crate::{
+    PublishableMessage,
+    config::{DaemonConfig, PublishOptions, Qos},
+    module::{ModuleRegistry, RunningModules},
+    playback_data::PlaybackData,
+};
+
+/// A request to start or stop a module
+#[derive(Debug, PartialEq)]
+pub struct ControlRequest {
+    /// The module name, lowercase
+    pub module: String,
+    /// The value sent, 1 to start and 0 to stop
+    pub value: f32,
+}
+
+/// Parse a received message into a control request, None if it is not one
+pub fn parse_control(base_node: &str, msg: &PlaybackData) -> Option<ControlRequest> {
+    let module = msg
+        .topic
+        .strip_prefix(base_node)?
+        .strip_prefix("/Daemon/Control/")?;
+    // skip our own acks
+    if module.is_empty() || module.contains('/') {
+        return None;
+    }
+    Some(ControlRequest {
+        module: module.to_ascii_lowercase(),
+        value: *msg.values.first().unwrap_or(&-1f32),
+    })
+}
+
+/// Apply a control request to the running modules, returning the ack to publish
+pub async fn handle_control(
+    req: ControlRequest,
+    base_node: &str,
+    conf: &mut DaemonConfig,
+    running: &mut RunningModules,
+) -> PublishableMessage {
+    let ok = match apply_control(&req, conf, running).await {
+        Ok(()) => true,
+        Err(err) => {
+            warn!("Could not apply control of {}: {}", req.module, err);
+            false
+        }
+    };
+
+    PublishableMessage {
+        topic: format!("{}/Daemon/Control/{}/Ack", base_node, req.module),
+        data: vec![req.value, ok as u8 as f32],
+        unit: "bool".to_string(),
+        time: UNIX_EPOCH.elapsed().unwrap().as_micros() as u64,
+        // whatever the rules, the requester is waiting on it
+        options: Some(PublishOptions {
+            qos: Qos::ExactlyOnce,
+            ..Default::default()
+        }),
+    }
+}
+
+async fn apply_control(
+    req: &ControlRequest,
+    conf: &mut DaemonConfig,
+    running: &mut RunningModules,
+) -> Result<(), String> {
+    let enable = if req.value == 1.0 {
+        true
+    } else if req.value == 0.0 {
+        false
+    } else {
+        return Err(format!("Bad value {}, use 1 or 0", req.value));
+    };
+
+    let mut new_conf = conf.clone();
+    new_conf.set_enabled(&req.module, enable)?;
+    new_conf.validate().map_err(|err| err.to_string())?;
+
+    info!(
+        "{} {} module from control topic",
+        if enable { "Starting" } else { "Stopping" },
+        req.module
+    );
+    running.reload(ModuleRegistry::from_config(&new_conf)).await;
+    *conf = new_conf;
+
+    let is_running = running
+        .names()
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(&req.module));
+    if is_running != enable {
+        return Err(format!(
+            "Module is {}",
+            if is_running { "running" } else { "not running" }
+        ));
+    }
+    Ok(())
+}
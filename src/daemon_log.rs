@@ -0,0 +1,159 @@
+//! HELPER: The daemon's own log output
+//!
+//! Logs go to stdout, as ANSI text or as JSON lines with `[log] json`.
+//! With `[log] file` they are also written as JSON lines to `<output_folder>/logs/daemon.log`,
+//! rotated to `daemon.log.1`, `daemon.log.2`, ... once `file_max_mb` is reached, keeping `file_count`.
+//! Each line has an RFC 3339 timestamp, to be matched against the telemetry of an event.
+//!
+//! `RUST_LOG` filters both, defaulting to INFO.
+
+use std::{
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{
+    EnvFilter, Layer, fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt,
+};
+
+use crate::config::LogConfig;
+
+/// The folder under the output folder holding the log files
+pub const LOG_FOLDER: &str = "logs";
+
+const LOG_FILE: &str = "daemon.log";
+
+/// Set up the global tracing subscriber, must be called once
+pub fn init(conf: &LogConfig, output_folder: &Path) {
+    let stdout = if conf.json {
+        tracing_subscriber::fmt::layer()
+            .json()
+            .with_thread_ids(true)
+            .with_thread_names(true)
+            .with_span_events(FmtSpan::CLOSE)
+            .boxed()
+    } else {
+        tracing_subscriber::fmt::layer()
+            .with_thread_ids(true)
+            .with_ansi(true)
+            .with_thread_names(true)
+            .with_span_events(FmtSpan::CLOSE)
+            .boxed()
+    };
+
+    let mut file_err = None;
+    let file = if conf.file {
+        match RollingFile::open(
+            &output_folder.join(LOG_FOLDER),
+            conf.file_max_mb.saturating_mul(1_000_000),
+            conf.file_count,
+        ) {
+            Ok(file) => Some(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_thread_names(true)
+                    .with_writer(Mutex::new(file)),
+            ),
+            Err(err) => {
+                file_err = Some(err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    tracing_subscriber::registry()
+        .with(
+            EnvFilter::builder()
+                .with_default_directive(LevelFilter::INFO.into())
+                .from_env_lossy(),
+        )
+        .with(stdout)
+        .with(file)
+        .try_init()
+        .expect("Could not init tracing");
+
+    if let Some(err) = file_err {
+        tracing::warn!(
+            "Could not open daemon log file, logging to stdout only: {}",
+            err
+        );
+    }
+}
+
+/// A log file rotated by size
+pub struct RollingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    count: usize,
+    file: File,
+    size: u64,
+}
+
+impl RollingFile {
+    /// Open or continue the log in dir, rotating at max_bytes and keeping count old files
+    pub fn open(dir: &Path, max_bytes: u64, count: usize) -> io::Result<RollingFile> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(LOG_FILE);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RollingFile {
+            path,
+            max_bytes,
+            count,
+            file,
+            size,
+        })
+    }
+
+    /// The path of the nth old file
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = OsString::from(&self.path);
+        path.push(format!(".{n}"));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        // shift every old file up one, dropping the oldest
+        for n in (1..self.count).rev() {
+            match std::fs::rename(self.rotated(n), self.rotated(n + 1)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        if self.count > 0 {
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RollingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0
+            && self.size + buf.len() as u64 > self.max_bytes
+            && let Err(err) = self.rotate()
+        {
+            // keep writing to the full file rather than lose the line
+            eprintln!("Could not rotate {}: {}", self.path.display(), err);
+        }
+        let len = self.file.write(buf)?;
+        self.size += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -11,16 +11,16 @@
 use std::time::{Duration, UNIX_EPOCH};
 
 use tokio::{
-    io::{AsyncBufReadExt, BufReader},
+    io::{AsyncBufRead, AsyncBufReadExt, BufReader},
     sync::mpsc::Sender,
 };
-use tokio_serial::{SerialPortBuilderExt, SerialStream};
+use tokio_serial::SerialPortBuilderExt;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, warn};
 
 use socketcan::{CanFrame, EmbeddedFrame, StandardId};
 
-use crate::PublishableMessage;
+use crate::{PublishableMessage, publish::Publisher, sim};
 
 const CAN_ID: u16 = 0x630;
 
@@ -28,14 +28,17 @@ pub async fn collect_daq(
     cancel_token: CancellationToken,
     device: String,
     daq_monitor_tx: Sender<bool>,
-    mqtt_sender_tx: Sender<PublishableMessage>,
+    mqtt_sender_tx: Publisher,
     can_handler_tx: Sender<CanFrame>,
 ) {
-    let port = tokio_serial::new(device, 115_200)
-        .open_native_async()
-        .expect("Failed to open port");
-
-    let reader = BufReader::<SerialStream>::new(port);
+    let reader: Box<dyn AsyncBufRead + Unpin + Send> = if sim::is_enabled() {
+        Box::new(BufReader::new(sim::daq_stream(cancel_token.clone())))
+    } else {
+        let port = tokio_serial::new(device, 115_200)
+            .open_native_async()
+            .expect("Failed to open port");
+        Box::new(BufReader::new(port))
+    };
 
     let mut lines = reader.lines();
 
@@ -91,8 +94,8 @@ pub async fn collect_daq(
                     topic:"TPU/DAQ/Shockpots".to_string(),
                 data:vec![conv_shock(*clean_res.get(1).unwrap()),
                 conv_shock(*clean_res.get(2).unwrap()),conv_shock(*clean_res.get(3).unwrap()), conv_shock(*clean_res.get(7).unwrap())], unit: "in".to_string(),
-            time},
-                    PublishableMessage { topic: "TPU/DAQ/SteringAngle".to_string(), data: vec![ conv_wheel(*clean_res.get(6).unwrap())], unit: "V".to_string(), time }
+            time, options: None},
+                    PublishableMessage { topic: "TPU/DAQ/SteringAngle".to_string(), data: vec![ conv_wheel(*clean_res.get(6).unwrap())], unit: "V".to_string(), time, options: None }
             ], vec![CanFrame::new(StandardId::new(CAN_ID).expect("Failed to create standard id!"),
                 &(conv_wheel(*clean_res.get(6).unwrap())).to_be_bytes()).expect("Failed to create CAN frame!")])
             } // NOTE: CAN Frame currently only sends wheel sensor data in big endian
@@ -104,11 +107,12 @@ pub async fn collect_daq(
             warn!("Failed to send to daq watchdog: {}", err);
         };
         for mqtt in mqtt_msgs {
-            if let Err(err) = mqtt_sender_tx
-                .send_timeout(mqtt, Duration::from_millis(50))
-                .await
-            {
-                warn!("Could not pub to sender from daq: {}", err);
+            // a full queue is handled by the DAQ publish policy (see `publish`), but even with
+            // `block` the serial line must keep being read
+            match tokio::time::timeout(Duration::from_millis(50), mqtt_sender_tx.send(mqtt)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => warn!("Could not pub to sender from daq: {}", err),
+                Err(_) => warn!("Could not pub to sender from daq: publish queue full"),
             }
         }
 
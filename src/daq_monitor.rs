@@ -3,6 +3,7 @@
 //!
 //! See daq
 
+use futures_util::future::BoxFuture;
 use socketcan::CanFrame;
 use tokio::sync::mpsc::Sender;
 use tokio::task::JoinHandle;
@@ -10,16 +11,60 @@ use tokio::time::interval;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, warn};
 
-use crate::PublishableMessage;
 use crate::daq::collect_daq;
+use crate::health::{ModuleHealth, ModuleState};
+use crate::metrics::METRICS;
+use crate::module::{Module, ModuleContext, ModuleInputs, ModuleResult};
+use crate::publish::Publisher;
 
 use std::time::Duration;
 
+/// The DAQ module, see `monitor_daq`
+pub struct DaqModule {
+    device: String,
+}
+
+impl DaqModule {
+    pub fn new(device: String) -> Self {
+        Self { device }
+    }
+}
+
+impl Module for DaqModule {
+    fn name(&self) -> &'static str {
+        "DAQ"
+    }
+
+    fn inputs(&self) -> ModuleInputs {
+        ModuleInputs {
+            publish: true,
+            can: true,
+            ..Default::default()
+        }
+    }
+
+    fn run(&self, mut ctx: ModuleContext) -> BoxFuture<'static, ModuleResult> {
+        let device = self.device.clone();
+        Box::pin(async move {
+            monitor_daq(
+                ctx.cancel_token.clone(),
+                device,
+                ctx.publisher()?,
+                ctx.can()?,
+                ctx.health,
+            )
+            .await;
+            Ok(())
+        })
+    }
+}
+
 pub async fn monitor_daq(
     cancel_token: CancellationToken,
     device: String,
-    mqtt_sender_tx: Sender<PublishableMessage>,
+    mqtt_sender_tx: Publisher,
     can_handler_tx: Sender<CanFrame>,
+    health: ModuleHealth,
 ) {
     let mut timeout = interval(Duration::from_millis(200));
 
@@ -57,12 +102,16 @@ pub async fn monitor_daq(
                     daq_cancel_token = CancellationToken::new();
                     task = tokio::task::spawn(collect_daq(daq_cancel_token.clone(), device.clone(), daq_monitor_tx.clone(), mqtt_sender_tx.clone(), can_handler_tx.clone()));
                     warn!("Respawing DAQ thread");
+                    METRICS.daq_respawned();
+                    health.error();
+                    health.set_state(ModuleState::Degraded);
                 }
                 watchdog = false;
             }
 
             _ = daq_monitor_rx.recv() => {
                 watchdog = true;
+                health.work();
             }
         }
     }
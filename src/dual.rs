@@ -0,0 +1,569 @@
+//! HELPER: MQTT and Zenoh at once, for consumers which only speak one of them
+//!
+//! `DualTransport` wraps both transports as one for the `Dispatcher` (see `transport`):
+//! - Each published topic goes to MQTT, Zenoh, or both, by the first matching `[routing]` rule.
+//!   Topics going to both skip a disconnected transport while the other is connected, as of the
+//!   last `connected` check, which the `Dispatcher` makes every heartbeat
+//! - Received messages are merged, and one seen again within `dedup_ms` is dropped,
+//!   so a message published to both (or bridged) is only handled once
+//! - With `bridge`, a message received on one transport is forwarded to the other if routed there,
+//!   unless the daemon published it itself. Forwarded messages are tagged with their origin
+//!   (`<base_node>/mqtt` or `<base_node>/zenoh`) in a MQTT v5 user property or Zenoh attachment,
+//!   and a tagged message is never forwarded again, so bridges on both ends cannot loop.
+//!   Forwarded messages and errors are counted by direction in `metrics`, and up to
+//!   `FORWARD_CAPACITY` wait to be forwarded, the rest counted as errors
+//!
+//! Subscriptions go to both, succeeding if either does, so `$SYS` is only subscribed on MQTT.
+//! Unsubscribing does the same.  With `bridge` everything (`#`) is subscribed, not only the topics
+//! of the running modules (see `module`).
+
+use std::{
+    collections::{HashMap, VecDeque, hash_map::DefaultHasher},
+    hash::{Hash, Hasher},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use futures_util::future::BoxFuture;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, trace, warn};
+
+use crate::{
+    config::{PublishOptions, Route, RoutingConfig},
+    metrics::METRICS,
+    transport::{Incoming, Transport, TransportResult},
+};
+
+/// How many bridged messages may wait to be forwarded
+const FORWARD_CAPACITY: usize = 1000;
+
+/// A message to forward to the other transport
+struct Forward {
+    to_mqtt: bool,
+    topic: String,
+    payload: Vec<u8>,
+}
+
+impl Forward {
+    /// The direction it is counted under in `metrics`
+    fn direction(&self) -> &'static str {
+        if self.to_mqtt {
+            "zenoh-to-mqtt"
+        } else {
+            "mqtt-to-zenoh"
+        }
+    }
+}
+
+/// Remembers recent messages by hash, to drop duplicates
+struct Dedup {
+    window: Duration,
+    seen: HashMap<u64, Instant>,
+    order: VecDeque<(Instant, u64)>,
+}
+
+impl Dedup {
+    fn new(window: Duration) -> Dedup {
+        Dedup {
+            window,
+            seen: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn hash(topic: &str, payload: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        topic.hash(&mut hasher);
+        payload.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Forget messages older than the window
+    fn expire(&mut self, now: Instant) {
+        while let Some((time, hash)) = self.order.front().copied()
+            && now.duration_since(time) > self.window
+        {
+            self.order.pop_front();
+            if self.seen.get(&hash) == Some(&time) {
+                self.seen.remove(&hash);
+            }
+        }
+    }
+
+    /// Whether the message was seen within the window
+    fn contains(&mut self, topic: &str, payload: &[u8]) -> bool {
+        self.expire(Instant::now());
+        self.seen.contains_key(&Self::hash(topic, payload))
+    }
+
+    /// Whether the message was not seen within the window, remembering it if so
+    fn first_seen(&mut self, topic: &str, payload: &[u8]) -> bool {
+        let now = Instant::now();
+        self.expire(now);
+        let hash = Self::hash(topic, payload);
+        if self.seen.contains_key(&hash) {
+            return false;
+        }
+        self.seen.insert(hash, now);
+        self.order.push_back((now, hash));
+        true
+    }
+}
+
+/// MQTT and Zenoh as one transport, see `dual`
+pub struct DualTransport {
+    mqtt: Arc<dyn Transport>,
+    zenoh: Arc<dyn Transport>,
+    routing: RoutingConfig,
+    /// The base node, in the origin tag of bridged messages
+    node: String,
+    /// Every message received
+    dedup: Mutex<Dedup>,
+    /// Every message published, which is never bridged as it was already sent where it goes
+    own: Mutex<Dedup>,
+    /// Whether each transport was up at the last `connected` check, assumed before the first
+    mqtt_up: AtomicBool,
+    zenoh_up: AtomicBool,
+    forward_tx: mpsc::Sender<Forward>,
+    /// Taken by the forwarder on connect
+    forward_rx: Mutex<Option<mpsc::Receiver<Forward>>>,
+    /// Where the forwarder runs, until the daemon shuts down
+    task_tracker: TaskTracker,
+    cancel_token: CancellationToken,
+}
+
+impl DualTransport {
+    pub fn new(
+        mqtt: Arc<dyn Transport>,
+        zenoh: Arc<dyn Transport>,
+        routing: RoutingConfig,
+        node: String,
+        task_tracker: &TaskTracker,
+        cancel_token: &CancellationToken,
+    ) -> DualTransport {
+        let (forward_tx, forward_rx) = mpsc::channel(FORWARD_CAPACITY);
+        DualTransport {
+            mqtt,
+            zenoh,
+            dedup: Mutex::new(Dedup::new(Duration::from_millis(routing.dedup_ms))),
+            own: Mutex::new(Dedup::new(Duration::from_millis(routing.dedup_ms))),
+            routing,
+            node,
+            mqtt_up: AtomicBool::new(true),
+            zenoh_up: AtomicBool::new(true),
+            forward_tx,
+            forward_rx: Mutex::new(Some(forward_rx)),
+            task_tracker: task_tracker.clone(),
+            cancel_token: cancel_token.clone(),
+        }
+    }
+}
+
+/// Publish bridged messages until cancelled or the transport is dropped
+async fn forwarder(
+    cancel_token: CancellationToken,
+    mut forward_rx: mpsc::Receiver<Forward>,
+    mqtt: Arc<dyn Transport>,
+    zenoh: Arc<dyn Transport>,
+    node: String,
+) {
+    let to_mqtt_origin = format!("{node}/zenoh");
+    let to_zenoh_origin = format!("{node}/mqtt");
+    loop {
+        let forward = tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            forward = forward_rx.recv() => match forward {
+                Some(forward) => forward,
+                None => break,
+            },
+        };
+        let direction = forward.direction();
+        let (to, origin) = if forward.to_mqtt {
+            (&mqtt, &to_mqtt_origin)
+        } else {
+            (&zenoh, &to_zenoh_origin)
+        };
+        trace!("Bridging {} {}", forward.topic, direction);
+        // a backed up transport can take forever
+        let forwarded = tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            forwarded = to.forward(&forward.topic, forward.payload, origin) => forwarded,
+        };
+        match forwarded {
+            Ok(()) => METRICS.bridged(direction),
+            Err(err) => {
+                METRICS.bridge_failed(direction);
+                warn!("Could not bridge {} {}: {}", forward.topic, direction, err);
+            }
+        }
+    }
+    debug!("Shutting down bridge forwarder");
+}
+
+impl Transport for DualTransport {
+    fn connect(&self) -> BoxFuture<'_, TransportResult> {
+        Box::pin(async {
+            self.mqtt.connect().await?;
+            self.zenoh.connect().await?;
+            if let Some(forward_rx) = self.forward_rx.lock().unwrap().take() {
+                self.task_tracker.spawn(forwarder(
+                    self.cancel_token.clone(),
+                    forward_rx,
+                    self.mqtt.clone(),
+                    self.zenoh.clone(),
+                    self.node.clone(),
+                ));
+            }
+            Ok(())
+        })
+    }
+
+    fn subscribe<'a>(&'a self, filter: &'a str) -> BoxFuture<'a, TransportResult> {
+        Box::pin(async move {
+            let mqtt = self.mqtt.subscribe(filter).await;
+            let zenoh = self.zenoh.subscribe(filter).await;
+            match (mqtt, zenoh) {
+                (Err(err), Err(_)) => Err(err),
+                (Err(err), Ok(())) => {
+                    debug!("Could not subscribe to {} on MQTT: {}", filter, err);
+                    Ok(())
+                }
+                (Ok(()), Err(err)) => {
+                    debug!("Could not subscribe to {} on Zenoh: {}", filter, err);
+                    Ok(())
+                }
+                (Ok(()), Ok(())) => Ok(()),
+            }
+        })
+    }
+
+    fn unsubscribe<'a>(&'a self, filter: &'a str) -> BoxFuture<'a, TransportResult> {
+        Box::pin(async move {
+            let mqtt = self.mqtt.unsubscribe(filter).await;
+            let zenoh = self.zenoh.unsubscribe(filter).await;
+            mqtt.or(zenoh)
+        })
+    }
+
+    fn publish<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: Vec<u8>,
+        options: Option<PublishOptions>,
+    ) -> BoxFuture<'a, TransportResult> {
+        Box::pin(async move {
+            if self.routing.bridge {
+                self.own.lock().unwrap().first_seen(topic, &payload);
+            }
+            match self.routing.route(topic) {
+                Route::Mqtt => self.mqtt.publish(topic, payload, options).await,
+                Route::Zenoh => self.zenoh.publish(topic, payload, options).await,
+                Route::Both => {
+                    // a dead link must not hold up the other one
+                    let mqtt_up = self.mqtt_up.load(Ordering::Relaxed);
+                    let zenoh_up = self.zenoh_up.load(Ordering::Relaxed);
+                    let mqtt = if mqtt_up || !zenoh_up {
+                        self.mqtt.publish(topic, payload.clone(), options).await
+                    } else {
+                        Ok(())
+                    };
+                    let zenoh = if zenoh_up || !mqtt_up {
+                        self.zenoh.publish(topic, payload, options).await
+                    } else {
+                        Ok(())
+                    };
+                    mqtt.and(zenoh)
+                }
+            }
+        })
+    }
+
+    fn recv(&self) -> BoxFuture<'_, Option<Incoming>> {
+        Box::pin(async {
+            loop {
+                let (incoming, from_mqtt) = tokio::select! {
+                    Some(incoming) = self.mqtt.recv() => (incoming, true),
+                    Some(incoming) = self.zenoh.recv() => (incoming, false),
+                    else => return None,
+                };
+                let Incoming::Message {
+                    topic,
+                    payload,
+                    origin,
+                } = incoming
+                else {
+                    return Some(incoming);
+                };
+                if !self.dedup.lock().unwrap().first_seen(&topic, &payload) {
+                    trace!("Dropping duplicate of {}", topic);
+                    continue;
+                }
+
+                if let Some(origin) = &origin {
+                    trace!("Not bridging {}, already bridged by {}", topic, origin);
+                } else if self.routing.bridge
+                    && !self.own.lock().unwrap().contains(&topic, &payload)
+                {
+                    let to_other = match self.routing.route(&topic) {
+                        Route::Mqtt => !from_mqtt,
+                        Route::Zenoh => from_mqtt,
+                        Route::Both => true,
+                    };
+                    // sent from the forwarder, as awaiting here would not be cancel safe
+                    if to_other
+                        && let Err(TrySendError::Full(forward)) =
+                            self.forward_tx.try_send(Forward {
+                                to_mqtt: !from_mqtt,
+                                topic: topic.clone(),
+                                payload: payload.clone(),
+                            })
+                    {
+                        METRICS.bridge_failed(forward.direction());
+                        debug!(
+                            "Not bridging {} {}, the bridge is backed up",
+                            topic,
+                            forward.direction()
+                        );
+                    }
+                }
+                return Some(Incoming::Message {
+                    topic,
+                    payload,
+                    origin,
+                });
+            }
+        })
+    }
+
+    fn connected(&self) -> BoxFuture<'_, bool> {
+        Box::pin(async {
+            let mqtt_up = self.mqtt.connected().await;
+            let zenoh_up = self.zenoh.connected().await;
+            self.mqtt_up.store(mqtt_up, Ordering::Relaxed);
+            self.zenoh_up.store(zenoh_up, Ordering::Relaxed);
+            mqtt_up || zenoh_up
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        PublishableMessage,
+        config::{DaemonConfig, RouteRule},
+        module::ModuleRegistry,
+        sd_notify::Heartbeat,
+        topic,
+        transport::{Dispatcher, Loopback, encode},
+    };
+
+    /// A loopback remembering the topics published and the filters subscribed to it
+    #[derive(Default)]
+    struct Recorder {
+        loopback: Loopback,
+        published: Mutex<Vec<String>>,
+        subscribed: Mutex<Vec<String>>,
+    }
+
+    impl Recorder {
+        fn published(&self) -> Vec<String> {
+            std::mem::take(&mut *self.published.lock().unwrap())
+        }
+
+        fn subscribed_to(&self, topic: &str) -> bool {
+            self.subscribed
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|filter| topic::matches(filter, topic))
+        }
+    }
+
+    impl Transport for Recorder {
+        fn connect(&self) -> BoxFuture<'_, TransportResult> {
+            self.loopback.connect()
+        }
+
+        fn subscribe<'a>(&'a self, filter: &'a str) -> BoxFuture<'a, TransportResult> {
+            self.subscribed.lock().unwrap().push(filter.to_string());
+            self.loopback.subscribe(filter)
+        }
+
+        fn unsubscribe<'a>(&'a self, filter: &'a str) -> BoxFuture<'a, TransportResult> {
+            self.subscribed.lock().unwrap().retain(|sub| sub != filter);
+            self.loopback.unsubscribe(filter)
+        }
+
+        fn publish<'a>(
+            &'a self,
+            topic: &'a str,
+            payload: Vec<u8>,
+            options: Option<PublishOptions>,
+        ) -> BoxFuture<'a, TransportResult> {
+            self.published.lock().unwrap().push(topic.to_string());
+            self.loopback.publish(topic, payload, options)
+        }
+
+        fn forward<'a>(
+            &'a self,
+            topic: &'a str,
+            payload: Vec<u8>,
+            origin: &'a str,
+        ) -> BoxFuture<'a, TransportResult> {
+            self.published.lock().unwrap().push(topic.to_string());
+            self.loopback.forward(topic, payload, origin)
+        }
+
+        fn recv(&self) -> BoxFuture<'_, Option<Incoming>> {
+            self.loopback.recv()
+        }
+
+        fn connected(&self) -> BoxFuture<'_, bool> {
+            self.loopback.connected()
+        }
+    }
+
+    fn msg(topic: &str) -> PublishableMessage {
+        PublishableMessage {
+            topic: topic.to_string(),
+            data: vec![1.0],
+            unit: String::new(),
+            time: 7,
+            options: None,
+        }
+    }
+
+    async fn recv_topic(dual: &DualTransport) -> Option<String> {
+        match tokio::time::timeout(Duration::from_millis(100), dual.recv()).await {
+            Ok(Some(Incoming::Message { topic, .. })) => Some(topic),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_dedups_and_bridges() {
+        let mqtt = Arc::new(Recorder::default());
+        let zenoh = Arc::new(Recorder::default());
+        let dual = DualTransport::new(
+            mqtt.clone(),
+            zenoh.clone(),
+            RoutingConfig {
+                rules: vec![RouteRule {
+                    filter: "TPU/OnBoard/#".to_string(),
+                    to: Route::Zenoh,
+                }],
+                bridge: true,
+                ..Default::default()
+            },
+            "TPU".to_string(),
+            &TaskTracker::new(),
+            &CancellationToken::new(),
+        );
+        dual.connect().await.unwrap();
+
+        // published to both, echoed by both, handled once
+        dual.publish("TPU/GPS/Speed", encode(msg("TPU/GPS/Speed")).unwrap(), None)
+            .await
+            .unwrap();
+        assert_eq!(mqtt.published(), vec!["TPU/GPS/Speed"]);
+        assert_eq!(zenoh.published(), vec!["TPU/GPS/Speed"]);
+        assert_eq!(recv_topic(&dual).await.as_deref(), Some("TPU/GPS/Speed"));
+        assert_eq!(recv_topic(&dual).await, None);
+
+        // routed to zenoh only
+        dual.publish(
+            "TPU/OnBoard/Cpu",
+            encode(msg("TPU/OnBoard/Cpu")).unwrap(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(mqtt.published().is_empty());
+        assert_eq!(zenoh.published(), vec!["TPU/OnBoard/Cpu"]);
+        assert_eq!(recv_topic(&dual).await.as_deref(), Some("TPU/OnBoard/Cpu"));
+
+        // received on MQTT, bridged to zenoh, and its echo from zenoh dropped
+        mqtt.loopback.inject(msg("BMS/Pack/Voltage")).unwrap();
+        assert_eq!(recv_topic(&dual).await.as_deref(), Some("BMS/Pack/Voltage"));
+        assert_eq!(recv_topic(&dual).await, None);
+        assert_eq!(zenoh.published(), vec!["BMS/Pack/Voltage"]);
+        assert!(mqtt.published().is_empty());
+
+        // received on zenoh but routed to zenoh only, so not bridged
+        zenoh.loopback.inject(msg("TPU/OnBoard/Mem")).unwrap();
+        assert_eq!(recv_topic(&dual).await.as_deref(), Some("TPU/OnBoard/Mem"));
+        assert!(mqtt.published().is_empty());
+
+        // already bridged elsewhere, so handled but never forwarded again
+        let payload = encode(msg("VCU/State")).unwrap();
+        mqtt.loopback
+            .forward("VCU/State", payload, "Base/zenoh")
+            .await
+            .unwrap();
+        assert_eq!(recv_topic(&dual).await.as_deref(), Some("VCU/State"));
+        assert_eq!(recv_topic(&dual).await, None);
+        assert!(zenoh.published().is_empty());
+    }
+
+    async fn eventually(condition: impl Fn() -> bool) -> bool {
+        for _ in 0..100 {
+            if condition() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn bridges_topics_no_module_needs() {
+        let mut conf = DaemonConfig::default();
+        conf.zenoh.enable = true;
+        conf.routing.bridge = true;
+        let (_channels, transport_channels, _) = ModuleRegistry::from_config(&conf).channels();
+
+        let mqtt = Arc::new(Recorder::default());
+        let zenoh = Arc::new(Recorder::default());
+        let token = CancellationToken::new();
+        let dual = DualTransport::new(
+            mqtt.clone(),
+            zenoh.clone(),
+            conf.routing.clone(),
+            conf.general.base_node.clone(),
+            &TaskTracker::new(),
+            &token,
+        );
+        dual.connect().await.unwrap();
+        let output_folder = tempfile::tempdir().unwrap();
+        tokio::spawn(
+            Dispatcher::new(
+                token.clone(),
+                transport_channels,
+                false,
+                None,
+                output_folder.path().to_path_buf(),
+                Heartbeat::default(),
+                None,
+            )
+            .run(Arc::new(dual)),
+        );
+
+        // no module is running, yet it is subscribed and bridged
+        assert!(eventually(|| mqtt.subscribed_to("BMS/Pack/Voltage")).await);
+        mqtt.loopback.inject(msg("BMS/Pack/Voltage")).unwrap();
+        assert!(
+            eventually(|| zenoh
+                .published
+                .lock()
+                .unwrap()
+                .contains(&"BMS/Pack/Voltage".to_string()))
+            .await
+        );
+        token.cancel();
+    }
+}
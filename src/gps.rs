@@ -8,9 +8,9 @@
 
 use std::{error::Error, time::UNIX_EPOCH};
 
-use futures_util::SinkExt;
+use futures_util::{SinkExt, future::BoxFuture};
 use gpsd_proto::{Pps, Tpv, UnifiedResponse};
-use tokio::{net::TcpStream, sync::mpsc::Sender};
+use tokio::net::TcpStream;
 use tokio_stream::StreamExt::{self};
 use tokio_util::{
     codec::{Framed, LinesCodec},
@@ -18,15 +18,47 @@ use tokio_util::{
 };
 use tracing::{debug, info, trace, warn};
 
-use crate::PublishableMessage;
+use crate::{
+    PublishableMessage,
+    health::ModuleHealth,
+    module::{Module, ModuleContext, ModuleInputs, ModuleResult},
+    publish::Publisher,
+    sim,
+};
+
+/// The GPS module, see `gps_manager`
+pub struct GpsModule;
+
+impl Module for GpsModule {
+    fn name(&self) -> &'static str {
+        "GPS"
+    }
+
+    fn inputs(&self) -> ModuleInputs {
+        ModuleInputs {
+            publish: true,
+            ..Default::default()
+        }
+    }
+
+    fn run(&self, mut ctx: ModuleContext) -> BoxFuture<'static, ModuleResult> {
+        Box::pin(async move {
+            gps_manager(ctx.cancel_token.clone(), ctx.publisher()?, ctx.health).await
+        })
+    }
+}
 
 /// changes settings and sends GPS data
 pub async fn gps_manager(
     cancel_token: CancellationToken,
-    mqtt_sender_tx: Sender<PublishableMessage>,
+    mqtt_sender_tx: Publisher,
+    health: ModuleHealth,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     // lets create some GPS
-    let stream = TcpStream::connect("127.0.0.1:2947").await?;
+    let stream = match sim::gpsd_address() {
+        Some(addr) => TcpStream::connect(addr).await?,
+        None => TcpStream::connect("127.0.0.1:2947").await?,
+    };
     let mut framed = Framed::new(stream, LinesCodec::new());
     if let Err(e) = framed.send(gpsd_proto::ENABLE_WATCH_CMD).await {
         warn!("Could not watch GPS: {e}");
@@ -45,12 +77,16 @@ pub async fn gps_manager(
                         match msg {
                             Ok(msg) => {
                                 if let Some(data) = handle_gps_msg(msg).await {
+                                    health.work();
                                     send_gps_data(data, &mqtt_sender_tx, time).await;
+                                } else {
+                                    health.error();
                                 }
 
                             },
                             Err(err) => {
                                 warn!("Error decoding GPS message {err}");
+                                health.error();
                             }
                         }
                     },
@@ -75,11 +111,7 @@ async fn handle_gps_msg(msg: String) -> Option<UnifiedResponse> {
     }
 }
 
-async fn send_gps_data(
-    data: UnifiedResponse,
-    mqtt_sender_tx: &Sender<PublishableMessage>,
-    time: u64,
-) {
+async fn send_gps_data(data: UnifiedResponse, mqtt_sender_tx: &Publisher, time: u64) {
     let msgs = match data {
         UnifiedResponse::Version(v) => {
             trace!("Got GPS Version: {:?}", v);
@@ -166,6 +198,7 @@ fn parse_tpv(tpv: Tpv, time: u64) -> Vec<PublishableMessage> {
         data: vec![tpv.mode as usize as f32],
         unit: "enum".to_string(),
         time,
+        options: None,
     });
 
     if let Some(tpv_speed) = tpv.speed {
@@ -174,6 +207,7 @@ fn parse_tpv(tpv: Tpv, time: u64) -> Vec<PublishableMessage> {
             data: vec![tpv_speed],
             unit: "knot".to_string(),
             time,
+            options: None,
         });
     }
 
@@ -185,6 +219,7 @@ fn parse_tpv(tpv: Tpv, time: u64) -> Vec<PublishableMessage> {
             data: vec![lat as f32, lon as f32],
             unit: "coordinate".to_string(),
             time,
+            options: None,
         });
     }
 
@@ -194,6 +229,7 @@ fn parse_tpv(tpv: Tpv, time: u64) -> Vec<PublishableMessage> {
             data: vec![tpv_alt_hae],
             unit: "meter".to_string(),
             time,
+            options: None,
         });
     }
     ret
@@ -207,6 +243,7 @@ fn parse_pps(pps: Pps, time: u64) -> Vec<PublishableMessage> {
             data: vec![pps_precision],
             unit: "NTP precison".to_string(),
             time,
+            options: None,
         }]
     } else {
         vec![]
@@ -10,17 +10,59 @@ use std::time::Duration;
 use std::time::UNIX_EPOCH;
 
 use crate::PublishableMessage;
+use crate::health::ModuleHealth;
+use crate::module::{Module, ModuleContext, ModuleInputs, ModuleResult};
+use crate::publish::Publisher;
+use crate::sim;
+use futures_util::future::BoxFuture;
 use regex::Regex;
-use tokio::process::Command;
-use tokio::sync::mpsc::Sender;
 use tokio_util::sync::CancellationToken;
 use tracing::debug;
 use tracing::warn;
 
+/// The HaLow statistics module, see `halow_scraper`
+pub struct HalowModule {
+    base_name: String,
+}
+
+impl HalowModule {
+    pub fn new(base_name: String) -> Self {
+        Self { base_name }
+    }
+}
+
+impl Module for HalowModule {
+    fn name(&self) -> &'static str {
+        "Halow"
+    }
+
+    fn inputs(&self) -> ModuleInputs {
+        ModuleInputs {
+            publish: true,
+            ..Default::default()
+        }
+    }
+
+    fn run(&self, mut ctx: ModuleContext) -> BoxFuture<'static, ModuleResult> {
+        let base_name = self.base_name.clone();
+        Box::pin(async move {
+            halow_scraper(
+                ctx.cancel_token.clone(),
+                base_name,
+                ctx.publisher()?,
+                ctx.health,
+            )
+            .await;
+            Ok(())
+        })
+    }
+}
+
 pub async fn halow_scraper(
     cancel_token: CancellationToken,
     base_name: String,
-    mqtt_sender_tx: Sender<PublishableMessage>,
+    mqtt_sender_tx: Publisher,
+    health: ModuleHealth,
 ) {
     let mut signal_sync = tokio::time::interval(Duration::from_millis(50));
 
@@ -40,6 +82,11 @@ pub async fn halow_scraper(
             },
             _ =  signal_sync.tick() => {
                 let msgs = handle_tick(&rssi_regex, &mcs_regex, rssi_topic.clone(), mcs_topic_tx.clone(), mcs_topic_rx.clone()).await;
+                if msgs.is_empty() {
+                    health.error();
+                } else {
+                    health.work();
+                }
                 handle_sends(msgs, &mqtt_sender_tx).await;
             }
         }
@@ -54,7 +101,7 @@ async fn handle_tick(
     mcs_topic_rx: String,
 ) -> Vec<PublishableMessage> {
     let mut send: Vec<PublishableMessage> = Vec::with_capacity(2);
-    let output = Command::new("cli_app")
+    let output = sim::command("cli_app")
         .args(["show", "signal"])
         .output()
         .await
@@ -68,6 +115,7 @@ async fn handle_tick(
             data: vec![fl],
             unit: "dBm".to_string(),
             time: UNIX_EPOCH.elapsed().unwrap().as_micros() as u64,
+            options: None,
         })
     } else {
         warn!(
@@ -76,7 +124,7 @@ async fn handle_tick(
         );
     }
 
-    let output = Command::new("cli_app")
+    let output = sim::command("cli_app")
         .args(["show", "ap", "0"])
         .output()
         .await
@@ -94,6 +142,7 @@ async fn handle_tick(
                 data: vec![fl],
                 unit: "".to_string(),
                 time: UNIX_EPOCH.elapsed().unwrap().as_micros() as u64,
+                options: None,
             });
         }
 
@@ -105,6 +154,7 @@ async fn handle_tick(
                 data: vec![fl],
                 unit: "".to_string(),
                 time: UNIX_EPOCH.elapsed().unwrap().as_micros() as u64,
+                options: None,
             })
         }
     }
@@ -112,10 +162,7 @@ async fn handle_tick(
     send
 }
 
-async fn handle_sends(
-    send_list: Vec<PublishableMessage>,
-    mqtt_sender_tx: &Sender<PublishableMessage>,
-) {
+async fn handle_sends(send_list: Vec<PublishableMessage>, mqtt_sender_tx: &Publisher) {
     for item in send_list.into_iter() {
         if let Err(err) = mqtt_sender_tx.send(item).await {
             warn!("Error putting message in MQTT queue for net: {} ", err);
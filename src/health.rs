@@ -0,0 +1,207 @@
+//! HELPER: Per module health and heartbeat
+//!
+//! Every module is given a `ModuleHealth` handle, which it uses to mark when it did
+//! useful work and when it hit an error.  The supervisor drives the state through
+//! starting/running/degraded/failed.
+//!
+//! Health is published to `<base_node>/Daemon/<Module>/Health` every `HEALTH_PERIOD`,
+//! and immediately whenever the state changes, with the values:
+//! `[state, seconds since last useful work (-1 if never), error count]`
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, AtomicU64, Ordering},
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::{PublishableMessage, publish::Publisher};
+
+/// How often health is published when nothing changes
+const HEALTH_PERIOD: Duration = Duration::from_secs(5);
+
+/// The state of a module, published as its number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModuleState {
+    /// Started or restarted, has not done useful work yet
+    Starting = 0,
+    /// Doing useful work
+    Running = 1,
+    /// Struggling, for example waiting to be restarted
+    Degraded = 2,
+    /// Stopped with an error and not coming back
+    Failed = 3,
+    /// Stopped on purpose
+    Stopped = 4,
+}
+
+impl ModuleState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModuleState::Starting => "starting",
+            ModuleState::Running => "running",
+            ModuleState::Degraded => "degraded",
+            ModuleState::Failed => "failed",
+            ModuleState::Stopped => "stopped",
+        }
+    }
+}
+
+struct HealthInner {
+    state: watch::Sender<ModuleState>,
+    /// unix time of the last useful work in us, 0 if never
+    last_work_us: AtomicU64,
+    errors: AtomicU32,
+}
+
+/// A cheap to clone handle on the health of a module
+#[derive(Clone)]
+pub struct ModuleHealth {
+    inner: Arc<HealthInner>,
+}
+
+impl Default for ModuleHealth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ModuleHealth {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(HealthInner {
+                state: watch::channel(ModuleState::Starting).0,
+                last_work_us: AtomicU64::new(0),
+                errors: AtomicU32::new(0),
+            }),
+        }
+    }
+
+    /// Set the state, only notifying if it changed
+    pub fn set_state(&self, state: ModuleState) {
+        self.inner.state.send_if_modified(|curr| {
+            if *curr == state {
+                false
+            } else {
+                *curr = state;
+                true
+            }
+        });
+    }
+
+    /// Mark that useful work was done, which also marks a starting or degraded module running
+    pub fn work(&self) {
+        self.inner.last_work_us.store(
+            UNIX_EPOCH.elapsed().unwrap().as_micros() as u64,
+            Ordering::Relaxed,
+        );
+        if matches!(
+            *self.inner.state.borrow(),
+            ModuleState::Starting | ModuleState::Degraded
+        ) {
+            self.set_state(ModuleState::Running);
+        }
+    }
+
+    /// Count an error
+    pub fn error(&self) {
+        self.inner.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn state(&self) -> ModuleState {
+        *self.inner.state.borrow()
+    }
+
+    /// Unix time of the last useful work in us, None if never
+    pub fn last_work_us(&self) -> Option<u64> {
+        match self.inner.last_work_us.load(Ordering::Relaxed) {
+            0 => None,
+            time => Some(time),
+        }
+    }
+
+    pub fn errors(&self) -> u32 {
+        self.inner.errors.load(Ordering::Relaxed)
+    }
+
+    fn to_message(&self, topic: &str) -> PublishableMessage {
+        let now = UNIX_EPOCH.elapsed().unwrap().as_micros() as u64;
+        let since_work = match self.last_work_us() {
+            Some(time) => now.saturating_sub(time) as f32 / 1e6,
+            None => -1f32,
+        };
+        PublishableMessage {
+            topic: topic.to_string(),
+            data: vec![self.state() as u8 as f32, since_work, self.errors() as f32],
+            unit: "state".to_string(),
+            time: now,
+            options: None,
+        }
+    }
+}
+
+/// The health of every running module, by name
+#[derive(Clone, Default)]
+pub struct HealthBoard(Arc<Mutex<BTreeMap<&'static str, ModuleHealth>>>);
+
+impl HealthBoard {
+    pub fn insert(&self, name: &'static str, health: ModuleHealth) {
+        self.0.lock().unwrap().insert(name, health);
+    }
+
+    pub fn remove(&self, name: &'static str) {
+        self.0.lock().unwrap().remove(name);
+    }
+
+    /// The modules and their health, sorted by name
+    pub fn modules(&self) -> Vec<(&'static str, ModuleHealth)> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, health)| (*name, health.clone()))
+            .collect()
+    }
+}
+
+/// Publish the health of a module periodically and on every state change
+pub async fn health_reporter(
+    cancel_token: CancellationToken,
+    name: &'static str,
+    health: ModuleHealth,
+    base_node: String,
+    mqtt_sender_tx: Publisher,
+) {
+    let topic = format!("{base_node}/Daemon/{name}/Health");
+    let mut state_recv = health.inner.state.subscribe();
+    let mut period = tokio::time::interval(HEALTH_PERIOD);
+
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => {
+                debug!("Shutting down health reporter for {}", name);
+                // a last report of the final state, unless the queue is stuck
+                if tokio::time::timeout(HEALTH_PERIOD, mqtt_sender_tx.send(health.to_message(&topic)))
+                    .await
+                    .is_err()
+                {
+                    warn!("Could not publish final health of {}", name);
+                }
+                break;
+            },
+            _ = period.tick() => {},
+            Ok(_) = state_recv.changed() => {
+                state_recv.borrow_and_update();
+            },
+        }
+        if let Err(err) = mqtt_sender_tx.send(health.to_message(&topic)).await {
+            warn!("Could not publish health of {}: {}", name, err);
+        }
+    }
+}
@@ -1,6 +1,24 @@
 // HELPERS
+pub mod api;
 pub mod can_handler;
+pub mod config;
+pub mod control;
+pub mod daemon_log;
+pub mod dual;
+pub mod health;
+pub mod lvc;
+pub mod metrics;
+pub mod module;
 pub mod mqtt_handler;
+pub mod publish;
+pub mod remap;
+pub mod sd_notify;
+pub mod session;
+pub mod sim;
+pub mod spool;
+pub mod supervisor;
+pub mod topic;
+pub mod transport;
 pub mod uploader;
 pub mod zenoh_handler;
 
@@ -16,9 +34,9 @@ pub mod lockdown;
 pub mod logger;
 pub mod net;
 pub mod numerical;
+pub mod storage;
 pub mod sys_parser;
 pub mod visual;
-pub mod zenoh_bridge;
 
 // PROTOBUF
 pub mod playback_data;
@@ -31,19 +49,17 @@ pub struct PublishableMessage {
     pub data: Vec<f32>,
     pub unit: String,
     pub time: u64,
+    /// Overrides the `[mqtt]` rules of the topic
+    pub options: Option<config::PublishOptions>,
 }
 
 /// Indicate a HV transition
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum HVTransition {
-    TransitionOn(HVOnData),
+    /// The session of the event, see `session`
+    TransitionOn(session::EventSession),
     TransitionOff,
 }
-#[derive(Clone, Copy)]
-pub struct HVOnData {
-    /// Time HV enabled
-    pub time_ms: u64,
-}
 
 /// the topic to listen for for HV enable, 1 is off and 0 is on
 pub const HV_EN_TOPIC: &str = "BMS/Shutdown/State";
@@ -59,7 +75,3 @@ pub const SEND_LOGGER_DATA: &str = "Scylla/Logger/Send";
 
 /// the topic to listen for when to send serial data to scylla, 1 means send
 pub const SEND_SERIAL_DATA: &str = "Scylla/Serial/Send";
-
-///pub const SEND_
-/// The save location for all files
-pub static SAVE_LOCATION: std::sync::OnceLock<String> = std::sync::OnceLock::new();
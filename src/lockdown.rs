@@ -8,15 +8,49 @@
 //!  - `/dev/ttyCerberus` and `/dev/ttyShepherd`
 use std::{error::Error, process::Stdio, time::Duration};
 
-use tokio::{
-    io,
-    process::{Child, Command},
-    sync::watch::Receiver,
-};
+use futures_util::future::BoxFuture;
+use tokio::{io, process::Child, sync::watch::Receiver};
 use tokio_util::sync::CancellationToken;
 use tracing::{info, warn};
 
-use crate::{HVTransition, SAVE_LOCATION};
+use crate::{
+    HVTransition,
+    health::{ModuleHealth, ModuleState},
+    module::{Module, ModuleContext, ModuleInputs, ModuleResult},
+    session::{ArtifactKind, EventSession},
+    sim,
+};
+
+/// The lockdown module, see `lockdown_runner`
+pub struct LockdownModule {
+    usbs: Vec<String>,
+}
+
+impl LockdownModule {
+    pub fn new(usbs: Vec<String>) -> Self {
+        Self { usbs }
+    }
+}
+
+impl Module for LockdownModule {
+    fn name(&self) -> &'static str {
+        "Lockdown"
+    }
+
+    fn inputs(&self) -> ModuleInputs {
+        ModuleInputs {
+            hv: true,
+            ..Default::default()
+        }
+    }
+
+    fn run(&self, mut ctx: ModuleContext) -> BoxFuture<'static, ModuleResult> {
+        let usbs = self.usbs.clone();
+        Box::pin(async move {
+            lockdown_runner(ctx.cancel_token.clone(), ctx.hv()?, usbs, ctx.health).await
+        })
+    }
+}
 
 /// Run various HV on/off lockdowns
 /// Takes in a receiver of HV state
@@ -24,12 +58,15 @@ pub async fn lockdown_runner(
     cancel_token: CancellationToken,
     mut hv_stat_recv: Receiver<HVTransition>,
     usbs: Vec<String>,
+    health: ModuleHealth,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     let mut cmds: Option<(Child, Child)> = None;
     info!("Unlocking initially!");
     if let Err(err) = hv_transition_disabled(&mut cmds, &usbs).await {
         warn!("Could not unlock!!! {}", err);
+        health.error();
     }
+    health.set_state(ModuleState::Running);
 
     loop {
         tokio::select! {
@@ -41,20 +78,25 @@ pub async fn lockdown_runner(
             },
             new = hv_stat_recv.changed() => {
                 new?;
-                let curr_data = *hv_stat_recv.borrow_and_update();
+                let curr_data = hv_stat_recv.borrow_and_update().clone();
                  match curr_data {
-                    HVTransition::TransitionOn(hvon_data) => {
+                    HVTransition::TransitionOn(session) => {
                         info!("Locking down!");
-                        let Ok(children) = hv_transition_enabled(hvon_data.time_ms, &usbs).await else {
+                        let Ok(children) = hv_transition_enabled(&session, &usbs).await else {
                             warn!("Could not lock down!!!");
+                            health.error();
                             continue;
                         };
                         cmds = Some(children);
+                        health.work();
                     },
                     HVTransition::TransitionOff => {
                         info!("Unlocking!");
                     if let Err(err) = hv_transition_disabled(&mut cmds, &usbs).await {
                         warn!("Could not unlock!!! {}", err);
+                        health.error();
+                    } else {
+                        health.work();
                     }
                     },
                 }
@@ -65,10 +107,13 @@ pub async fn lockdown_runner(
 }
 
 /// Transition to HV on
-pub async fn hv_transition_enabled(time_ms: u64, usbs: &Vec<String>) -> io::Result<(Child, Child)> {
+pub async fn hv_transition_enabled(
+    session: &EventSession,
+    usbs: &Vec<String>,
+) -> io::Result<(Child, Child)> {
     // unbind from the usbipd server
     for item in usbs {
-        Command::new("usbip")
+        sim::command("usbip")
             .args(["unbind", "--busid", item])
             .spawn()?
             .wait()
@@ -77,11 +122,11 @@ pub async fn hv_transition_enabled(time_ms: u64, usbs: &Vec<String>) -> io::Resu
 
     tokio::time::sleep(Duration::from_secs(2)).await;
 
-    let mut cmd_cerb_conf = Command::new("stty")
+    let mut cmd_cerb_conf = sim::command("stty")
         .args(["-F", "/dev/ttyCerberus", "115200"])
         .spawn()?;
 
-    let mut cmd_shep_conf = Command::new("stty")
+    let mut cmd_shep_conf = sim::command("stty")
         .args(["-F", "/dev/ttyShepherd", "115200"])
         .spawn()?;
 
@@ -93,37 +138,17 @@ pub async fn hv_transition_enabled(time_ms: u64, usbs: &Vec<String>) -> io::Resu
     // if !cmd_cerb_dis.wait().await.unwrap().success() && !cmd_shep_dis.wait().await.unwrap().success()  {
     //     info!("Failed to run USBIP command(s) to unbind");
     // }
-    let cerb_save_loc = format!(
-        "{}/event-{}/cerberus-dump.cap",
-        SAVE_LOCATION.get().unwrap(),
-        time_ms
-    );
-    let shep_save_loc = format!(
-        "{}/event-{}/shepherd-dump.cap",
-        SAVE_LOCATION.get().unwrap(),
-        time_ms
-    );
+    let cerb_save_loc = session.artifact("Lockdown", ArtifactKind::CerberusSerial);
+    let shep_save_loc = session.artifact("Lockdown", ArtifactKind::ShepherdSerial);
     Ok((
-        Command::new("minicom")
-            .args([
-                "-D",
-                "/dev/ttyCerberus",
-                "-O",
-                "timestamp=extended",
-                "-C",
-                &cerb_save_loc,
-            ])
+        sim::command("minicom")
+            .args(["-D", "/dev/ttyCerberus", "-O", "timestamp=extended", "-C"])
+            .arg(&cerb_save_loc)
             .stdout(Stdio::null())
             .spawn()?,
-        Command::new("minicom")
-            .args([
-                "-D",
-                "/dev/ttyCerberus",
-                "-O",
-                "timestamp=extended",
-                "-C",
-                &shep_save_loc,
-            ])
+        sim::command("minicom")
+            .args(["-D", "/dev/ttyCerberus", "-O", "timestamp=extended", "-C"])
+            .arg(&shep_save_loc)
             .stdout(Stdio::null())
             .spawn()?,
     ))
@@ -136,7 +161,7 @@ pub async fn hv_transition_disabled(
 ) -> io::Result<()> {
     // bind to the usbipd daemon
     for usb in usbs {
-        Command::new("usbip")
+        sim::command("usbip")
             .args(["bind", "--busid", usb])
             .spawn()?
             .wait()
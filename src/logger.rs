@@ -1,33 +1,89 @@
 //! Plaintext data logger using length prepended protobuf.
-//! It creates a file upon HV going on, and writes all topics to it.
+//! It creates a file upon HV going on, and writes all topics matching `include` to it.
 //! This file can then be uploaded with the uploader binary included.
 //!
 //! Beta, well tested
 //!
 //! Requires:
-//!  - All MQTT messages
+//!  - The MQTT messages to log
 //!  - Getting HV topic signal
 //!
 
 use std::error::Error;
 
+use futures_util::future::BoxFuture;
 use protobuf::Message;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::{
     fs::File,
     io::{AsyncWriteExt, BufWriter},
+    sync::broadcast::error::RecvError,
 };
 use tokio_util::sync::CancellationToken;
 use tracing::{info, warn};
 
-use crate::{HVTransition, SAVE_LOCATION, playback_data};
+use crate::{
+    HVTransition,
+    health::{ModuleHealth, ModuleState},
+    metrics::METRICS,
+    module::{Module, ModuleContext, ModuleInputs, ModuleResult},
+    playback_data,
+    session::ArtifactKind,
+    topic,
+};
+
+/// The logger module, see `logger_manager`
+pub struct LoggerModule {
+    include: Vec<String>,
+}
+
+impl LoggerModule {
+    /// Log the topics matching any of the include filters
+    pub fn new(include: Vec<String>) -> LoggerModule {
+        LoggerModule { include }
+    }
+}
+
+impl Module for LoggerModule {
+    fn name(&self) -> &'static str {
+        "Logger"
+    }
+
+    fn inputs(&self) -> ModuleInputs {
+        ModuleInputs {
+            received: true,
+            hv: true,
+            ..Default::default()
+        }
+    }
+
+    fn topics(&self) -> Vec<String> {
+        self.include.clone()
+    }
+
+    fn run(&self, mut ctx: ModuleContext) -> BoxFuture<'static, ModuleResult> {
+        let include = self.include.clone();
+        Box::pin(async move {
+            logger_manager(
+                ctx.cancel_token.clone(),
+                ctx.received()?,
+                ctx.hv()?,
+                include,
+                ctx.health,
+            )
+            .await
+        })
+    }
+}
 
 /// runs the mute/unmute functionality
-/// Takes in a receiver of all MQTT messages
+/// Takes in a receiver of all MQTT messages, logging those matching an include filter
 pub async fn logger_manager(
     cancel_token: CancellationToken,
     mut mqtt_recv_rx: tokio::sync::broadcast::Receiver<playback_data::PlaybackData>,
     mut hv_stat_recv: tokio::sync::watch::Receiver<HVTransition>,
+    include: Vec<String>,
+    health: ModuleHealth,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     let mut writer: Option<BufWriter<File>> = None;
 
@@ -39,6 +95,8 @@ pub async fn logger_manager(
         info!("Waiting for good time");
         tokio::time::sleep(Duration::from_secs(1)).await;
     }
+    // sitting idle until HV on is normal
+    health.set_state(ModuleState::Running);
 
     loop {
         tokio::select! {
@@ -50,11 +108,17 @@ pub async fn logger_manager(
             },
             new = hv_stat_recv.changed() => {
               new?;
-              let val = *hv_stat_recv.borrow_and_update();
+              let val = hv_stat_recv.borrow_and_update().clone();
               match val {
-                  HVTransition::TransitionOn(hvon_data) => {
-                        let filename = format!("{}/event-{}/data_dump.log", SAVE_LOCATION.get().unwrap(), hvon_data.time_ms);
-                        writer = Some(BufWriter::new(File::create_new(filename).await.expect("Could not create log file!")));
+                  HVTransition::TransitionOn(session) => {
+                        let filename = session.artifact("Logger", ArtifactKind::DataLog);
+                        match File::create_new(&filename).await {
+                            Ok(file) => writer = Some(BufWriter::new(file)),
+                            Err(err) => {
+                                warn!("Could not create log file {}: {}", filename.display(), err);
+                                health.error();
+                            }
+                        }
                   },
                   HVTransition::TransitionOff => {
                     if let Some(writ) = writer.as_mut() {
@@ -69,19 +133,30 @@ pub async fn logger_manager(
 
             },
             msg = mqtt_recv_rx.recv() => {
+                if let Err(RecvError::Lagged(cnt)) = msg {
+                    METRICS.lagged("Logger", cnt);
+                }
                 if writer.is_none() {
                     continue;
                 }
 
                 match msg  {
+                    // received for another module
+                    Ok(msg) if !include.iter().any(|filter| topic::matches(filter, &msg.topic)) => {}
                     Ok(msg) => {
-                        if let Some(writ) = writer.as_mut()
-                            && let Err(err) = writ.write(&msg.write_length_delimited_to_bytes().unwrap()).await {
-                                warn!("Could not write to log! {}", err);
+                        if let Some(writ) = writer.as_mut() {
+                            match writ.write(&msg.write_length_delimited_to_bytes().unwrap()).await {
+                                Ok(_) => health.work(),
+                                Err(err) => {
+                                    warn!("Could not write to log! {}", err);
+                                    health.error();
+                                }
                             }
+                        }
                     },
                     Err(err) => {
                         warn!("Could not receive message: Err: {}", err);
+                        health.error();
                         continue;
                     }
                 }
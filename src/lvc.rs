@@ -0,0 +1,159 @@
+//! HELPER: Zenoh last-value cache, so a late joiner gets the whole car state at once
+//!
+//! With `[zenoh] lvc`, the Zenoh transport keeps the newest `ServerData` (by `time_us`, so
+//! backfilled replays do not hide live values) of every key it puts, and answers a `get` on
+//! `<lvc_key>/**` with each cached sample matching the selector, as `<lvc_key>/<key>`.
+//! For example `z_get -s 'tpu/lvc/**'` fetches a snapshot of everything, and
+//! `tpu/lvc/TPU/GPS/*` only the GPS values.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use protobuf::Message;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace, warn};
+use zenoh::{
+    bytes::Encoding,
+    handlers::FifoChannelHandler,
+    key_expr::KeyExpr,
+    query::{Query, Queryable},
+};
+
+use crate::serverdata;
+
+/// The latest payload put on each key, see `lvc`
+pub struct Lvc {
+    key: String,
+    /// By key, the time of the payload and the payload
+    latest: Mutex<BTreeMap<String, (u64, Vec<u8>)>>,
+}
+
+impl Lvc {
+    /// An empty cache, answering queries under `key`
+    pub fn new(key: String) -> Lvc {
+        Lvc {
+            key,
+            latest: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The key expression of the queryable
+    pub fn key_expr(&self) -> String {
+        format!("{}/**", self.key)
+    }
+
+    /// Remember a `ServerData` put on a key, unless a newer one is cached
+    pub fn update(&self, key: &str, payload: &[u8]) {
+        let time = match serverdata::ServerData::parse_from_bytes(payload) {
+            Ok(data) => data.time_us,
+            Err(err) => {
+                trace!("Not caching {}, not server data: {}", key, err);
+                return;
+            }
+        };
+        let mut latest = self.latest.lock().unwrap();
+        match latest.get_mut(key) {
+            Some((cached_time, _)) if *cached_time > time => {}
+            Some(cached) => *cached = (time, payload.to_vec()),
+            None => {
+                latest.insert(key.to_string(), (time, payload.to_vec()));
+            }
+        }
+    }
+
+    /// The cached samples matching a query, by their key under the LVC key
+    pub fn answer(&self, selector: &KeyExpr) -> Vec<(String, Vec<u8>)> {
+        self.latest
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(key, (_, payload))| {
+                let reply_key = KeyExpr::try_from(format!("{}/{}", self.key, key)).ok()?;
+                selector
+                    .intersects(&reply_key)
+                    .then(|| (reply_key.to_string(), payload.clone()))
+            })
+            .collect()
+    }
+}
+
+/// Answer queries from the cache until cancelled or the session closes
+pub async fn serve(
+    cancel_token: CancellationToken,
+    queryable: Queryable<FifoChannelHandler<Query>>,
+    lvc: Arc<Lvc>,
+) {
+    loop {
+        let query = tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            query = queryable.recv_async() => match query {
+                Ok(query) => query,
+                Err(_) => break,
+            },
+        };
+        let answer = lvc.answer(query.key_expr());
+        trace!(
+            "Answering {} with {} samples",
+            query.key_expr(),
+            answer.len()
+        );
+        for (key, payload) in answer {
+            if let Err(err) = query
+                .reply(key, payload)
+                .encoding(Encoding::APPLICATION_PROTOBUF)
+                .await
+            {
+                warn!("Could not answer LVC query {}: {}", query.key_expr(), err);
+                break;
+            }
+        }
+    }
+    debug!("Shutting down LVC queryable");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(time_us: u64, value: f32) -> Vec<u8> {
+        let mut data = serverdata::ServerData::new();
+        data.time_us = time_us;
+        data.values = vec![value];
+        data.write_to_bytes().unwrap()
+    }
+
+    fn selector(key_expr: &str) -> KeyExpr<'static> {
+        KeyExpr::try_from(key_expr.to_string()).unwrap()
+    }
+
+    #[test]
+    fn keeps_newest_and_answers_matching() {
+        let lvc = Lvc::new("tpu/lvc".to_string());
+        lvc.update("TPU/GPS/Speed", &data(10, 1.0));
+        lvc.update("TPU/GPS/Mode", &data(5, 3.0));
+        lvc.update("BMS/State", &data(7, 2.0));
+        // a backfilled replay is older, a live value newer
+        lvc.update("TPU/GPS/Speed", &data(8, 0.5));
+        lvc.update("BMS/State", &data(9, 4.0));
+        lvc.update("BMS/Garbage", b"\xff\xff");
+
+        assert_eq!(
+            lvc.answer(&selector(&lvc.key_expr())),
+            vec![
+                ("tpu/lvc/BMS/State".to_string(), data(9, 4.0)),
+                ("tpu/lvc/TPU/GPS/Mode".to_string(), data(5, 3.0)),
+                ("tpu/lvc/TPU/GPS/Speed".to_string(), data(10, 1.0)),
+            ]
+        );
+        assert_eq!(
+            lvc.answer(&selector("tpu/lvc/TPU/GPS/*"))
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect::<Vec<_>>(),
+            vec!["tpu/lvc/TPU/GPS/Mode", "tpu/lvc/TPU/GPS/Speed"]
+        );
+        assert!(lvc.answer(&selector("tpu/other/**")).is_empty());
+    }
+}
@@ -1,103 +1,127 @@
-use std::sync::Arc;
+use std::{error::Error, process::ExitCode, sync::Arc};
 
-use clap::Parser;
+use clap::{Parser, builder::BoolishValueParser};
 use odysseus_daemon::{
-    HVTransition, PublishableMessage, SAVE_LOCATION,
-    audible::audible_manager,
-    can::can_data_scraper,
+    api::api_server,
     can_handler::can_handler,
-    color::color_controller,
-    daq_monitor::monitor_daq,
-    gps::gps_manager,
-    halow::halow_scraper,
-    lockdown::lockdown_runner,
-    logger::logger_manager,
-    mqtt_handler::MqttProcessor,
-    net::network_scraper,
-    numerical::collect_data,
-    playback_data,
-    sys_parser::sys_parser,
-    visual::{SavePipelineOpts, run_save_pipeline},
-    zenoh_bridge::{zenoh_fwd, zenoh_rev},
-    zenoh_handler::ZenohProcessor,
+    config::DaemonConfig,
+    control::{ControlRequest, handle_control, parse_control},
+    daemon_log,
+    dual::DualTransport,
+    module::{ModuleRegistry, RunningModules},
+    mqtt_handler::MqttTransport,
+    playback_data::PlaybackData,
+    publish::{Publisher, drop_reporter},
+    sd_notify::{Heartbeat, Notifier, watchdog, watchdog_period},
+    sim,
+    spool::{SPOOL_FOLDER, Spool},
+    transport::{Dispatcher, Transport},
+    zenoh_handler::{ZenohTransport, liveliness, peer_alive},
 };
-use rumqttc::v5::{AsyncClient, mqttbytes::v5::Publish};
 use tokio::{
-    signal,
-    sync::{broadcast, mpsc, watch},
+    signal::{
+        self,
+        unix::{SignalKind, signal},
+    },
+    sync::mpsc,
 };
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
-use tracing::{info, level_filters::LevelFilter};
-use tracing_subscriber::{EnvFilter, fmt::format::FmtSpan};
-
-use socketcan::CanFrame;
+use tracing::{info, warn};
 
 /// ody-visual command line arguments
-#[derive(Parser, Debug)]
+/// Anything given here overrides the config file
+#[derive(Parser, Debug, Clone)]
 #[command(version)]
 struct VisualArgs {
+    /// The TOML or JSON5 config file, with one section per module
+    #[arg(long, env = "ODYSSEUS_DAEMON_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Print the effective configuration (config file merged with args) and exit
+    #[arg(long)]
+    print_config: bool,
+
     /// Augment HV on
-    #[arg(long, env = "ODYSSEUS_DAEMON_AUGMENT_HV")]
-    mock: bool,
+    #[arg(long, env = "ODYSSEUS_DAEMON_AUGMENT_HV", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    mock: Option<bool>,
+
+    /// Log to stdout as JSON lines instead of text
+    #[arg(long, env = "ODYSSEUS_DAEMON_LOG_JSON", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    log_json: Option<bool>,
+
+    /// Also log to a rotating file in the output folder
+    #[arg(long, env = "ODYSSEUS_DAEMON_LOG_FILE", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    log_file: Option<bool>,
+
+    /// Run every module against simulated hardware, for running on a laptop
+    #[arg(long, env = "ODYSSEUS_DAEMON_SIM", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    sim: Option<bool>,
 
     /// Enable lockdown module
-    #[arg(short = 's', long, env = "ODYSSEUS_DAEMON_LOCKDOWN_ENABLE")]
-    lockdown: bool,
+    #[arg(short = 's', long, env = "ODYSSEUS_DAEMON_LOCKDOWN_ENABLE", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    lockdown: Option<bool>,
 
     /// USB device ports (as seen on usbip list -l) to lock down
     #[arg(long, env = "ODYSSEUS_DAEMON_LOCKDOWN_USBS", num_args = 1..4)]
     usbs_locked: Option<Vec<String>>,
 
     /// Enable audio module
-    #[arg(short = 'a', long, env = "ODYSSEUS_DAEMON_AUDIBLE_ENABLE")]
-    audible: bool,
+    #[arg(short = 'a', long, env = "ODYSSEUS_DAEMON_AUDIBLE_ENABLE", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    audible: Option<bool>,
 
     /// Enable data module
-    #[arg(short = 'd', long, env = "ODYSSEUS_DAEMON_DATA_ENABLE")]
-    data: bool,
+    #[arg(short = 'd', long, env = "ODYSSEUS_DAEMON_DATA_ENABLE", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    data: Option<bool>,
 
     /// Enable daq module
-    #[arg(long, env = "ODYSSEUS_DAEMON_DAQ_ENABLE")]
-    daq: bool,
+    #[arg(long, env = "ODYSSEUS_DAEMON_DAQ_ENABLE", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    daq: Option<bool>,
 
     /// Daq USB device
     #[arg(long, env = "ODYSSEUS_DAEMON_DAQ_DEVICE")]
     daq_device: Option<String>,
 
     /// Enable logger
-    #[arg(long, env = "ODYSSEUS_DAEMON_LOGGER_ENABLE")]
-    logger: bool,
+    #[arg(long, env = "ODYSSEUS_DAEMON_LOGGER_ENABLE", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    logger: Option<bool>,
 
     /// Enable video module
-    #[arg(short = 'v', long, env = "ODYSSEUS_DAEMON_VIDEO_ENABLE")]
-    video: bool,
+    #[arg(short = 'v', long, env = "ODYSSEUS_DAEMON_VIDEO_ENABLE", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    video: Option<bool>,
 
     /// Enable Mosquitto SYS translator module
-    #[arg(long, env = "ODYSSEUS_DAEMON_SYS_ENABLE")]
-    sys: bool,
+    #[arg(long, env = "ODYSSEUS_DAEMON_SYS_ENABLE", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    sys: Option<bool>,
 
     /// Enable wheel LED color controller
-    #[arg(long, env = "ODYSSEUS_DAEMON_COLOR_ENABLE")]
-    color: bool,
+    #[arg(long, env = "ODYSSEUS_DAEMON_COLOR_ENABLE", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    color: Option<bool>,
 
     /// The input video file
     #[arg(short = 'l', long, env = "ODYSSEUS_DAEMON_VIDEO_FILE")]
     video_uri: Option<String>,
 
-    /// The MQTT/Siren URL
-    #[arg(
-        short = 'u',
-        long,
-        default_value = "localhost:1883",
-        env = "ODYSSEUS_DAEMON_SIREN_URL"
-    )]
-    mqtt_url: String,
+    /// The MQTT/Siren URL [default: localhost:1883]
+    #[arg(short = 'u', long, env = "ODYSSEUS_DAEMON_SIREN_URL")]
+    mqtt_url: Option<String>,
+
+    /// The MQTT username, the password is read from ODYSSEUS_DAEMON_MQTT_PASSWORD or mqtt.password_file
+    #[arg(long, env = "ODYSSEUS_DAEMON_MQTT_USERNAME")]
+    mqtt_username: Option<String>,
+
+    /// A file holding the MQTT password
+    #[arg(long, env = "ODYSSEUS_DAEMON_MQTT_PASSWORD_FILE")]
+    mqtt_password_file: Option<PathBuf>,
+
+    /// The CA certificate to verify the MQTT broker with, for mqtts:// URLs
+    #[arg(long, env = "ODYSSEUS_DAEMON_MQTT_CA")]
+    mqtt_ca: Option<PathBuf>,
 
     /// Use Zenoh instead of MQTT -- will eventually become default
-    #[arg(short = 'z', long, env = "ODYSSEUS_DAEMON_ZENOH")]
-    zenoh: bool,
+    #[arg(short = 'z', long, env = "ODYSSEUS_DAEMON_ZENOH", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    zenoh: Option<bool>,
 
     /// The Scylla URL
     #[arg(short = 'S', long, env = "ODYSSEUS_DAEMON_SCYLLA_URL")]
@@ -105,289 +129,575 @@ struct VisualArgs {
 
     /// The output folder of data (videos, audio, text logs, etc), no trailing slash
     #[arg(short = 'f', long, env = "ODYSSEUS_DAEMON_OUTPUT_FOLDER")]
-    output_folder: String,
+    output_folder: Option<String>,
 
-    /// The SocketCAN interface port
-    #[arg(
-        short = 'c',
-        long,
-        env = "ODYSSEUS_DAEMON_SOCKETCAN_IFACE",
-        default_value = "vcan0"
-    )]
-    socketcan_iface: String,
+    /// The SocketCAN interface port [default: vcan0]
+    #[arg(short = 'c', long, env = "ODYSSEUS_DAEMON_SOCKETCAN_IFACE")]
+    socketcan_iface: Option<String>,
 
     /// Whether to enable the GPS module
-    #[arg(long, env = "ODYSSEUS_DAEMON_GPS")]
-    gps: bool,
+    #[arg(long, env = "ODYSSEUS_DAEMON_GPS", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    gps: Option<bool>,
 
     /// Whether to enable the Network statistics module
-    #[arg(long, env = "ODYSSEUS_DAEMON_NET_STATS")]
-    net: bool,
+    #[arg(long, env = "ODYSSEUS_DAEMON_NET_STATS", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    net: Option<bool>,
 
     /// Whether to enable the Network statistics module
-    #[arg(long, env = "ODYSSEUS_DAEMON_HALOW_STATS")]
-    halow: bool,
+    #[arg(long, env = "ODYSSEUS_DAEMON_HALOW_STATS", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    halow: Option<bool>,
 
     /// Whether to enable the Network statistics module
-    #[arg(long, env = "ODYSSEUS_DAEMON_CAN_STATS")]
-    can: bool,
+    #[arg(long, env = "ODYSSEUS_DAEMON_CAN_STATS", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    can: Option<bool>,
+
+    /// Enable the storage manager, deleting old events to keep free space
+    #[arg(long, env = "ODYSSEUS_DAEMON_STORAGE_ENABLE", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    storage: Option<bool>,
 
     /// The internet interfaces to gather statistics from (for net)
     #[arg(long, env = "ODYSSEUS_DAEMON_NET_IFACES")]
     net_ifaces: Option<Vec<String>>,
 
-    /// The Halow interfaces to gather statistics from (for halow)
-    #[arg(long, env = "ODYSSEUS_DAEMON_HW_IFACES", default_value = "wlan0")]
-    hw_iface: String,
+    /// The Halow interfaces to gather statistics from (for halow) [default: wlan0]
+    #[arg(long, env = "ODYSSEUS_DAEMON_HW_IFACES")]
+    hw_iface: Option<String>,
 
-    /// The base MQTT topic/node name (for net, halow)
-    #[arg(long, env = "ODYSSEUS_DAEMON_BASE_NODE", default_value = "TPU")]
-    base_node: String,
+    /// The base MQTT topic/node name (for net, halow) [default: TPU]
+    #[arg(long, env = "ODYSSEUS_DAEMON_BASE_NODE")]
+    base_node: Option<String>,
 
-    /// Zenoh bridge (MQTT->Zenoh) support
+    /// Use Zenoh alongside MQTT, routing topics between them per the [routing] config
+    #[arg(long, env = "ODYSSEUS_DAEMON_DUAL", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    dual: Option<bool>,
+
+    /// With both transports, forward messages received on one to the other
+    #[arg(long, env = "ODYSSEUS_DAEMON_BRIDGE", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    bridge: Option<bool>,
+
+    /// Deprecated, same as --dual --bridge
     #[arg(long, env = "ODYSSEUS_DAEMON_ZENOH_BRIDGE_FWD")]
     zenoh_fwd: bool,
 
-    /// Zenoh bridge (Zenoh->MQTT) support
+    /// Deprecated, same as --dual --bridge
     #[arg(long, env = "ODYSSEUS_DAEMON_ZENOH_BRIDGE_REV")]
     zenoh_rev: bool,
 
-    /// Zenoh bridge (Zenoh->MQTT) support
-    #[arg(
-        long,
-        env = "ODYSSEUS_DAEMON_ZENOH_CONF",
-        default_value_os = "./zenoh.json5"
-    )]
-    zenoh_conf: PathBuf,
+    /// The Zenoh config file [default: ./zenoh.json5]
+    #[arg(long, env = "ODYSSEUS_DAEMON_ZENOH_CONF")]
+    zenoh_conf: Option<PathBuf>,
+
+    /// Answer Zenoh queries on the LVC key with the latest value of every topic
+    #[arg(long, env = "ODYSSEUS_DAEMON_ZENOH_LVC", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    zenoh_lvc: Option<bool>,
+
+    /// Spool outgoing messages to disk while disconnected, replaying them once connected
+    #[arg(long, env = "ODYSSEUS_DAEMON_SPOOL_ENABLE", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    spool: Option<bool>,
+
+    /// Enable the local HTTP status API
+    #[arg(long, env = "ODYSSEUS_DAEMON_API_ENABLE", num_args = 0..=1, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    api: Option<bool>,
+
+    /// The port of the HTTP status API [default: 8080]
+    #[arg(long, env = "ODYSSEUS_DAEMON_API_PORT")]
+    api_port: Option<u16>,
+}
+
+impl VisualArgs {
+    /// Apply the args on top of the config file, a flag given as true or false overrides it
+    fn apply(self, conf: &mut DaemonConfig) {
+        conf.general.augment_hv = self.mock.unwrap_or(conf.general.augment_hv);
+        conf.general.sim = self.sim.unwrap_or(conf.general.sim);
+        conf.log.json = self.log_json.unwrap_or(conf.log.json);
+        conf.log.file = self.log_file.unwrap_or(conf.log.file);
+        conf.lockdown.enable = self.lockdown.unwrap_or(conf.lockdown.enable);
+        conf.audible.enable = self.audible.unwrap_or(conf.audible.enable);
+        conf.data.enable = self.data.unwrap_or(conf.data.enable);
+        conf.daq.enable = self.daq.unwrap_or(conf.daq.enable);
+        conf.logger.enable = self.logger.unwrap_or(conf.logger.enable);
+        conf.video.enable = self.video.unwrap_or(conf.video.enable);
+        conf.sys.enable = self.sys.unwrap_or(conf.sys.enable);
+        conf.color.enable = self.color.unwrap_or(conf.color.enable);
+        conf.gps.enable = self.gps.unwrap_or(conf.gps.enable);
+        conf.net.enable = self.net.unwrap_or(conf.net.enable);
+        conf.halow.enable = self.halow.unwrap_or(conf.halow.enable);
+        conf.can.enable = self.can.unwrap_or(conf.can.enable);
+        conf.storage.enable = self.storage.unwrap_or(conf.storage.enable);
+        // the deprecated bridge flags are --dual --bridge
+        let deprecated_bridge = self.zenoh_fwd || self.zenoh_rev;
+        let dual = if deprecated_bridge {
+            Some(true)
+        } else {
+            self.dual
+        };
+        match (dual, self.zenoh) {
+            (Some(true), _) => {
+                conf.mqtt.enable = true;
+                conf.zenoh.enable = true;
+            }
+            // instead of MQTT
+            (_, Some(true)) => {
+                conf.mqtt.enable = false;
+                conf.zenoh.enable = true;
+            }
+            // back to MQTT only
+            (Some(false), _) | (_, Some(false)) => {
+                conf.mqtt.enable = true;
+                conf.zenoh.enable = false;
+            }
+            (None, None) => {}
+        }
+        conf.routing.bridge = deprecated_bridge || self.bridge.unwrap_or(conf.routing.bridge);
+        conf.zenoh.lvc = self.zenoh_lvc.unwrap_or(conf.zenoh.lvc);
+        conf.spool.enable = self.spool.unwrap_or(conf.spool.enable);
+        conf.api.enable = self.api.unwrap_or(conf.api.enable);
+
+        if let Some(usbs) = self.usbs_locked {
+            conf.lockdown.usbs = usbs;
+        }
+        if let Some(device) = self.daq_device {
+            conf.daq.device = Some(device);
+        }
+        if let Some(uri) = self.video_uri {
+            conf.video.uri = Some(uri);
+        }
+        if let Some(url) = self.mqtt_url {
+            conf.mqtt.url = url;
+        }
+        if let Some(username) = self.mqtt_username {
+            conf.mqtt.username = Some(username);
+        }
+        if let Some(file) = self.mqtt_password_file {
+            conf.mqtt.password_file = Some(file);
+        }
+        if let Some(ca) = self.mqtt_ca {
+            conf.mqtt.ca = Some(ca);
+        }
+        if let Some(url) = self.scylla_url {
+            conf.general.scylla_url = Some(url);
+        }
+        if let Some(folder) = self.output_folder {
+            conf.general.output_folder = folder;
+        }
+        if let Some(iface) = self.socketcan_iface {
+            conf.general.socketcan_iface = iface;
+        }
+        if let Some(ifaces) = self.net_ifaces {
+            conf.net.ifaces = ifaces;
+        }
+        if let Some(iface) = self.hw_iface {
+            conf.halow.iface = iface;
+        }
+        if let Some(base_node) = self.base_node {
+            conf.general.base_node = base_node;
+        }
+        if let Some(zenoh_conf) = self.zenoh_conf {
+            conf.zenoh.conf = zenoh_conf;
+        }
+        if let Some(port) = self.api_port {
+            conf.api.port = port;
+        }
+    }
+}
+
+/// Read the config file again and apply the args on top, for SIGHUP
+fn reload_config(
+    cli: &VisualArgs,
+    running: &DaemonConfig,
+) -> Result<DaemonConfig, Box<dyn Error + Send + Sync>> {
+    let mut conf = match &cli.config {
+        Some(path) => DaemonConfig::load(path)?,
+        None => DaemonConfig::default(),
+    };
+    cli.clone().apply(&mut conf);
+    conf.fill_defaults();
+    conf.keep_fixed(running);
+    if conf.general.sim {
+        sim::fill_config(&mut conf);
+    }
+    conf.validate()?;
+    Ok(conf)
+}
+
+/// The STATUS= notification for systemd
+fn status(running: &RunningModules) -> String {
+    let names = running.names();
+    if names.is_empty() {
+        "STATUS=No modules running".to_string()
+    } else {
+        format!("STATUS=Running {}", names.join(", "))
+    }
+}
+
+/// A change to the running modules, see `reloader`
+enum Reload {
+    /// Re-read the config, on SIGHUP
+    Config,
+    /// Start or stop a module, see `control`
+    Control(ControlRequest),
+}
+
+/// Pass control requests on to the reloader until cancelled
+async fn control_listener(
+    cancel_token: CancellationToken,
+    mut control_rx: mpsc::Receiver<PlaybackData>,
+    base_node: String,
+    reload_tx: mpsc::Sender<Reload>,
+) {
+    loop {
+        let msg = tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            msg = control_rx.recv() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+        };
+        if let Some(req) = parse_control(&base_node, &msg)
+            && reload_tx.send(Reload::Control(req)).await.is_err()
+        {
+            break;
+        }
+    }
+}
+
+/// Apply reloads to the running modules one at a time until cancelled, so the signal loop is
+/// never held up by one
+async fn reloader(
+    cancel_token: CancellationToken,
+    mut reload_rx: mpsc::Receiver<Reload>,
+    cli: VisualArgs,
+    mut conf: DaemonConfig,
+    mut running: RunningModules,
+    mqtt_sender_tx: Publisher,
+    notifier: Option<Notifier>,
+) {
+    loop {
+        let reload = tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            reload = reload_rx.recv() => match reload {
+                Some(reload) => reload,
+                None => break,
+            },
+        };
+        match reload {
+            Reload::Config => match reload_config(&cli, &conf) {
+                Ok(new_conf) => {
+                    running.reload(ModuleRegistry::from_config(&new_conf)).await;
+                    conf = new_conf;
+                }
+                Err(err) => {
+                    warn!("Could not reload config, keeping the old one: {}", err);
+                    continue;
+                }
+            },
+            Reload::Control(req) => {
+                let base_node = conf.general.base_node.clone();
+                let ack = handle_control(req, &base_node, &mut conf, &mut running).await;
+                if let Err(err) = mqtt_sender_tx.send(ack).await {
+                    warn!("Could not send control ack: {}", err);
+                }
+            }
+        }
+        if let Some(notifier) = &notifier
+            && let Err(err) = notifier.notify(&status(&running))
+        {
+            warn!("Could not notify systemd: {}", err);
+        }
+    }
 }
 
 /// Folder hierarchy
 /// Main folder --> specified by the user --output_folder
 ///                                               |
 ///                                               |
-///                                         event-<TIME_MS>
+///                                         event-<TIME_MS>  (see `session`)
 ///                                               |
 ///                                              / \
-/// (video): ner24-frontcam.avi; (logger): data_dump.log; (serial): serial_dump.log; (audio): ner24-comms.mp3
+/// (video): ner24-frontcam.mp4; (logger): data_dump.log; (serial): cerberus-dump.cap, shepherd-dump.cap; (manifest): session.json
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     let cli = VisualArgs::parse();
 
+    let mut conf = match &cli.config {
+        Some(path) => match DaemonConfig::load(path) {
+            Ok(conf) => conf,
+            Err(err) => {
+                eprintln!("{err}");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => DaemonConfig::default(),
+    };
+    let print_config = cli.print_config;
+    cli.clone().apply(&mut conf);
+    conf.fill_defaults();
+
+    if print_config {
+        match conf.to_toml() {
+            Ok(dump) => println!("{dump}"),
+            Err(err) => eprintln!("Could not print config: {err}"),
+        }
+        return ExitCode::SUCCESS;
+    }
+    if conf.general.sim {
+        sim::fill_config(&mut conf);
+    }
+    if let Err(err) = conf.validate() {
+        eprintln!("Invalid configuration: {err}");
+        return ExitCode::FAILURE;
+    }
+
     println!("Initializing odysseus daemon...");
     println!("Initializing fmt subscriber");
-    // construct a subscriber that prints formatted traces to stdout
     // if RUST_LOG is not set, defaults to loglevel INFO
-    let subscriber = tracing_subscriber::fmt()
-        .with_thread_ids(true)
-        .with_ansi(true)
-        .with_thread_names(true)
-        .with_span_events(FmtSpan::CLOSE)
-        .with_env_filter(
-            EnvFilter::builder()
-                .with_default_directive(LevelFilter::INFO.into())
-                .from_env_lossy(),
-        )
-        .finish();
-    // use that subscriber to process traces emitted after this point
-    tracing::subscriber::set_global_default(subscriber).expect("Could not init tracing");
-
-    // set save location
-    SAVE_LOCATION.get_or_init(|| cli.output_folder.clone());
-
-    // channel to pass the mqtt data
-    // TODO tune buffer size
-    let (mqtt_sender_tx, mqtt_sender_rx) = mpsc::channel::<PublishableMessage>(1000);
-
-    let (mqtt_sys_tx, mqtt_sys_rx) = if cli.sys {
-        let res = mpsc::channel::<Publish>(100);
-        (Some(res.0), Some(res.1))
-    } else {
-        (None, None)
-    };
-
-    let (can_handler_tx, can_handler_rx) = mpsc::channel::<CanFrame>(1000);
-
-    let (hv_stat_send, hv_stat_recv) = watch::channel(HVTransition::TransitionOff);
-    let (mute_stat_send, mute_stat_recv) = watch::channel(false);
-
-    // create wildcard mqtt channel only if logger is enabled
-    let (mqtt_recv_tx, mqtt_recv_rx) = if cli.logger || cli.color || cli.zenoh_fwd {
-        let (tx, rx) = broadcast::channel::<playback_data::PlaybackData>(1000);
-        (Some(tx), Some(rx))
-    } else {
-        (None, None)
-    };
+    daemon_log::init(&conf.log, Path::new(&conf.general.output_folder));
+    if cli.zenoh_fwd || cli.zenoh_rev {
+        warn!("--zenoh-fwd and --zenoh-rev are deprecated, use --dual --bridge instead");
+    }
 
     let task_tracker = TaskTracker::new();
     let token = CancellationToken::new();
 
-    if cli.zenoh {
-        info!("Running zenoh processor");
-        let processor = ZenohProcessor::new(
-            token.clone(),
-            mqtt_sender_rx,
-            hv_stat_send,
-            cli.mock,
-            mute_stat_send,
-            mqtt_recv_tx,
-            cli.zenoh_conf.clone(),
-            cli.scylla_url,
-        )
-        .await;
-        task_tracker.spawn(processor.process_zenoh());
+    // dropped on every return, removing the sim root
+    let _sim = if conf.general.sim {
+        match sim::start(token.clone(), &task_tracker, &conf).await {
+            Ok(guard) => Some(guard),
+            Err(err) => {
+                warn!("Could not start simulation: {}", err);
+                return ExitCode::FAILURE;
+            }
+        }
     } else {
-        info!("Running MQTT processor");
-        let (recv, opts) = MqttProcessor::new(
-            token.clone(),
-            mqtt_sender_rx,
-            hv_stat_send,
-            cli.mock,
-            mute_stat_send,
-            mqtt_recv_tx,
-            mqtt_sys_tx,
-            cli.mqtt_url,
-            cli.scylla_url,
-        );
+        None
+    };
 
-        let (client, eventloop) = AsyncClient::new(opts, 600);
-        let client_sharable: Arc<AsyncClient> = Arc::new(client);
-        task_tracker.spawn(recv.process_mqtt(client_sharable.clone(), eventloop));
+    let mut registry = ModuleRegistry::from_config(&conf);
+    let control_rx = registry.control(format!("{}/Daemon/Control/+", conf.general.base_node));
+    // for the latest value of each topic in the API
+    if conf.api.enable {
+        registry.require_topics(&conf.api.topics);
+    }
+    let (module_channels, transport_channels, can_handler_rx) = registry.channels();
+
+    let heartbeat = Heartbeat::default();
+
+    let mqtt = if conf.mqtt.enable {
+        match MqttTransport::new(&conf.mqtt, &conf.general.base_node) {
+            Ok(mqtt) => Some(Arc::new(mqtt)),
+            Err(err) => {
+                warn!("Could not set up MQTT: {}", err);
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        None
+    };
+    let zenoh = if conf.zenoh.enable {
+        match ZenohTransport::new(&conf.zenoh, &task_tracker, &token) {
+            Ok(zenoh) => Some(Arc::new(zenoh)),
+            Err(err) => {
+                warn!("Could not set up Zenoh: {}", err);
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        None
+    };
+    let transport: Arc<dyn Transport> = match (mqtt, zenoh.clone()) {
+        (Some(mqtt), Some(zenoh)) => {
+            info!("Running MQTT and Zenoh transports");
+            Arc::new(DualTransport::new(
+                mqtt,
+                zenoh,
+                conf.routing.clone(),
+                conf.general.base_node.clone(),
+                &task_tracker,
+                &token,
+            ))
+        }
+        (None, Some(zenoh)) => {
+            info!("Running Zenoh transport");
+            zenoh
+        }
+        (Some(mqtt), None) => {
+            info!("Running MQTT transport");
+            mqtt
+        }
+        (None, None) => {
+            warn!("No transport enabled");
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(err) = transport.connect().await {
+        warn!("Could not connect transport: {}", err);
+        return ExitCode::FAILURE;
     }
+    let spool = if conf.spool.enable {
+        let folder = PathBuf::from(&conf.general.output_folder).join(SPOOL_FOLDER);
+        match Spool::open(&folder, &conf.spool) {
+            Ok(spool) => Some(Arc::new(spool)),
+            Err(err) => {
+                warn!("Could not open spool in {}: {}", folder.display(), err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let dispatcher = Dispatcher::new(
+        token.clone(),
+        transport_channels,
+        conf.general.augment_hv,
+        conf.general.scylla_url.clone(),
+        PathBuf::from(&conf.general.output_folder),
+        heartbeat.clone(),
+        spool,
+    );
+    task_tracker.spawn(dispatcher.run(transport));
 
     // TASK SPAWNING
 
-    info!("Enable CAN handler");
-    task_tracker.spawn(can_handler(
+    task_tracker.spawn(drop_reporter(
         token.clone(),
-        cli.socketcan_iface.clone(),
-        can_handler_rx,
+        conf.general.base_node.clone(),
+        module_channels.publisher(),
     ));
 
-    if cli.video {
-        info!("Running video module");
-        task_tracker.spawn(run_save_pipeline(
-            token.clone(),
-            hv_stat_recv.clone(),
-            SavePipelineOpts {
-                video: cli
-                    .video_uri
-                    .expect("Must provide video URI if video is enabled!"),
-            },
-        ));
-    }
-    if cli.data {
-        info!("Running TPU data collector");
-        task_tracker.spawn(collect_data(token.clone(), mqtt_sender_tx.clone()));
-    }
-    if cli.daq {
-        info!("Running DAQ data collector");
-        task_tracker.spawn(monitor_daq(
+    if let Some(zenoh) = zenoh {
+        task_tracker.spawn(liveliness(
             token.clone(),
-            cli.daq_device.expect("failed to init daq device"),
-            mqtt_sender_tx.clone(),
-            can_handler_tx,
+            zenoh.clone(),
+            conf.general.base_node.clone(),
+            module_channels.modules(),
         ));
+        if conf.zenoh.peer_alive {
+            task_tracker.spawn(peer_alive(
+                token.clone(),
+                zenoh,
+                conf.general.base_node.clone(),
+                module_channels.publisher(),
+            ));
+        }
     }
 
-    if cli.lockdown {
-        info!("Running lockdown module");
-        task_tracker.spawn(lockdown_runner(
+    if sim::is_enabled() {
+        info!("Enable simulated CAN handler");
+        task_tracker.spawn(sim::can_logger(token.clone(), can_handler_rx));
+    } else {
+        info!("Enable CAN handler");
+        task_tracker.spawn(can_handler(
             token.clone(),
-            hv_stat_recv.clone(),
-            cli.usbs_locked
-                .expect("USBs required when enabling lockdown module"),
+            conf.general.socketcan_iface.clone(),
+            can_handler_rx,
         ));
     }
 
-    if cli.audible {
-        info!("Running audio module");
-        task_tracker.spawn(audible_manager(token.clone(), mute_stat_recv));
-    }
-    if cli.logger {
-        info!("Running logger module");
-        task_tracker.spawn(logger_manager(
-            token.clone(),
-            mqtt_recv_rx.as_ref().unwrap().resubscribe(),
-            hv_stat_recv.clone(),
-        ));
-    }
+    let running = registry.spawn(&task_tracker, &token, &module_channels);
 
-    if cli.zenoh_rev {
-        info!("Running Zenoh Rev");
-        task_tracker.spawn(zenoh_rev(
-            token.clone(),
-            cli.zenoh_conf,
-            mqtt_sender_tx.clone(),
-        ));
-    } else if cli.zenoh_fwd {
-        info!("Running Zenoh Fwd");
-        task_tracker.spawn(zenoh_fwd(
+    if conf.api.enable {
+        task_tracker.spawn(api_server(
+            task_tracker.clone(),
             token.clone(),
-            cli.zenoh_conf,
-            (*(mqtt_recv_rx.as_ref().unwrap())).resubscribe(),
+            format!("{}:{}", conf.api.bind, conf.api.port),
+            module_channels.clone(),
+            running.health(),
+            conf.general.scylla_url.clone(),
+            PathBuf::from(&conf.general.output_folder),
         ));
     }
 
-    if cli.sys {
-        info!("Running SYS translator");
-        task_tracker.spawn(sys_parser(
-            token.clone(),
-            mqtt_sys_rx.unwrap(),
-            mqtt_sender_tx.clone(),
-        ));
+    let notifier = Notifier::from_env();
+    if let Some(notifier) = &notifier {
+        if let Err(err) = notifier.notify(&format!("READY=1\n{}", status(&running))) {
+            warn!("Could not notify systemd: {}", err);
+        }
+        if let Some(period) = watchdog_period() {
+            info!("Pinging systemd watchdog every {:?}", period);
+            task_tracker.spawn(watchdog(token.clone(), notifier.clone(), heartbeat, period));
+        }
     }
 
-    if cli.gps {
-        info!("Running GPS Manager");
-        task_tracker.spawn(gps_manager(token.clone(), mqtt_sender_tx.clone()));
-    }
+    let (reload_tx, reload_rx) = mpsc::channel(8);
+    task_tracker.spawn(control_listener(
+        token.clone(),
+        control_rx,
+        conf.general.base_node.clone(),
+        reload_tx.clone(),
+    ));
+    task_tracker.spawn(reloader(
+        token.clone(),
+        reload_rx,
+        cli,
+        conf,
+        running,
+        module_channels.publisher(),
+        notifier.clone(),
+    ));
 
-    if cli.color {
-        info!("Running color controller for wheel");
-        task_tracker.spawn(color_controller(token.clone(), mqtt_recv_rx.unwrap()));
-    }
+    task_tracker.close();
 
-    if cli.net
-        && let Some(ifaces) = cli.net_ifaces
+    info!("Initialization complete, ready...");
+    info!("Use Ctrl+C, SIGINT, or SIGTERM to exit cleanly, and SIGHUP to reload the config!");
+
+    let mut sigterm = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+    let mut sighup = signal(SignalKind::hangup()).expect("Could not listen for SIGHUP");
+
+    // listen for ctrl_c or SIGTERM, then cancel, close, and await for all tasks in the tracker.  Other tasks cancel vai the default tokio system
+    loop {
+        tokio::select! {
+            res = signal::ctrl_c() => {
+                res.expect("Could not read cancellation trigger (ctr+c)");
+                break;
+            },
+            _ = sigterm.recv() => break,
+            _ = sighup.recv() => {
+                info!("Received SIGHUP, reloading config");
+                if reload_tx.try_send(Reload::Config).is_err() {
+                    warn!("Could not reload config, too many reloads pending");
+                }
+            },
+        }
+    }
+    info!("Received exit signal, shutting down!");
+    if let Some(notifier) = &notifier
+        && let Err(err) = notifier.notify("STOPPING=1")
     {
-        info!("Running network statistics scraper");
-        task_tracker.spawn(network_scraper(
-            token.clone(),
-            mqtt_sender_tx.clone(),
-            cli.base_node.clone(),
-            ifaces,
-        ));
+        warn!("Could not notify systemd: {}", err);
     }
+    token.cancel();
+    task_tracker.wait().await;
+    ExitCode::SUCCESS
+}
 
-    if cli.halow {
-        info!("Running halow statistics scraper");
-        task_tracker.spawn(halow_scraper(
-            token.clone(),
-            cli.base_node.clone(),
-            mqtt_sender_tx.clone(),
-        ));
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if cli.can {
-        info!("Running CAN statistics scraper");
-        task_tracker.spawn(can_data_scraper(
-            token.clone(),
-            mqtt_sender_tx,
-            cli.base_node,
-            cli.socketcan_iface,
-        ));
+    fn apply(args: &[&str], conf: &mut DaemonConfig) {
+        VisualArgs::try_parse_from(std::iter::once("odysseus-daemon").chain(args.iter().copied()))
+            .unwrap()
+            .apply(conf);
     }
 
-    task_tracker.close();
-
-    info!("Initialization complete, ready...");
-    info!("Use Ctrl+C or SIGINT to exit cleanly!");
+    #[test]
+    fn flags_override_the_file_both_ways() {
+        let mut conf = DaemonConfig::default();
+        conf.data.enable = true;
+        conf.gps.enable = true;
+        conf.zenoh.enable = true;
 
-    // listen for ctrl_c, then cancel, close, and await for all tasks in the tracker.  Other tasks cancel vai the default tokio system
-    signal::ctrl_c()
-        .await
-        .expect("Could not read cancellation trigger (ctr+c)");
-    info!("Received exit signal, shutting down!");
-    token.cancel();
-    task_tracker.wait().await;
+        apply(
+            &["--data=false", "--gps", "--net", "true", "--dual", "false"],
+            &mut conf,
+        );
+        assert!(!conf.data.enable);
+        assert!(conf.gps.enable);
+        assert!(conf.net.enable);
+        assert!(conf.mqtt.enable && !conf.zenoh.enable);
+
+        // unset flags keep the file
+        apply(&[], &mut conf);
+        assert!(!conf.data.enable && conf.gps.enable && conf.net.enable);
+    }
 }
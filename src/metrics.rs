@@ -0,0 +1,317 @@
+//! HELPER: Prometheus metrics of the daemon internals
+//!
+//! Counters are bumped through the global `METRICS` and served as Prometheus text on
+//! `GET /metrics` of the API (see `api`).
+//! - `ody_messages_received_total` and `ody_messages_published_total`, by topic prefix (the first topic level)
+//! - `ody_queue_depth`, by queue, including `publish` (the `mqtt_sender_tx` channel)
+//! - `ody_broadcast_lagged_total`, messages dropped by a slow receiver of all messages, by module
+//! - `ody_publish_dropped_total`, messages dropped from the publish queue, by topic (see `publish`)
+//! - `ody_upload_bytes_total` and `ody_upload_failures_total`
+//! - `ody_daq_respawns_total`
+//! - `ody_spool_written_total`, `ody_spool_replayed_total` and `ody_spool_bytes` (see `spool`)
+//! - `ody_bridge_forwarded_total` and `ody_bridge_errors_total`, by direction (see `dual`)
+//! - `ody_zenoh_samples_dropped_total`, received Zenoh samples dropped while backed up (see `zenoh_handler`)
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use crate::module::QueueDepths;
+
+/// The counters of the whole daemon
+pub static METRICS: Metrics = Metrics::new();
+
+/// Counters of the daemon internals, see `METRICS`
+pub struct Metrics {
+    received: Mutex<BTreeMap<String, u64>>,
+    published: Mutex<BTreeMap<String, u64>>,
+    lagged: Mutex<BTreeMap<&'static str, u64>>,
+    dropped: Mutex<BTreeMap<String, u64>>,
+    bridged: Mutex<BTreeMap<&'static str, u64>>,
+    bridge_errors: Mutex<BTreeMap<&'static str, u64>>,
+    upload_bytes: AtomicU64,
+    upload_failures: AtomicU64,
+    daq_respawns: AtomicU64,
+    spool_written: AtomicU64,
+    spool_replayed: AtomicU64,
+    spool_bytes: AtomicU64,
+    zenoh_dropped: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Metrics {
+        Metrics {
+            received: Mutex::new(BTreeMap::new()),
+            published: Mutex::new(BTreeMap::new()),
+            lagged: Mutex::new(BTreeMap::new()),
+            dropped: Mutex::new(BTreeMap::new()),
+            bridged: Mutex::new(BTreeMap::new()),
+            bridge_errors: Mutex::new(BTreeMap::new()),
+            upload_bytes: AtomicU64::new(0),
+            upload_failures: AtomicU64::new(0),
+            daq_respawns: AtomicU64::new(0),
+            spool_written: AtomicU64::new(0),
+            spool_replayed: AtomicU64::new(0),
+            spool_bytes: AtomicU64::new(0),
+            zenoh_dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// A message was received by the transport
+    pub fn received(&self, topic: &str) {
+        bump(&self.received, topic);
+    }
+
+    /// A message was published by the transport
+    pub fn published(&self, topic: &str) {
+        bump(&self.published, topic);
+    }
+
+    /// A receiver of all messages lagged behind, losing cnt messages
+    pub fn lagged(&self, module: &'static str, cnt: u64) {
+        *self.lagged.lock().unwrap().entry(module).or_default() += cnt;
+    }
+
+    /// A message was dropped from the publish queue
+    pub fn dropped(&self, topic: &str) {
+        let mut dropped = self.dropped.lock().unwrap();
+        match dropped.get_mut(topic) {
+            Some(cnt) => *cnt += 1,
+            None => {
+                dropped.insert(topic.to_string(), 1);
+            }
+        }
+    }
+
+    /// The messages dropped from the publish queue so far, by topic
+    pub fn dropped_counts(&self) -> BTreeMap<String, u64> {
+        self.dropped.lock().unwrap().clone()
+    }
+
+    /// A message was bridged, in a direction such as `mqtt-to-zenoh`
+    pub fn bridged(&self, direction: &'static str) {
+        *self.bridged.lock().unwrap().entry(direction).or_default() += 1;
+    }
+
+    /// A message could not be bridged
+    pub fn bridge_failed(&self, direction: &'static str) {
+        *self
+            .bridge_errors
+            .lock()
+            .unwrap()
+            .entry(direction)
+            .or_default() += 1;
+    }
+
+    /// A file of bytes was uploaded to Scylla
+    pub fn uploaded(&self, bytes: u64) {
+        self.upload_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// A file could not be uploaded to Scylla
+    pub fn upload_failed(&self) {
+        self.upload_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The DAQ reader was respawned
+    pub fn daq_respawned(&self) {
+        self.daq_respawns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A message was written to the spool
+    pub fn spooled(&self) {
+        self.spool_written.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A spooled message was replayed
+    pub fn replayed(&self) {
+        self.spool_replayed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A received Zenoh sample was dropped
+    pub fn zenoh_dropped(&self) {
+        self.zenoh_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The bytes of the spool on disk
+    pub fn set_spool_bytes(&self, bytes: u64) {
+        self.spool_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Render every metric in the Prometheus text format
+    pub fn render(&self, queues: &QueueDepths) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "ody_messages_received_total",
+            "counter",
+            "Messages received by the transport, by topic prefix",
+        );
+        for (prefix, cnt) in self.received.lock().unwrap().iter() {
+            sample(
+                &mut out,
+                "ody_messages_received_total",
+                "prefix",
+                prefix,
+                *cnt,
+            );
+        }
+
+        header(
+            &mut out,
+            "ody_messages_published_total",
+            "counter",
+            "Messages published by the transport, by topic prefix",
+        );
+        for (prefix, cnt) in self.published.lock().unwrap().iter() {
+            sample(
+                &mut out,
+                "ody_messages_published_total",
+                "prefix",
+                prefix,
+                *cnt,
+            );
+        }
+
+        header(
+            &mut out,
+            "ody_queue_depth",
+            "gauge",
+            "Messages waiting in an internal queue",
+        );
+        let depths = [
+            ("publish", queues.publish),
+            ("received", queues.received),
+            ("sys", queues.sys),
+            ("can", queues.can),
+        ];
+        for (queue, depth) in depths {
+            sample(&mut out, "ody_queue_depth", "queue", queue, depth as u64);
+        }
+
+        header(
+            &mut out,
+            "ody_broadcast_lagged_total",
+            "counter",
+            "Messages lost by a module lagging behind the received channel",
+        );
+        for (module, cnt) in self.lagged.lock().unwrap().iter() {
+            sample(
+                &mut out,
+                "ody_broadcast_lagged_total",
+                "module",
+                module,
+                *cnt,
+            );
+        }
+
+        header(
+            &mut out,
+            "ody_publish_dropped_total",
+            "counter",
+            "Messages dropped or coalesced away in the publish queue, by topic",
+        );
+        for (topic, cnt) in self.dropped.lock().unwrap().iter() {
+            sample(&mut out, "ody_publish_dropped_total", "topic", topic, *cnt);
+        }
+
+        for (name, help, counts) in [
+            (
+                "ody_bridge_forwarded_total",
+                "Messages forwarded to the other transport, by direction",
+                &self.bridged,
+            ),
+            (
+                "ody_bridge_errors_total",
+                "Messages which could not be forwarded to the other transport, by direction",
+                &self.bridge_errors,
+            ),
+        ] {
+            header(&mut out, name, "counter", help);
+            for (direction, cnt) in counts.lock().unwrap().iter() {
+                sample(&mut out, name, "direction", direction, *cnt);
+            }
+        }
+
+        for (name, kind, help, val) in [
+            (
+                "ody_upload_bytes_total",
+                "counter",
+                "Bytes uploaded to Scylla",
+                &self.upload_bytes,
+            ),
+            (
+                "ody_upload_failures_total",
+                "counter",
+                "Files which could not be uploaded to Scylla",
+                &self.upload_failures,
+            ),
+            (
+                "ody_daq_respawns_total",
+                "counter",
+                "Times the DAQ reader was respawned",
+                &self.daq_respawns,
+            ),
+            (
+                "ody_spool_written_total",
+                "counter",
+                "Messages written to the spool while disconnected",
+                &self.spool_written,
+            ),
+            (
+                "ody_spool_replayed_total",
+                "counter",
+                "Spooled messages replayed once connected",
+                &self.spool_replayed,
+            ),
+            (
+                "ody_spool_bytes",
+                "gauge",
+                "Bytes waiting in the spool",
+                &self.spool_bytes,
+            ),
+            (
+                "ody_zenoh_samples_dropped_total",
+                "counter",
+                "Received Zenoh samples dropped, as too many were waiting",
+                &self.zenoh_dropped,
+            ),
+        ] {
+            header(&mut out, name, kind, help);
+            let _ = writeln!(out, "{name} {}", val.load(Ordering::Relaxed));
+        }
+
+        out
+    }
+}
+
+/// Count a message under the first level of its topic
+fn bump(counts: &Mutex<BTreeMap<String, u64>>, topic: &str) {
+    let prefix = topic.split('/').next().unwrap_or_default();
+    let mut counts = counts.lock().unwrap();
+    match counts.get_mut(prefix) {
+        Some(cnt) => *cnt += 1,
+        None => {
+            counts.insert(prefix.to_string(), 1);
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn sample(out: &mut String, name: &str, label: &str, value: &str, cnt: u64) {
+    let value = value
+        .replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n");
+    let _ = writeln!(out, "{name}{{{label}=\"{value}\"}} {cnt}");
+}
@@ -0,0 +1,625 @@
+//! HELPER: The common module interface and the registry wiring modules together
+//!
+//! Every module implements `Module` and declares the inputs it needs.  The registry
+//! builds every channel, so a module disabled at startup can still be started later on, and
+//! hands each module a `ModuleContext` holding exactly the ones it declared.
+//! The transport only subscribes to the topics the running modules receive, see `Module::topics`,
+//! and to `$SYS` while a module reads it.
+//! On a config reload, `RunningModules` only restarts the modules whose settings changed,
+//! and updates the subscriptions.
+//!
+//! Adding a new module:
+//! 1. Add a config section for it in `config`
+//! 2. Implement `Module` for it in its own file
+//! 3. Register it in `ModuleRegistry::from_config`
+
+use std::{collections::BTreeSet, error::Error, fmt::Debug, path::PathBuf};
+
+use futures_util::future::BoxFuture;
+use rumqttc::v5::mqttbytes::v5::Publish;
+use serde::Serialize;
+use socketcan::CanFrame;
+use tokio::{
+    sync::{broadcast, mpsc, watch},
+    task::JoinHandle,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{info, warn};
+
+use crate::{
+    HVTransition,
+    audible::AudibleModule,
+    can::CanModule,
+    color::ColorModule,
+    config::{DaemonConfig, PublishConfig, RestartConfig},
+    daq_monitor::DaqModule,
+    gps::GpsModule,
+    halow::HalowModule,
+    health::{HealthBoard, ModuleHealth},
+    lockdown::LockdownModule,
+    logger::LoggerModule,
+    net::NetModule,
+    numerical::DataModule,
+    playback_data::PlaybackData,
+    publish::{self, PublishQueue, Publisher},
+    storage::StorageModule,
+    supervisor::supervise,
+    sys_parser::SysModule,
+    visual::VideoModule,
+};
+
+/// How many control messages may wait, see `ModuleRegistry::control`
+const CONTROL_CAPACITY: usize = 16;
+
+/// What a module returns when it stops
+pub type ModuleResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+/// The inputs a module needs from the rest of the daemon
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ModuleInputs {
+    /// A sender of messages to publish
+    pub publish: bool,
+    /// A receiver of all received messages
+    pub received: bool,
+    /// A receiver of the HV state
+    pub hv: bool,
+    /// A receiver of the mute button state
+    pub mute: bool,
+    /// A receiver of the MQTT $SYS messages
+    pub sys: bool,
+    /// A sender of frames to put on the CAN bus
+    pub can: bool,
+}
+
+/// A daemon module
+pub trait Module: Send + Sync {
+    /// The name of the module, used in logs
+    fn name(&self) -> &'static str;
+
+    /// The inputs the module needs, only these are given in the context
+    fn inputs(&self) -> ModuleInputs;
+
+    /// The MQTT style filters of the messages the module receives, if it declared `received`.
+    /// Other messages may still be received, when another module subscribed to them
+    fn topics(&self) -> Vec<String> {
+        vec!["#".to_string()]
+    }
+
+    /// Run the module until the context's cancel token is cancelled.
+    /// Called again with a fresh context whenever the module is restarted
+    fn run(&self, ctx: ModuleContext) -> BoxFuture<'static, ModuleResult>;
+
+    /// Release anything held outside the daemon, called once after `run` returns
+    fn shutdown(&self) -> BoxFuture<'static, ()> {
+        Box::pin(async {})
+    }
+}
+
+/// Everything a module is given to run, only the inputs it declared are set
+pub struct ModuleContext {
+    pub cancel_token: CancellationToken,
+    /// Mark useful work and errors here, see `health`
+    pub health: ModuleHealth,
+    name: &'static str,
+    mqtt_sender_tx: Option<Publisher>,
+    mqtt_recv_rx: Option<broadcast::Receiver<PlaybackData>>,
+    hv_stat_recv: Option<watch::Receiver<HVTransition>>,
+    mute_stat_recv: Option<watch::Receiver<bool>>,
+    mqtt_sys_rx: Option<broadcast::Receiver<Publish>>,
+    can_handler_tx: Option<mpsc::Sender<CanFrame>>,
+}
+
+impl ModuleContext {
+    fn missing(&self, input: &str) -> Box<dyn Error + Send + Sync> {
+        format!("Module {} did not declare input {}", self.name, input).into()
+    }
+
+    /// Take the sender of messages to publish
+    pub fn publisher(&mut self) -> Result<Publisher, Box<dyn Error + Send + Sync>> {
+        self.mqtt_sender_tx
+            .take()
+            .ok_or_else(|| self.missing("publish"))
+    }
+
+    /// Take the receiver of all received messages
+    pub fn received(
+        &mut self,
+    ) -> Result<broadcast::Receiver<PlaybackData>, Box<dyn Error + Send + Sync>> {
+        self.mqtt_recv_rx
+            .take()
+            .ok_or_else(|| self.missing("received"))
+    }
+
+    /// Take the receiver of the HV state
+    pub fn hv(&mut self) -> Result<watch::Receiver<HVTransition>, Box<dyn Error + Send + Sync>> {
+        self.hv_stat_recv.take().ok_or_else(|| self.missing("hv"))
+    }
+
+    /// Take the receiver of the mute button state
+    pub fn mute(&mut self) -> Result<watch::Receiver<bool>, Box<dyn Error + Send + Sync>> {
+        self.mute_stat_recv
+            .take()
+            .ok_or_else(|| self.missing("mute"))
+    }
+
+    /// Take the receiver of MQTT $SYS messages
+    pub fn sys(&mut self) -> Result<broadcast::Receiver<Publish>, Box<dyn Error + Send + Sync>> {
+        self.mqtt_sys_rx.take().ok_or_else(|| self.missing("sys"))
+    }
+
+    /// Take the sender of CAN frames
+    pub fn can(&mut self) -> Result<mpsc::Sender<CanFrame>, Box<dyn Error + Send + Sync>> {
+        self.can_handler_tx
+            .take()
+            .ok_or_else(|| self.missing("can"))
+    }
+}
+
+/// How many messages are waiting in each channel
+#[derive(Debug, Serialize)]
+pub struct QueueDepths {
+    pub publish: usize,
+    pub received: usize,
+    pub sys: usize,
+    pub can: usize,
+}
+
+/// The module facing ends of the channel graph
+#[derive(Clone)]
+pub struct ModuleChannels {
+    mqtt_sender_tx: Publisher,
+    publish: PublishConfig,
+    hv_stat_recv: watch::Receiver<HVTransition>,
+    mute_stat_recv: watch::Receiver<bool>,
+    connected_recv: watch::Receiver<bool>,
+    mqtt_recv_tx: broadcast::Sender<PlaybackData>,
+    mqtt_sys_tx: broadcast::Sender<Publish>,
+    can_handler_tx: mpsc::Sender<CanFrame>,
+    subscriptions: watch::Sender<BTreeSet<String>>,
+    modules: watch::Sender<BTreeSet<String>>,
+}
+
+impl ModuleChannels {
+    /// A sender of messages to publish, for daemon internals
+    pub fn publisher(&self) -> Publisher {
+        self.mqtt_sender_tx.clone()
+    }
+
+    /// A receiver of all received messages, for daemon internals
+    pub fn received(&self) -> broadcast::Receiver<PlaybackData> {
+        self.mqtt_recv_tx.subscribe()
+    }
+
+    /// A receiver of the HV state, for daemon internals
+    pub fn hv(&self) -> watch::Receiver<HVTransition> {
+        self.hv_stat_recv.clone()
+    }
+
+    /// A receiver of the mute button state, for daemon internals
+    pub fn mute(&self) -> watch::Receiver<bool> {
+        self.mute_stat_recv.clone()
+    }
+
+    /// A receiver of whether the transport is connected
+    pub fn connected(&self) -> watch::Receiver<bool> {
+        self.connected_recv.clone()
+    }
+
+    /// A receiver of the names of the running modules, for daemon internals
+    pub fn modules(&self) -> watch::Receiver<BTreeSet<String>> {
+        self.modules.subscribe()
+    }
+
+    pub fn queue_depths(&self) -> QueueDepths {
+        fn mpsc_len<T>(tx: &mpsc::Sender<T>) -> usize {
+            tx.max_capacity() - tx.capacity()
+        }
+        QueueDepths {
+            publish: self.mqtt_sender_tx.len(),
+            received: self.mqtt_recv_tx.len(),
+            sys: self.mqtt_sys_tx.len(),
+            can: mpsc_len(&self.can_handler_tx),
+        }
+    }
+
+    /// Build the context for a module, with only the inputs it declared
+    pub fn context(
+        &self,
+        module: &dyn Module,
+        cancel_token: CancellationToken,
+        health: ModuleHealth,
+    ) -> ModuleContext {
+        let inputs = module.inputs();
+        ModuleContext {
+            cancel_token,
+            health,
+            name: module.name(),
+            mqtt_sender_tx: inputs.publish.then(|| {
+                self.mqtt_sender_tx
+                    .with_source(module.name(), self.publish.policy(module.name()))
+            }),
+            mqtt_recv_rx: inputs.received.then(|| self.mqtt_recv_tx.subscribe()),
+            hv_stat_recv: inputs.hv.then(|| self.hv_stat_recv.clone()),
+            mute_stat_recv: inputs.mute.then(|| self.mute_stat_recv.clone()),
+            mqtt_sys_rx: inputs.sys.then(|| self.mqtt_sys_tx.subscribe()),
+            can_handler_tx: inputs.can.then(|| self.can_handler_tx.clone()),
+        }
+    }
+}
+
+/// The transport facing ends of the channel graph, given to the transport `Dispatcher`
+pub struct TransportChannels {
+    /// A receiver of any messages to publish
+    pub mqtt_sender_rx: PublishQueue,
+    /// A sender of the current HV state
+    pub hv_stat_send: watch::Sender<HVTransition>,
+    /// A sender of the current mute button state
+    pub mute_stat_send: watch::Sender<bool>,
+    /// A sender of whether the transport is connected
+    pub connected_send: watch::Sender<bool>,
+    /// A sender of all received messages
+    pub mqtt_recv_tx: broadcast::Sender<PlaybackData>,
+    /// A sender of $SYS messages
+    pub mqtt_sys_tx: broadcast::Sender<Publish>,
+    /// A receiver of the filters the modules need received
+    pub subscriptions: watch::Receiver<BTreeSet<String>>,
+    /// A filter and the sender of the live messages matching it, see `ModuleRegistry::control`
+    pub control: Option<(String, mpsc::Sender<PlaybackData>)>,
+}
+
+/// A module built from its config section
+struct ModuleEntry {
+    module: Box<dyn Module>,
+    restart: RestartConfig,
+    /// The config the module was built from, compared on reload to tell if it changed
+    settings: String,
+}
+
+/// The set of enabled modules
+pub struct ModuleRegistry {
+    modules: Vec<ModuleEntry>,
+    /// Topics received by daemon internals rather than modules
+    required_topics: Vec<String>,
+    /// See `control`
+    control: Option<(String, mpsc::Sender<PlaybackData>)>,
+    base_node: String,
+    publish: PublishConfig,
+}
+
+impl ModuleRegistry {
+    fn add(&mut self, module: Box<dyn Module>, restart: RestartConfig, settings: &dyn Debug) {
+        self.modules.push(ModuleEntry {
+            module,
+            restart,
+            settings: format!("{settings:?}"),
+        });
+    }
+
+    /// Create every module enabled in the config
+    pub fn from_config(conf: &DaemonConfig) -> ModuleRegistry {
+        let mut registry = ModuleRegistry {
+            modules: Vec::new(),
+            required_topics: Vec::new(),
+            control: None,
+            base_node: conf.general.base_node.clone(),
+            publish: conf.publish.clone(),
+        };
+        if conf.mqtt.enable && conf.zenoh.enable && conf.routing.bridge {
+            // the bridge forwards everything, not only what the modules need
+            registry.required_topics.push("#".to_string());
+        }
+
+        if conf.video.enable
+            && let Some(uri) = &conf.video.uri
+        {
+            registry.add(
+                Box::new(VideoModule::new(uri.clone())),
+                conf.video.restart,
+                &conf.video,
+            );
+        }
+        if conf.data.enable {
+            registry.add(Box::new(DataModule), conf.data.restart, &conf.data);
+        }
+        if conf.daq.enable
+            && let Some(device) = &conf.daq.device
+        {
+            registry.add(
+                Box::new(DaqModule::new(device.clone())),
+                conf.daq.restart,
+                &conf.daq,
+            );
+        }
+        if conf.lockdown.enable {
+            registry.add(
+                Box::new(LockdownModule::new(conf.lockdown.usbs.clone())),
+                conf.lockdown.restart,
+                &conf.lockdown,
+            );
+        }
+        if conf.audible.enable {
+            registry.add(Box::new(AudibleModule), conf.audible.restart, &conf.audible);
+        }
+        if conf.logger.enable {
+            registry.add(
+                Box::new(LoggerModule::new(conf.logger.include.clone())),
+                conf.logger.restart,
+                &conf.logger,
+            );
+        }
+        if conf.sys.enable {
+            registry.add(Box::new(SysModule), conf.sys.restart, &conf.sys);
+        }
+        if conf.gps.enable {
+            registry.add(Box::new(GpsModule), conf.gps.restart, &conf.gps);
+        }
+        if conf.color.enable {
+            registry.add(Box::new(ColorModule), conf.color.restart, &conf.color);
+        }
+        if conf.net.enable && !conf.net.ifaces.is_empty() {
+            registry.add(
+                Box::new(NetModule::new(
+                    conf.general.base_node.clone(),
+                    conf.net.ifaces.clone(),
+                )),
+                conf.net.restart,
+                &conf.net,
+            );
+        }
+        if conf.halow.enable {
+            registry.add(
+                Box::new(HalowModule::new(conf.general.base_node.clone())),
+                conf.halow.restart,
+                &conf.halow,
+            );
+        }
+        if conf.can.enable {
+            registry.add(
+                Box::new(CanModule::new(
+                    conf.general.base_node.clone(),
+                    conf.general.socketcan_iface.clone(),
+                )),
+                conf.can.restart,
+                &conf.can,
+            );
+        }
+        if conf.storage.enable {
+            registry.add(
+                Box::new(StorageModule::new(
+                    conf.general.base_node.clone(),
+                    PathBuf::from(&conf.general.output_folder),
+                    conf.storage.clone(),
+                )),
+                conf.storage.restart,
+                &conf.storage,
+            );
+        }
+
+        registry
+    }
+
+    /// Subscribe to these topics even if no module needs them, kept across reloads
+    pub fn require_topics(&mut self, filters: &[String]) {
+        self.required_topics.extend_from_slice(filters);
+    }
+
+    /// Subscribe to a filter, kept across reloads, and receive its live messages on their own
+    /// rather than picking them out of everything received
+    pub fn control(&mut self, filter: String) -> mpsc::Receiver<PlaybackData> {
+        let (control_tx, control_rx) = mpsc::channel(CONTROL_CAPACITY);
+        self.required_topics.push(filter.clone());
+        self.control = Some((filter, control_tx));
+        control_rx
+    }
+
+    /// Build the channel graph, with every input so any module can be started later on.
+    /// Returns the receiver of CAN frames
+    pub fn channels(&self) -> (ModuleChannels, TransportChannels, mpsc::Receiver<CanFrame>) {
+        let (mqtt_sender_tx, mqtt_sender_rx) = publish::queue(self.publish.capacity);
+        let (hv_stat_send, hv_stat_recv) = watch::channel(HVTransition::TransitionOff);
+        let (mute_stat_send, mute_stat_recv) = watch::channel(false);
+        let (connected_send, connected_recv) = watch::channel(false);
+        let (subscriptions_send, subscriptions) = watch::channel(subscriptions(
+            &self.required_topics,
+            self.modules.iter().map(|entry| entry.module.as_ref()),
+        ));
+        let mqtt_recv_tx = broadcast::channel::<PlaybackData>(1000).0;
+        let mqtt_sys_tx = broadcast::channel::<Publish>(100).0;
+        let (can_handler_tx, can_handler_rx) = mpsc::channel::<CanFrame>(1000);
+
+        (
+            ModuleChannels {
+                mqtt_sender_tx,
+                publish: self.publish.clone(),
+                hv_stat_recv,
+                mute_stat_recv,
+                connected_recv,
+                mqtt_recv_tx: mqtt_recv_tx.clone(),
+                mqtt_sys_tx: mqtt_sys_tx.clone(),
+                can_handler_tx,
+                subscriptions: subscriptions_send,
+                modules: watch::channel(BTreeSet::new()).0,
+            },
+            TransportChannels {
+                mqtt_sender_rx,
+                hv_stat_send,
+                mute_stat_send,
+                connected_send,
+                mqtt_recv_tx,
+                mqtt_sys_tx,
+                subscriptions,
+                control: self.control.clone(),
+            },
+            can_handler_rx,
+        )
+    }
+
+    /// Spawn every module onto the tracker, each under a supervisor
+    pub fn spawn(
+        self,
+        task_tracker: &TaskTracker,
+        token: &CancellationToken,
+        channels: &ModuleChannels,
+    ) -> RunningModules {
+        let mut running = RunningModules {
+            task_tracker: task_tracker.clone(),
+            cancel_token: token.clone(),
+            channels: channels.clone(),
+            base_node: self.base_node,
+            required_topics: self.required_topics,
+            running: Vec::new(),
+            health: HealthBoard::default(),
+        };
+        for entry in self.modules {
+            running.start(entry);
+        }
+        running.update_modules();
+        running
+    }
+}
+
+/// The filters to subscribe to for the required topics and those of the modules
+fn subscriptions<'a>(
+    required_topics: &[String],
+    modules: impl Iterator<Item = &'a dyn Module>,
+) -> BTreeSet<String> {
+    let mut topics: BTreeSet<String> = required_topics.iter().cloned().collect();
+    for module in modules {
+        topics.extend(module_topics(module));
+    }
+    topics
+}
+
+/// The filters a module needs subscribed, per its inputs
+fn module_topics(module: &dyn Module) -> Vec<String> {
+    let inputs = module.inputs();
+    let mut topics = if inputs.received {
+        module.topics()
+    } else {
+        Vec::new()
+    };
+    if inputs.sys {
+        topics.push("$SYS/#".to_string());
+    }
+    topics
+}
+
+/// A module running under its supervisor
+struct RunningModule {
+    name: &'static str,
+    /// The topics it receives, see `module_topics`
+    topics: Vec<String>,
+    settings: String,
+    cancel_token: CancellationToken,
+    handle: JoinHandle<()>,
+}
+
+/// The spawned modules, which can be changed on a config reload
+pub struct RunningModules {
+    task_tracker: TaskTracker,
+    cancel_token: CancellationToken,
+    channels: ModuleChannels,
+    base_node: String,
+    required_topics: Vec<String>,
+    running: Vec<RunningModule>,
+    health: HealthBoard,
+}
+
+impl RunningModules {
+    fn start(&mut self, entry: ModuleEntry) {
+        info!("Running {} module", entry.module.name());
+        let cancel_token = self.cancel_token.child_token();
+        let name = entry.module.name();
+        let topics = module_topics(entry.module.as_ref());
+        let health = ModuleHealth::new();
+        self.health.insert(name, health.clone());
+        let handle = self.task_tracker.spawn(supervise(
+            self.task_tracker.clone(),
+            cancel_token.clone(),
+            entry.module,
+            entry.restart,
+            self.channels.clone(),
+            self.base_node.clone(),
+            health,
+        ));
+        self.running.push(RunningModule {
+            name,
+            topics,
+            settings: entry.settings,
+            cancel_token,
+            handle,
+        });
+    }
+
+    /// The health of the running modules, kept up to date across reloads
+    pub fn health(&self) -> HealthBoard {
+        self.health.clone()
+    }
+
+    /// The names of the running modules
+    pub fn names(&self) -> Vec<&'static str> {
+        self.running.iter().map(|running| running.name).collect()
+    }
+
+    /// Move to the modules of a new registry, only stopping and starting the
+    /// modules which were removed, added, or had their settings changed
+    pub async fn reload(&mut self, registry: ModuleRegistry) {
+        let mut entries = registry.modules;
+        let mut kept = Vec::new();
+
+        for running in std::mem::take(&mut self.running) {
+            let found = entries
+                .iter()
+                .position(|entry| entry.module.name() == running.name);
+            if let Some(idx) = found
+                && entries[idx].settings == running.settings
+            {
+                entries.remove(idx);
+                kept.push(running);
+                continue;
+            }
+
+            if found.is_some() {
+                info!("Settings of {} module changed, restarting", running.name);
+            } else {
+                info!("Stopping {} module", running.name);
+            }
+            // wait for it to go down fully, so it has released anything the new one needs
+            running.cancel_token.cancel();
+            if let Err(err) = running.handle.await {
+                warn!("Could not stop {} module cleanly: {}", running.name, err);
+            }
+            self.health.remove(running.name);
+        }
+
+        self.running = kept;
+        for entry in entries {
+            self.start(entry);
+        }
+        self.update_subscriptions();
+        self.update_modules();
+    }
+
+    /// Subscribe to the topics of the running modules, and no others
+    fn update_subscriptions(&self) {
+        let mut topics: BTreeSet<String> = self.required_topics.iter().cloned().collect();
+        for running in &self.running {
+            topics.extend(running.topics.iter().cloned());
+        }
+        self.channels.subscriptions.send_if_modified(|current| {
+            let changed = *current != topics;
+            *current = topics;
+            changed
+        });
+    }
+
+    /// Tell daemon internals which modules are running
+    fn update_modules(&self) {
+        let names: BTreeSet<String> = self.names().into_iter().map(str::to_string).collect();
+        self.channels.modules.send_if_modified(|current| {
+            let changed = *current != names;
+            *current = names;
+            changed
+        });
+    }
+}
@@ -1,64 +1,187 @@
 //! HELPER: Receive and send MQTT
 
 use std::{
-    sync::Arc,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    error::Error,
+    fs,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, SystemTime},
 };
 
-use protobuf::{Message, SpecialFields};
-use rumqttc::v5::{
-    AsyncClient, Event, EventLoop, MqttOptions,
-    mqttbytes::{
-        QoS,
-        v5::{Packet, Publish},
+use futures_util::future::BoxFuture;
+use rumqttc::{
+    TlsConfiguration,
+    tokio_rustls::rustls::{
+        ClientConfig, RootCertStore,
+        crypto::aws_lc_rs,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    },
+    v5::{
+        AsyncClient, Event, EventLoop, MqttOptions,
+        mqttbytes::{
+            QoS,
+            v5::{LastWill, Packet, PublishProperties},
+        },
     },
 };
-use tokio::sync::{
-    broadcast,
-    mpsc::{self, Receiver},
-    watch::Sender,
-};
-use tokio_util::sync::CancellationToken;
-use tracing::{debug, info, trace, warn};
+use tokio::sync::Mutex;
+use tracing::{info, trace, warn};
 
 use crate::{
-    HV_EN_TOPIC, HVTransition, MUTE_EN_TOPIC, PublishableMessage, SAVE_LOCATION, SEND_LOGGER_DATA,
-    SEND_SERIAL_DATA, SEND_VIDEO_DATA, playback_data, serverdata, uploader::upload_files,
+    PublishableMessage,
+    config::{MqttConfig, PublishOptions, Qos},
+    transport::{Incoming, ORIGIN_KEY, Transport, TransportResult, encode},
 };
 
-/// The chief processor of incoming mqtt data, this handles
-/// - mqtt state
-/// - reception via mqtt and subsequent parsing
-///   Takes in many channels:
-/// - mqtt_sender_rx: A receiver of any messages, it then publishes them
-/// - hv_stat_send: A sender of the current HV state (only if it changes!), will be set to ON if augment_hv_on is true
-/// - mute_stat_send: A sender of the current mute button state
-/// - mqtt_recv_tx: Optional, a sender of all mqtt messages, if None no messages sent
-pub struct MqttProcessor {
-    cancel_token: CancellationToken,
-    mqtt_sender_rx: Receiver<PublishableMessage>,
-    hv_stat_send: Sender<HVTransition>,
-    augment_hv_on: bool,
-    mute_stat_send: Sender<bool>,
-    mqtt_recv_tx: Option<broadcast::Sender<playback_data::PlaybackData>>,
-    mqtt_sys_send: Option<mpsc::Sender<Publish>>,
-    scylla_url: Option<String>,
+/// The env var holding the MQTT password, taking precedence over `mqtt.password_file`
+pub const PASSWORD_ENV: &str = "ODYSSEUS_DAEMON_MQTT_PASSWORD";
+
+/// How long to wait before reconnecting after a connection error
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// A parsed MQTT/Siren URL
+#[derive(Debug, PartialEq)]
+pub struct MqttUrl {
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+}
+
+/// Parse a MQTT URL, either `host:port`, `mqtt://host[:port]` or `mqtts://host[:port]` for TLS
+pub fn parse_url(url: &str) -> Result<MqttUrl, String> {
+    let (tls, rest) = match url.split_once("://") {
+        Some(("mqtt" | "tcp", rest)) => (false, rest),
+        Some(("mqtts" | "ssl", rest)) => (true, rest),
+        Some((scheme, _)) => return Err(format!("Unsupported MQTT URL scheme {scheme} in {url}")),
+        None => (false, url),
+    };
+    let rest = rest.trim_end_matches('/');
+    let (host, port) = match rest.rsplit_once(':') {
+        // an IPv6 address without a port
+        Some((host, _)) if host.starts_with('[') && !host.ends_with(']') => (rest, None),
+        Some((host, port)) => (host, Some(port)),
+        None => (rest, None),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() || host.contains(['/', '@']) {
+        return Err(format!("Invalid MQTT host in {url}"));
+    }
+    let port = match port {
+        Some(port) => port
+            .parse::<u16>()
+            .map_err(|_| format!("Invalid MQTT port in {url}"))?,
+        None if tls => 8883,
+        None => 1883,
+    };
+    Ok(MqttUrl {
+        host: host.to_string(),
+        port,
+        tls,
+    })
+}
+
+/// The password from the env, else from the password file
+fn password(conf: &MqttConfig) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+    if let Ok(password) = std::env::var(PASSWORD_ENV) {
+        return Ok(Some(password));
+    }
+    match &conf.password_file {
+        Some(path) => Ok(Some(
+            fs::read_to_string(path)
+                .map_err(|err| format!("Could not read {}: {}", path.display(), err))?
+                .trim_end_matches(['\r', '\n'])
+                .to_string(),
+        )),
+        None => Ok(None),
+    }
+}
+
+/// The retained `<base_node>/Daemon/Alive` message, 1 once connected and 0 as the last will
+fn alive(topic: &str, alive: bool) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    Ok(encode(PublishableMessage {
+        topic: topic.to_string(),
+        data: vec![if alive { 1.0 } else { 0.0 }],
+        unit: "bool".to_string(),
+        time: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_micros() as u64,
+        options: None,
+    })?)
+}
+
+/// Read a PEM file for the TLS config
+fn read_pem(path: &Path) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    Ok(fs::read(path).map_err(|err| format!("Could not read {}: {}", path.display(), err))?)
+}
+
+/// The certificates of a PEM file
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Box<dyn Error + Send + Sync>> {
+    Ok(CertificateDer::pem_slice_iter(&read_pem(path)?)
+        .collect::<Result<_, _>>()
+        .map_err(|err| format!("Bad certificate in {}: {}", path.display(), err))?)
 }
 
-#[allow(clippy::too_many_arguments)]
-impl MqttProcessor {
-    /// Creates a new mqtt receiver and sender
+/// The TLS config: trusting `ca`, else the platform roots, with the client certificate if given
+fn tls_config(conf: &MqttConfig) -> Result<ClientConfig, Box<dyn Error + Send + Sync>> {
+    let mut roots = RootCertStore::empty();
+    match &conf.ca {
+        Some(ca) => {
+            roots.add_parsable_certificates(read_certs(ca)?);
+        }
+        None => {
+            let native = rustls_native_certs::load_native_certs();
+            for err in native.errors {
+                warn!("Could not load platform certificates: {}", err);
+            }
+            roots.add_parsable_certificates(native.certs);
+        }
+    }
+    if roots.is_empty() {
+        return Err("No CA certificates to verify the broker with (mqtt.ca)".into());
+    }
+
+    // explicit, as more than one provider is compiled in
+    let builder = ClientConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+    Ok(match (&conf.client_cert, &conf.client_key) {
+        (Some(cert), Some(key)) => {
+            let key = PrivateKeyDer::from_pem_slice(&read_pem(key)?)
+                .map_err(|err| format!("Bad private key in {}: {}", key.display(), err))?;
+            builder.with_client_auth_cert(read_certs(cert)?, key)?
+        }
+        // `DaemonConfig::validate` rejects a lone cert or key
+        _ => builder.with_no_client_auth(),
+    })
+}
+
+/// The MQTT transport, see `transport`
+pub struct MqttTransport {
+    client: AsyncClient,
+    /// Polled by `recv`, which drives the whole connection
+    eventloop: Mutex<EventLoop>,
+    connected: AtomicBool,
+    /// Whether the last poll failed, to only warn once per outage
+    failing: AtomicBool,
+    /// For the publish rules
+    conf: MqttConfig,
+    /// Where the daemon announces itself, see `alive`
+    alive_topic: String,
+    /// Whether the daemon is yet to be announced since connecting, retried on every poll
+    announcing: AtomicBool,
+}
+
+impl MqttTransport {
+    /// Creates a new mqtt client for the Siren, with TLS and credentials per the config,
+    /// and a last will announcing the daemon is gone
     pub fn new(
-        cancel_token: CancellationToken,
-        mqtt_sender_rx: Receiver<PublishableMessage>,
-        hv_stat_send: Sender<HVTransition>,
-        augment_hv_on: bool,
-        mute_stat_send: Sender<bool>,
-        mqtt_recv_tx: Option<broadcast::Sender<playback_data::PlaybackData>>,
-        mqtt_sys_send: Option<mpsc::Sender<Publish>>,
-        mqtt_path: String,
-        scylla_url: Option<String>,
-    ) -> (MqttProcessor, MqttOptions) {
+        conf: &MqttConfig,
+        base_node: &str,
+    ) -> Result<MqttTransport, Box<dyn Error + Send + Sync>> {
+        let url = parse_url(&conf.url)?;
         // create the mqtt client and configure it
         let mut mqtt_opts = MqttOptions::new(
             format!(
@@ -68,210 +191,211 @@ impl MqttProcessor {
                     .expect("Time went backwards")
                     .as_millis()
             ),
-            mqtt_path.split_once(':').expect("Invalid Siren URL").0,
-            mqtt_path
-                .split_once(':')
-                .unwrap()
-                .1
-                .parse::<u16>()
-                .expect("Invalid Siren port"),
+            url.host,
+            url.port,
         );
         mqtt_opts
             .set_keep_alive(Duration::from_secs(20))
             .set_clean_start(false)
             .set_connection_timeout(3);
         //       .set_session_expiry_interval(Some(u32::MAX))
+        let alive_topic = format!("{base_node}/Daemon/Alive");
+        mqtt_opts.set_last_will(LastWill::new(
+            &alive_topic,
+            alive(&alive_topic, false)?,
+            QoS::AtLeastOnce,
+            true,
+            None,
+        ));
 
-        (
-            MqttProcessor {
-                cancel_token,
-                mqtt_sender_rx,
-                hv_stat_send,
-                augment_hv_on,
-                mute_stat_send,
-                mqtt_recv_tx,
-                mqtt_sys_send,
-                scylla_url,
-            },
-            mqtt_opts,
-        )
-    }
-
-    /// This handles the reception of mqtt messages, will not return
-    /// * `eventloop` - The eventloop returned by ::new to connect to.  The loop isnt sync so this is the best that can be done
-    /// * `client` - The async mqttt v5 client to use for subscriptions
-    pub async fn process_mqtt(self, client: Arc<AsyncClient>, mut eventloop: EventLoop) {
-        debug!("Subscribing to siren, all topics");
-        client
-            .subscribe("#", rumqttc::v5::mqttbytes::QoS::ExactlyOnce)
-            .await
-            .expect("Could not subscribe to Siren");
-        if self.mqtt_sys_send.is_some() {
-            debug!("Subscribing to $SYS");
-            client
-                .subscribe("$SYS/#", rumqttc::v5::mqttbytes::QoS::ExactlyOnce)
-                .await
-                .expect("Could not subscribe to Siren");
+        if url.tls {
+            mqtt_opts.set_transport(rumqttc::Transport::tls_with_config(TlsConfiguration::from(
+                tls_config(conf)?,
+            )));
         }
-        // if augment HV on, send as such, otherwise start default off
-        let mut last_stat = if self.augment_hv_on {
-            let time = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as u64;
-            warn!("HV status permanently set on!!");
-            if let Err(err) =
-                std::fs::create_dir(format!("{}/event-{}", SAVE_LOCATION.get().unwrap(), time))
-            {
-                panic!("Could not create folder for data, bailing out of this loop! {err}");
+
+        let password = password(conf)?;
+        match (&conf.username, password) {
+            (Some(username), password) => {
+                mqtt_opts.set_credentials(username, password.unwrap_or_default());
             }
-            self.hv_stat_send
-                .send(HVTransition::TransitionOn(crate::HVOnData {
-                    time_ms: time,
-                }))
-                .expect("HV Stat Channel Closed");
-            true
-        } else {
-            false
-        };
+            (None, Some(_)) => warn!("Ignoring MQTT password without a username (mqtt.username)"),
+            (None, None) => {}
+        }
 
-        info!("Spawning MQTT publisher!");
-        tokio::spawn(pub_handle(
-            self.cancel_token.clone(),
-            self.mqtt_sender_rx,
+        let (client, eventloop) = AsyncClient::new(mqtt_opts, 600);
+        Ok(MqttTransport {
             client,
-        ));
+            eventloop: Mutex::new(eventloop),
+            connected: AtomicBool::new(false),
+            failing: AtomicBool::new(false),
+            conf: conf.clone(),
+            alive_topic,
+            announcing: AtomicBool::new(false),
+        })
+    }
 
-        loop {
-            tokio::select! {
-                _ = self.cancel_token.cancelled() => {
-                    debug!("Shutting down MQTT processor!");
-                    break;
-                },
-                msg = eventloop.poll() => match msg {
-                    Ok(Event::Incoming(Packet::Publish(msg))) => {
-                        let Ok(topic) = std::str::from_utf8(&msg.topic) else {
-                            warn!("Could not parse topic, topic: {:?}", msg.topic);
-                            continue;
-                        };
-                        if let Some(ref sender) = self.mqtt_sys_send
-                            && topic.starts_with("$SYS") {
-                            if let Err(err) = sender.send(msg).await {
-                                warn!("Could not send to SYS processor: {}", err);
-                            }
-                            continue;
-                            };
+    /// Publish that the daemon is up, without waiting as only `recv` frees up the request queue
+    fn announce(&self) -> TransportResult {
+        let payload = alive(&self.alive_topic, true)?;
+        self.client
+            .try_publish(&self.alive_topic, QoS::AtLeastOnce, true, payload)?;
+        Ok(())
+    }
+
+    /// Publish with the options, or those of the topic, and extra properties
+    async fn send(
+        &self,
+        topic: &str,
+        payload: Vec<u8>,
+        options: Option<PublishOptions>,
+        mut properties: PublishProperties,
+    ) -> TransportResult {
+        let options = options.unwrap_or_else(|| self.conf.options(topic));
+        let qos = match options.qos {
+            Qos::AtMostOnce => QoS::AtMostOnce,
+            Qos::AtLeastOnce => QoS::AtLeastOnce,
+            Qos::ExactlyOnce => QoS::ExactlyOnce,
+        };
+        properties.message_expiry_interval = options.expiry_secs;
+        if properties == PublishProperties::default() {
+            self.client
+                .publish(topic, qos, options.retain, payload)
+                .await?
+        } else {
+            self.client
+                .publish_with_properties(topic, qos, options.retain, payload, properties)
+                .await?
+        }
+        Ok(())
+    }
+}
 
+impl Transport for MqttTransport {
+    fn connect(&self) -> BoxFuture<'_, TransportResult> {
+        // the eventloop connects on the first poll, and reconnects on every poll after an error
+        Box::pin(async { Ok(()) })
+    }
 
-                        let Ok(res) = serverdata::ServerData::parse_from_bytes(&msg.payload) else {
-                            warn!("Recieved unparsable mqtt message.");
-                            continue;
-                        };
+    fn subscribe<'a>(&'a self, filter: &'a str) -> BoxFuture<'a, TransportResult> {
+        Box::pin(async move {
+            self.client.subscribe(filter, QoS::ExactlyOnce).await?;
+            Ok(())
+        })
+    }
 
-                        let val = *res.values.first().unwrap_or(&-1f32) as u8;
-                        match topic {
-                            HV_EN_TOPIC => {
-                                if !self.augment_hv_on {
-                                    // ensure only triggering upon change from previous loop
-                                    if val == 1 && !last_stat {
-                                        debug!("Transitioning states to HV on, creating folder!");
-                                        if let Err(err) = std::fs::create_dir(format!("{}/event-{}", SAVE_LOCATION.get().unwrap(), res.time_us / 1000)) {
-                                            warn!("Could not create folder for data, bailing out of this loop! {}", err);
-                                            continue;
-                                        }
-                                       self.hv_stat_send.send(HVTransition::TransitionOn(
-                                           crate::HVOnData { time_ms:  res.time_us / 1000})).expect("HV Stat Channel Closed");
-                                        last_stat = true;
-                                    } else if val == 0 && last_stat {
-                                        debug!("Transitioning states to HV off");
-                                        self.hv_stat_send.send(HVTransition::TransitionOff).expect("HV Stat Channel Closed");
-                                        last_stat = false;
-                                    } else if val != 0 && val != 1 {
-                                        warn!("Received bad HV message!");
-                                    }
-                                }
-                            },
-                            MUTE_EN_TOPIC => {
-                                // mute button messages should be single shot
-                                if val == 1 {
-                                    self.mute_stat_send.send(true).expect("Mute Stat Channel Closed");
-                                } else if val == 0 {
-                                    self.mute_stat_send.send(false).expect("Mute Stat Channel Closed");
-                                } else {
-                                    warn!("Received bad mute message!");
-                                }
-                            },
-                            SEND_LOGGER_DATA => {
-                                if !last_stat && let Some(url) = &self.scylla_url {
-                                    info!("Sending Logger Data, {}", val);
+    fn unsubscribe<'a>(&'a self, filter: &'a str) -> BoxFuture<'a, TransportResult> {
+        Box::pin(async move {
+            self.client.unsubscribe(filter).await?;
+            Ok(())
+        })
+    }
 
-                                    upload_files(SAVE_LOCATION.get().unwrap(), url, true, false, false);
-                                }
-                            },
-                            SEND_SERIAL_DATA => {
-                                if !last_stat && let Some(url) = &self.scylla_url {
-                                    info!("Sending Serial Data, {}", val);
+    fn publish<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: Vec<u8>,
+        options: Option<PublishOptions>,
+    ) -> BoxFuture<'a, TransportResult> {
+        Box::pin(self.send(topic, payload, options, PublishProperties::default()))
+    }
 
-                                    upload_files(SAVE_LOCATION.get().unwrap(), url, false, false, true);
-                                }
-                            },
-                            SEND_VIDEO_DATA => {
-                                if !last_stat && let Some(url) = &self.scylla_url {
-                                    info!("Sending Video Data, {}", val);
+    fn forward<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: Vec<u8>,
+        origin: &'a str,
+    ) -> BoxFuture<'a, TransportResult> {
+        let properties = PublishProperties {
+            user_properties: vec![(ORIGIN_KEY.to_string(), origin.to_string())],
+            ..Default::default()
+        };
+        Box::pin(self.send(topic, payload, None, properties))
+    }
 
-                                    upload_files(SAVE_LOCATION.get().unwrap(), url, false, true, false);
-                                }
-                            }
-                            _ => {
-                            }
+    fn recv(&self) -> BoxFuture<'_, Option<Incoming>> {
+        Box::pin(async {
+            let mut eventloop = self.eventloop.lock().await;
+            loop {
+                if self.announcing.load(Ordering::Relaxed) {
+                    match self.announce() {
+                        Ok(()) => self.announcing.store(false, Ordering::Relaxed),
+                        Err(err) => trace!("Could not announce the daemon yet: {}", err),
+                    }
+                }
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(Packet::Publish(msg))) => {
+                        let Ok(topic) = std::str::from_utf8(&msg.topic) else {
+                            warn!("Could not parse topic, topic: {:?}", msg.topic);
+                            continue;
+                        };
+                        if topic.starts_with("$SYS") {
+                            return Some(Incoming::Sys(msg));
+                        }
+                        let origin = msg.properties.as_ref().and_then(|properties| {
+                            properties
+                                .user_properties
+                                .iter()
+                                .find(|(key, _)| key == ORIGIN_KEY)
+                                .map(|(_, origin)| origin.clone())
+                        });
+                        return Some(Incoming::Message {
+                            topic: topic.to_string(),
+                            payload: msg.payload.to_vec(),
+                            origin,
+                        });
+                    }
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("Connected to Siren");
+                        self.connected.store(true, Ordering::Relaxed);
+                        self.failing.store(false, Ordering::Relaxed);
+                        self.announcing.store(true, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        self.connected.store(false, Ordering::Relaxed);
+                        if self.failing.swap(true, Ordering::Relaxed) {
+                            trace!("Recieved error: {}", e);
+                        } else {
+                            warn!("Siren connection failed, retrying: {}", e);
                         }
-                        // if using it, send all mqtt messages to data logger
-                        if let Some(ref recv) = self.mqtt_recv_tx
-                            && let Err(err) = recv.send(playback_data::PlaybackData{
-                                topic:topic.to_string(),values:res.values,unit:res.unit,time_us:res.time_us, special_fields: SpecialFields::new() }) {
-                                warn!("Error sending message received! {}", err);
-                            }
+                        // the next poll reconnects, wait so a refused connection does not spin
+                        tokio::time::sleep(RECONNECT_DELAY).await;
                     }
-                    Err(e) => trace!("Recieved error: {}", e),
                     _ => {}
-                },
+                }
             }
-        }
+        })
+    }
+
+    fn connected(&self) -> BoxFuture<'_, bool> {
+        Box::pin(async { self.connected.load(Ordering::Relaxed) })
     }
 }
 
-async fn pub_handle(
-    cancel_token: CancellationToken,
-    mut mqtt_sender_rx: Receiver<PublishableMessage>,
-    client: Arc<AsyncClient>,
-) {
-    loop {
-        tokio::select! {
-            _ = cancel_token.cancelled() => {
-                    debug!("Shutting down MQTT publisher!");
-                    break;
-            },
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            sendable = mqtt_sender_rx.recv() => {
-                    match sendable {
-                        Some(sendable) => {
-                            trace!("Sending {:?}", sendable);
-                            let mut payload = serverdata::ServerData::new();
-                            payload.unit = sendable.unit.to_string();
-                            payload.values = sendable.data;
-                            payload.time_us =  sendable.time;
-                            let Ok(bytes) = protobuf::Message::write_to_bytes(&payload) else {
-                                warn!("Failed to serialize protobuf message!");
-                                continue;
-                            };
-                            let Ok(_) = client.publish(sendable.topic, QoS::ExactlyOnce, false, bytes).await else {
-                                warn!("Failed to send MQTT message!");
-                                continue;
-                            };
-                        },
-                        None => continue,
-                    }
-                }
-        }
+    fn url(host: &str, port: u16, tls: bool) -> Result<MqttUrl, String> {
+        Ok(MqttUrl {
+            host: host.to_string(),
+            port,
+            tls,
+        })
+    }
+
+    #[test]
+    fn parses_urls() {
+        assert_eq!(parse_url("localhost:1883"), url("localhost", 1883, false));
+        assert_eq!(parse_url("mqtt://siren"), url("siren", 1883, false));
+        assert_eq!(parse_url("mqtts://siren"), url("siren", 8883, true));
+        assert_eq!(parse_url("mqtts://siren:9883/"), url("siren", 9883, true));
+        assert_eq!(parse_url("mqtt://[::1]:1884"), url("::1", 1884, false));
+        assert_eq!(parse_url("mqtts://[fe80::1]"), url("fe80::1", 8883, true));
+        assert!(parse_url("http://siren:80").is_err());
+        assert!(parse_url("siren:port").is_err());
+        assert!(parse_url("siren:70000").is_err());
+        assert!(parse_url("mqtt://:1883").is_err());
+        assert!(parse_url("mqtt://user@siren").is_err());
     }
 }
@@ -5,71 +5,127 @@
 //! Requires:
 //!  - SYSFS for given iface
 
+use futures_util::future::BoxFuture;
 use std::{path::PathBuf, time::Duration, time::UNIX_EPOCH};
 use tracing::trace;
 
-use tokio::{sync::mpsc::Sender, time::Instant};
+use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, warn};
 
-use crate::PublishableMessage;
+use crate::{
+    PublishableMessage,
+    health::ModuleHealth,
+    module::{Module, ModuleContext, ModuleInputs, ModuleResult},
+    publish::Publisher,
+    sim,
+};
 
 /// The path of the measurement in sysfs
 /// the message to publish
 /// the last instant and value it was measured, or None if its a simple read
-type NetMeasurement = (PathBuf, PublishableMessage, Option<(Instant, u32)>);
+type NetMeasurement = (PathBuf, PublishableMessage, Option<(Instant, u64)>);
+
+/// The network statistics module, see `network_scraper`
+pub struct NetModule {
+    base_name: String,
+    network_ifaces: Vec<String>,
+}
+
+impl NetModule {
+    pub fn new(base_name: String, network_ifaces: Vec<String>) -> Self {
+        Self {
+            base_name,
+            network_ifaces,
+        }
+    }
+}
+
+impl Module for NetModule {
+    fn name(&self) -> &'static str {
+        "Net"
+    }
+
+    fn inputs(&self) -> ModuleInputs {
+        ModuleInputs {
+            publish: true,
+            ..Default::default()
+        }
+    }
+
+    fn run(&self, mut ctx: ModuleContext) -> BoxFuture<'static, ModuleResult> {
+        let base_name = self.base_name.clone();
+        let network_ifaces = self.network_ifaces.clone();
+        Box::pin(async move {
+            network_scraper(
+                ctx.cancel_token.clone(),
+                ctx.publisher()?,
+                base_name,
+                network_ifaces,
+                ctx.health,
+            )
+            .await;
+            Ok(())
+        })
+    }
+}
 
 pub async fn network_scraper(
     cancel_token: CancellationToken,
-    mqtt_sender_tx: Sender<PublishableMessage>,
+    mqtt_sender_tx: Publisher,
     base_name: String,
     network_ifaces: Vec<String>,
+    health: ModuleHealth,
 ) {
     let mut sync_timer = tokio::time::interval(Duration::from_millis(250));
     // the main structure of data to mutate
     let mut send_list: Vec<NetMeasurement> = vec![];
 
     for iface in network_ifaces {
-        let path: PathBuf = PathBuf::from(format!("/sys/class/net/{iface}/statistics/tx_bytes"));
+        let path: PathBuf = sim::sys_path(&format!("/sys/class/net/{iface}/statistics/tx_bytes"));
 
         let msg = PublishableMessage {
             topic: format!("{base_name}/{iface}/tx_bytes"),
             data: vec![],
             unit: "bytes/s".to_string(),
             time: 0,
+            options: None,
         };
 
         send_list.push((path, msg, Some((Instant::now(), 0))));
 
-        let path: PathBuf = PathBuf::from(format!("/sys/class/net/{iface}/statistics/rx_bytes"));
+        let path: PathBuf = sim::sys_path(&format!("/sys/class/net/{iface}/statistics/rx_bytes"));
 
         let msg = PublishableMessage {
             topic: format!("{base_name}/{iface}/rx_bytes"),
             data: vec![],
             unit: "bytes/s".to_string(),
             time: 0,
+            options: None,
         };
 
         send_list.push((path, msg, Some((Instant::now(), 0))));
 
-        let path: PathBuf = PathBuf::from(format!("/sys/class/net/{iface}/statistics/rx_errors"));
+        let path: PathBuf = sim::sys_path(&format!("/sys/class/net/{iface}/statistics/rx_errors"));
 
         let msg = PublishableMessage {
             topic: format!("{base_name}/{iface}/rx_errors"),
             data: vec![],
             unit: "bytes".to_string(),
             time: 0,
+            options: None,
         };
 
         send_list.push((path, msg, None));
 
-        let path: PathBuf = PathBuf::from(format!("/sys/class/net/{iface}/statistics/tx_errors"));
+        let path: PathBuf = sim::sys_path(&format!("/sys/class/net/{iface}/statistics/tx_errors"));
 
         let msg = PublishableMessage {
             topic: format!("{base_name}/{iface}/tx_errors"),
             data: vec![],
             unit: "bytes".to_string(),
             time: 0,
+            options: None,
         };
 
         send_list.push((path, msg, None));
@@ -84,9 +140,11 @@ pub async fn network_scraper(
             _ = sync_timer.tick() => {
                 if let Err(err) = handle_tick(&mut send_list).await {
                     warn!("Error trying to read net statistics: {}", err);
+                    health.error();
                     continue;
                 }
                 handle_sends(&send_list, &mqtt_sender_tx).await;
+                health.work();
             }
         }
     }
@@ -96,7 +154,7 @@ async fn handle_tick(send_list: &mut [NetMeasurement]) -> Result<(), std::io::Er
     for item in send_list.iter_mut() {
         let ok = tokio::fs::read_to_string(item.0.clone()).await?;
         let ok = ok.trim();
-        let res = ok.parse::<u32>().unwrap_or(0);
+        let res = ok.parse::<u64>().unwrap_or(0);
         item.1.data = if let Some(edit) = item.2.as_mut() {
             let old_time = edit.0;
             edit.0 = Instant::now();
@@ -106,7 +164,8 @@ async fn handle_tick(send_list: &mut [NetMeasurement]) -> Result<(), std::io::Er
                 "Debug write {} - {} / {:?} - {:?}",
                 edit.1, old, edit.0, old_time
             );
-            vec![((edit.1 - old) as f32 / (edit.0 - old_time).as_secs_f32())]
+            // a counter reset (iface recreated) reads as no traffic
+            vec![(edit.1.saturating_sub(old) as f32 / (edit.0 - old_time).as_secs_f32())]
         } else {
             vec![res as f32]
         };
@@ -116,13 +175,46 @@ async fn handle_tick(send_list: &mut [NetMeasurement]) -> Result<(), std::io::Er
     Ok(())
 }
 
-async fn handle_sends(
-    send_list: &Vec<NetMeasurement>,
-    mqtt_sender_tx: &Sender<PublishableMessage>,
-) {
+async fn handle_sends(send_list: &Vec<NetMeasurement>, mqtt_sender_tx: &Publisher) {
     for item in send_list {
         if let Err(err) = mqtt_sender_tx.send(item.1.clone()).await {
             warn!("Error putting message in MQTT queue for net: {} ", err);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counter(path: PathBuf) -> NetMeasurement {
+        let msg = PublishableMessage {
+            topic: "TPU/Net/wlan0/tx_bytes".to_string(),
+            data: vec![],
+            unit: "bytes/s".to_string(),
+            time: 0,
+            options: None,
+        };
+        (path, msg, Some((Instant::now(), 0)))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rates_counters_past_u32() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tx_bytes");
+        let mut send_list = vec![counter(path.clone())];
+
+        std::fs::write(&path, "5000000000\n").unwrap();
+        handle_tick(&mut send_list).await.unwrap();
+        tokio::time::advance(Duration::from_secs(1)).await;
+        std::fs::write(&path, "5000120000\n").unwrap();
+        handle_tick(&mut send_list).await.unwrap();
+        assert_eq!(send_list[0].1.data, vec![120_000.0]);
+
+        // reset, so no traffic rather than an underflow
+        tokio::time::advance(Duration::from_secs(1)).await;
+        std::fs::write(&path, "100\n").unwrap();
+        handle_tick(&mut send_list).await.unwrap();
+        assert_eq!(send_list[0].1.data, vec![0.0]);
+    }
+}
@@ -13,17 +13,46 @@ use std::{
     time::{Duration, UNIX_EPOCH},
 };
 
+use futures_util::future::BoxFuture;
 use sysinfo::{Components, MemoryRefreshKind, Pid, ProcessesToUpdate, System};
-use tokio::sync::mpsc::Sender;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, trace, warn};
 
-use crate::PublishableMessage;
+use crate::{
+    PublishableMessage,
+    health::ModuleHealth,
+    module::{Module, ModuleContext, ModuleInputs, ModuleResult},
+    publish::Publisher,
+};
+
+/// The TPU data module, see `collect_data`
+pub struct DataModule;
+
+impl Module for DataModule {
+    fn name(&self) -> &'static str {
+        "Data"
+    }
+
+    fn inputs(&self) -> ModuleInputs {
+        ModuleInputs {
+            publish: true,
+            ..Default::default()
+        }
+    }
+
+    fn run(&self, mut ctx: ModuleContext) -> BoxFuture<'static, ModuleResult> {
+        Box::pin(async move {
+            collect_data(ctx.cancel_token.clone(), ctx.publisher()?, ctx.health).await;
+            Ok(())
+        })
+    }
+}
 
 /// sender of the messages
 pub async fn collect_data(
     cancel_token: CancellationToken,
-    mqtt_sender_tx: Sender<PublishableMessage>,
+    mqtt_sender_tx: Publisher,
+    health: ModuleHealth,
 ) {
     // create requisites
     let mut sys = System::new_all();
@@ -82,7 +111,7 @@ pub async fn collect_data(
                     }
                 };
 
-                vec![PublishableMessage{ topic: TOPIC.to_string(), data: vec![value.unwrap()], unit: UNIT.to_string(),time: UNIX_EPOCH.elapsed().unwrap().as_micros() as u64 }]
+                vec![PublishableMessage{ topic: TOPIC.to_string(), data: vec![value.unwrap()], unit: UNIT.to_string(),time: UNIX_EPOCH.elapsed().unwrap().as_micros() as u64, options: None }]
 
             }
             _ = cpu_usage_int.tick() => {
@@ -104,8 +133,8 @@ pub async fn collect_data(
                 trace!("Using process: {:?}", process.name());
 
                 vec![
-                    PublishableMessage{ topic: TOPIC_C.to_string(), data: vec![sys.global_cpu_usage()], unit: UNIT_C.to_string(), time: UNIX_EPOCH.elapsed().unwrap().as_micros() as u64 },
-                PublishableMessage{ topic: TOPIC_B.to_string(), data: vec![process.cpu_usage()], unit: UNIT_B.to_string(), time: UNIX_EPOCH.elapsed().unwrap().as_micros() as u64 }]
+                    PublishableMessage{ topic: TOPIC_C.to_string(), data: vec![sys.global_cpu_usage()], unit: UNIT_C.to_string(), time: UNIX_EPOCH.elapsed().unwrap().as_micros() as u64, options: None },
+                PublishableMessage{ topic: TOPIC_B.to_string(), data: vec![process.cpu_usage()], unit: UNIT_B.to_string(), time: UNIX_EPOCH.elapsed().unwrap().as_micros() as u64, options: None }]
             },
             _ = mem_avail_int.tick() => {
                 const TOPIC: &str = "TPU/OnBoard/MemAvailable";
@@ -113,14 +142,16 @@ pub async fn collect_data(
 
                 sys.refresh_memory_specifics(MemoryRefreshKind::nothing().with_ram());
 
-                vec![PublishableMessage { topic: TOPIC.to_string(), data: vec![sys.free_memory() as f32 / 1e6], unit: UNIT.to_string(), time: UNIX_EPOCH.elapsed().unwrap().as_micros() as u64}]
+                vec![PublishableMessage { topic: TOPIC.to_string(), data: vec![sys.free_memory() as f32 / 1e6], unit: UNIT.to_string(), time: UNIX_EPOCH.elapsed().unwrap().as_micros() as u64, options: None}]
             }
         };
         for msg in msgs {
             let Ok(_) = mqtt_sender_tx.send(msg).await else {
                 warn!("Could not send mpsc msg to mqtt send");
+                health.error();
                 continue;
             };
         }
+        health.work();
     }
 }
@@ -0,0 +1,372 @@
+//! HELPER: The outgoing publish queue, shared by every module
+//!
+//! Each module gets a `Publisher` tagged with its name, and what happens when the queue is full
+//! depends on the `PublishPolicy` of that name in `[publish]`:
+//!  - `block` waits for room, as a plain channel would
+//!  - `drop-oldest` drops the oldest queued message of the same module
+//!  - `drop-newest` drops the message being sent
+//!  - `coalesce` replaces a queued message of the same module and topic, at any queue depth,
+//!    falling back to `drop-oldest`
+//!
+//! So a slow link degrades telemetry instead of stalling data acquisition.
+//! Every dropped or replaced message is counted by topic in `ody_publish_dropped_total`,
+//! and `drop_reporter` publishes the counts to `{base_node}/Daemon/Dropped/{topic}`.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    error::Error,
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::{PublishableMessage, config::PublishPolicy, metrics::METRICS};
+
+/// How often the drop counts are published
+pub const DROP_REPORT_PERIOD: Duration = Duration::from_secs(10);
+
+/// The queue was closed as the transport stopped
+#[derive(Debug)]
+pub struct PublishClosed;
+
+impl Display for PublishClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "publish queue closed")
+    }
+}
+
+impl Error for PublishClosed {}
+
+struct Queued {
+    source: &'static str,
+    msg: PublishableMessage,
+}
+
+struct Inner {
+    msgs: VecDeque<Queued>,
+    closed: bool,
+}
+
+struct Shared {
+    inner: Mutex<Inner>,
+    capacity: usize,
+    /// Notified when a message is queued
+    readable: Notify,
+    /// Notified when a message is taken
+    writable: Notify,
+}
+
+/// Create the publish queue holding up to capacity messages
+pub fn queue(capacity: usize) -> (Publisher, PublishQueue) {
+    let shared = Arc::new(Shared {
+        inner: Mutex::new(Inner {
+            msgs: VecDeque::with_capacity(capacity),
+            closed: false,
+        }),
+        capacity: capacity.max(1),
+        readable: Notify::new(),
+        writable: Notify::new(),
+    });
+    (
+        Publisher {
+            shared: shared.clone(),
+            source: "Daemon",
+            policy: PublishPolicy::Block,
+        },
+        PublishQueue { shared },
+    )
+}
+
+/// A sender of messages to publish, see `publish`
+#[derive(Clone)]
+pub struct Publisher {
+    shared: Arc<Shared>,
+    source: &'static str,
+    policy: PublishPolicy,
+}
+
+impl Publisher {
+    /// A publisher on the same queue for another source and policy
+    pub fn with_source(&self, source: &'static str, policy: PublishPolicy) -> Publisher {
+        Publisher {
+            shared: self.shared.clone(),
+            source,
+            policy,
+        }
+    }
+
+    /// Queue a message, waiting or dropping per the policy if the queue is full.
+    /// Only fails if the queue was closed, dropped messages are counted rather than errors
+    pub async fn send(&self, msg: PublishableMessage) -> Result<(), PublishClosed> {
+        loop {
+            let writable = self.shared.writable.notified();
+            tokio::pin!(writable);
+            // register before checking, so a take between the check and the wait is not missed
+            writable.as_mut().enable();
+
+            {
+                let mut inner = self.shared.inner.lock().unwrap();
+                if inner.closed {
+                    return Err(PublishClosed);
+                }
+                if self.policy == PublishPolicy::Coalesce
+                    && let Some(queued) = inner
+                        .msgs
+                        .iter_mut()
+                        .find(|q| q.source == self.source && q.msg.topic == msg.topic)
+                {
+                    let replaced = std::mem::replace(&mut queued.msg, msg);
+                    METRICS.dropped(&replaced.topic);
+                    return Ok(());
+                }
+                if inner.msgs.len() < self.shared.capacity {
+                    inner.msgs.push_back(Queued {
+                        source: self.source,
+                        msg,
+                    });
+                    drop(inner);
+                    self.shared.readable.notify_one();
+                    return Ok(());
+                }
+                match self.policy {
+                    PublishPolicy::Block => {}
+                    PublishPolicy::DropNewest => {
+                        METRICS.dropped(&msg.topic);
+                        return Ok(());
+                    }
+                    PublishPolicy::DropOldest | PublishPolicy::Coalesce => {
+                        match inner.msgs.iter().position(|q| q.source == self.source) {
+                            Some(idx) => {
+                                let oldest = inner.msgs.remove(idx).expect("Position in queue");
+                                METRICS.dropped(&oldest.msg.topic);
+                                inner.msgs.push_back(Queued {
+                                    source: self.source,
+                                    msg,
+                                });
+                            }
+                            // the queue is full of other modules' messages
+                            None => METRICS.dropped(&msg.topic),
+                        }
+                        return Ok(());
+                    }
+                }
+            }
+
+            writable.await;
+        }
+    }
+
+    /// How many messages are queued
+    pub fn len(&self) -> usize {
+        self.shared.inner.lock().unwrap().msgs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The receiving end of the publish queue, given to the transport
+pub struct PublishQueue {
+    shared: Arc<Shared>,
+}
+
+impl PublishQueue {
+    /// Wait for the next message to publish
+    pub async fn recv(&mut self) -> PublishableMessage {
+        loop {
+            let popped = self.shared.inner.lock().unwrap().msgs.pop_front();
+            if let Some(queued) = popped {
+                self.shared.writable.notify_waiters();
+                return queued.msg;
+            }
+            self.shared.readable.notified().await;
+        }
+    }
+}
+
+impl Drop for PublishQueue {
+    fn drop(&mut self) {
+        self.shared.inner.lock().unwrap().closed = true;
+        self.shared.writable.notify_waiters();
+    }
+}
+
+/// Publish the count of dropped messages of every topic which dropped more since the last report
+pub async fn drop_reporter(
+    cancel_token: CancellationToken,
+    base_node: String,
+    publisher: Publisher,
+) {
+    let mut reported: BTreeMap<String, u64> = BTreeMap::new();
+    let mut period = tokio::time::interval(DROP_REPORT_PERIOD);
+
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => {
+                debug!("Shutting down drop reporter");
+                break;
+            },
+            _ = period.tick() => {
+                let time = UNIX_EPOCH.elapsed().unwrap().as_micros() as u64;
+                for (topic, cnt) in METRICS.dropped_counts() {
+                    if reported.get(&topic) == Some(&cnt) {
+                        continue;
+                    }
+                    let msg = PublishableMessage {
+                        topic: format!("{base_node}/Daemon/Dropped/{topic}"),
+                        data: vec![cnt as f32],
+                        unit: "messages".to_string(),
+                        time,
+                        options: None,
+                    };
+                    if let Err(err) = publisher.send(msg).await {
+                        warn!("Could not publish dropped count of {}: {}", topic, err);
+                    }
+                    reported.insert(topic, cnt);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(topic: &str, val: f32) -> PublishableMessage {
+        PublishableMessage {
+            topic: topic.to_string(),
+            data: vec![val],
+            unit: String::new(),
+            time: 0,
+            options: None,
+        }
+    }
+
+    /// The topic and value of every queued message, in order
+    async fn drain(queue: &mut PublishQueue, publisher: &Publisher) -> Vec<(String, f32)> {
+        let mut msgs = Vec::new();
+        while !publisher.is_empty() {
+            let msg = queue.recv().await;
+            msgs.push((msg.topic, msg.data[0]));
+        }
+        msgs
+    }
+
+    fn dropped(topic: &str) -> u64 {
+        METRICS.dropped_counts().get(topic).copied().unwrap_or(0)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn block_waits_for_room() {
+        let (publisher, mut queue) = queue(1);
+        publisher.send(msg("test/block", 1.0)).await.unwrap();
+
+        let blocked = tokio::spawn({
+            let publisher = publisher.clone();
+            async move { publisher.send(msg("test/block", 2.0)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!blocked.is_finished());
+
+        // taking one wakes it
+        assert_eq!(queue.recv().await.data, vec![1.0]);
+        blocked.await.unwrap().unwrap();
+        assert_eq!(queue.recv().await.data, vec![2.0]);
+        assert_eq!(dropped("test/block"), 0);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_evicts_own_messages() {
+        let (publisher, mut queue) = queue(2);
+        let own = publisher.with_source("Own", PublishPolicy::DropOldest);
+        let other = publisher.with_source("Other", PublishPolicy::Block);
+
+        other.send(msg("test/oldest/other", 1.0)).await.unwrap();
+        own.send(msg("test/oldest/own", 1.0)).await.unwrap();
+        own.send(msg("test/oldest/own", 2.0)).await.unwrap();
+        assert_eq!(
+            drain(&mut queue, &publisher).await,
+            vec![
+                ("test/oldest/other".to_string(), 1.0),
+                ("test/oldest/own".to_string(), 2.0)
+            ]
+        );
+        assert_eq!(dropped("test/oldest/own"), 1);
+
+        // full of other messages, so its own is dropped
+        other.send(msg("test/oldest/other", 2.0)).await.unwrap();
+        other.send(msg("test/oldest/other", 3.0)).await.unwrap();
+        own.send(msg("test/oldest/own", 3.0)).await.unwrap();
+        assert_eq!(
+            drain(&mut queue, &publisher).await,
+            vec![
+                ("test/oldest/other".to_string(), 2.0),
+                ("test/oldest/other".to_string(), 3.0)
+            ]
+        );
+        assert_eq!(dropped("test/oldest/own"), 2);
+        assert_eq!(dropped("test/oldest/other"), 0);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_counts() {
+        let (publisher, mut queue) = queue(1);
+        let newest = publisher.with_source("Newest", PublishPolicy::DropNewest);
+
+        newest.send(msg("test/newest", 1.0)).await.unwrap();
+        newest.send(msg("test/newest", 2.0)).await.unwrap();
+        newest.send(msg("test/newest", 3.0)).await.unwrap();
+        assert_eq!(
+            drain(&mut queue, &publisher).await,
+            vec![("test/newest".to_string(), 1.0)]
+        );
+        assert_eq!(dropped("test/newest"), 2);
+    }
+
+    #[tokio::test]
+    async fn coalesce_replaces_per_source_and_topic() {
+        let (publisher, mut queue) = queue(4);
+        let first = publisher.with_source("First", PublishPolicy::Coalesce);
+        let second = publisher.with_source("Second", PublishPolicy::Coalesce);
+
+        // replaced in place, with room to spare
+        first.send(msg("test/coalesce/a", 1.0)).await.unwrap();
+        first.send(msg("test/coalesce/b", 1.0)).await.unwrap();
+        second.send(msg("test/coalesce/a", 1.0)).await.unwrap();
+        first.send(msg("test/coalesce/a", 2.0)).await.unwrap();
+        assert_eq!(
+            drain(&mut queue, &publisher).await,
+            vec![
+                ("test/coalesce/a".to_string(), 2.0),
+                ("test/coalesce/b".to_string(), 1.0),
+                ("test/coalesce/a".to_string(), 1.0)
+            ]
+        );
+        assert_eq!(dropped("test/coalesce/a"), 1);
+        assert_eq!(dropped("test/coalesce/b"), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closed_once_the_queue_is_dropped() {
+        let (publisher, queue) = queue(1);
+        publisher.send(msg("test/closed", 1.0)).await.unwrap();
+        let blocked = tokio::spawn({
+            let publisher = publisher.clone();
+            async move { publisher.send(msg("test/closed", 2.0)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        drop(queue);
+        assert!(matches!(blocked.await.unwrap(), Err(PublishClosed)));
+        assert!(matches!(
+            publisher.send(msg("test/closed", 3.0)).await,
+            Err(PublishClosed)
+        ));
+    }
+}
@@ -0,0 +1,312 @@
+//! HELPER: Map MQTT topics to Zenoh keys and back, per `[zenoh.remap]`
+//!
+//! A topic goes to Zenoh by:
+//! - Dropping it if it matches a `drop` filter
+//! - Applying the regex rules `to = "zenoh"`, in order
+//! - Dropping it unless it starts with `strip_prefix`, then removing that
+//! - Escaping each level, so any topic is a valid key: `%`, `*`, `$`, `?`, `#`, `+`, spaces and
+//!   control characters become `%XX`, and an empty level becomes a lone `%`
+//! - Adding `add_prefix`
+//!
+//! and comes back from Zenoh by undoing each step in reverse, with the rules `to = "mqtt"`.
+//! Without rules this is lossless, so a topic sent through Zenoh is received unchanged.
+
+use regex::Regex;
+
+use crate::{
+    config::{RemapConfig, RewriteTo},
+    topic,
+};
+
+/// Escaped in key levels, on top of control characters
+const ESCAPED: &[char] = &['%', '*', '$', '?', '#', '+', ' '];
+
+/// The compiled `[zenoh.remap]`, see `remap`
+#[derive(Debug, Clone, Default)]
+pub struct Remap {
+    strip_prefix: String,
+    add_prefix: String,
+    drop: Vec<String>,
+    rules: Vec<(Regex, String, RewriteTo)>,
+}
+
+impl Remap {
+    pub fn new(conf: &RemapConfig) -> Result<Remap, String> {
+        for prefix in [&conf.strip_prefix, &conf.add_prefix].into_iter().flatten() {
+            if !prefix.ends_with('/') {
+                return Err(format!(
+                    "Remap prefix {prefix} must end in / (zenoh.remap.strip_prefix, zenoh.remap.add_prefix)"
+                ));
+            }
+        }
+        if let Some(prefix) = &conf.add_prefix
+            && !valid_key(prefix.trim_end_matches('/'))
+        {
+            return Err(format!(
+                "Remap prefix {prefix} is not a Zenoh key (zenoh.remap.add_prefix)"
+            ));
+        }
+        if let Some(filter) = conf.drop.iter().find(|filter| !topic::valid_filter(filter)) {
+            return Err(format!(
+                "Invalid remap drop filter {filter} (zenoh.remap.drop)"
+            ));
+        }
+        let rules = conf
+            .rules
+            .iter()
+            .map(|rule| {
+                Regex::new(&rule.pattern)
+                    .map(|regex| (regex, rule.replace.clone(), rule.to))
+                    .map_err(|err| format!("Invalid remap rule {}: {}", rule.pattern, err))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Remap {
+            strip_prefix: conf.strip_prefix.clone().unwrap_or_default(),
+            add_prefix: conf.add_prefix.clone().unwrap_or_default(),
+            drop: conf.drop.clone(),
+            rules,
+        })
+    }
+
+    fn rewrite(&self, topic: String, to: RewriteTo) -> String {
+        self.rules
+            .iter()
+            .filter(|(_, _, rule_to)| *rule_to == to)
+            .fold(topic, |topic, (regex, replace, _)| {
+                regex.replace_all(&topic, replace.as_str()).into_owned()
+            })
+    }
+
+    fn dropped(&self, topic: &str) -> bool {
+        self.drop.iter().any(|filter| topic::matches(filter, topic))
+    }
+
+    /// The Zenoh key of a topic, none if it is not sent to Zenoh
+    pub fn to_zenoh(&self, topic: &str) -> Option<String> {
+        if self.dropped(topic) {
+            return None;
+        }
+        let topic = self.rewrite(topic.to_string(), RewriteTo::Zenoh);
+        let rest = topic.strip_prefix(&self.strip_prefix)?;
+        Some(format!("{}{}", self.add_prefix, escape(rest)))
+    }
+
+    /// The topic of a Zenoh key, none if it is not taken from Zenoh
+    pub fn to_mqtt(&self, key: &str) -> Option<String> {
+        let rest = unescape(key.strip_prefix(&self.add_prefix)?)?;
+        let topic = self.rewrite(format!("{}{}", self.strip_prefix, rest), RewriteTo::Mqtt);
+        (!self.dropped(&topic)).then_some(topic)
+    }
+
+    /// The Zenoh key expression of a MQTT style filter, none if it matches nothing sent to Zenoh
+    pub fn filter_to_zenoh(&self, filter: &str) -> Option<String> {
+        let filter = self.rewrite(filter.to_string(), RewriteTo::Zenoh);
+        let levels = match filter.strip_prefix(&self.strip_prefix) {
+            Some(rest) => rest
+                .split('/')
+                .map(|level| match level {
+                    "#" => "**".to_string(),
+                    "+" => "*".to_string(),
+                    level => escape_level(level),
+                })
+                .collect::<Vec<_>>()
+                .join("/"),
+            None if topic::covers(&filter, &format!("{}#", self.strip_prefix)) => "**".to_string(),
+            None => return None,
+        };
+        Some(format!("{}{}", self.add_prefix, levels))
+    }
+}
+
+/// Whether a Zenoh key has no wildcards, empty levels, or characters `escape` would escape
+pub fn valid_key(key: &str) -> bool {
+    key.split('/')
+        .all(|level| !level.is_empty() && !level.contains(ESCAPED))
+}
+
+fn escape_level(level: &str) -> String {
+    if level.is_empty() {
+        return "%".to_string();
+    }
+    let mut escaped = String::with_capacity(level.len());
+    for c in level.chars() {
+        if ESCAPED.contains(&c) || c.is_ascii_control() {
+            escaped.push_str(&format!("%{:02X}", c as u32));
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+fn unescape_level(level: &str) -> Option<String> {
+    if level == "%" {
+        return Some(String::new());
+    }
+    let mut bytes = Vec::with_capacity(level.len());
+    let mut rest = level.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail
+                .get(..2)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+            bytes.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// A topic as a valid Zenoh key, see `remap`
+pub fn escape(topic: &str) -> String {
+    topic
+        .split('/')
+        .map(escape_level)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The topic of an escaped key, none if badly escaped
+pub fn unescape(key: &str) -> Option<String> {
+    Some(
+        key.split('/')
+            .map(unescape_level)
+            .collect::<Option<Vec<_>>>()?
+            .join("/"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RewriteRule;
+    use zenoh::key_expr::KeyExpr;
+
+    const TOPICS: &[&str] = &[
+        "TPU/GPS/Speed",
+        "TPU/Daemon/Control/data",
+        "BMS/Cell 12/Voltage",
+        "a*b/c**/*",
+        "$SYS/broker/uptime",
+        "WHEEL/#/+",
+        "what?/100%/%41",
+        "a//b",
+        "/leading",
+        "trailing/",
+        "/",
+        "",
+        "tab\there/ünïcode",
+    ];
+
+    #[test]
+    fn escapes_to_valid_keys() {
+        for topic in TOPICS {
+            let key = escape(topic);
+            assert!(KeyExpr::try_from(key.clone()).is_ok(), "{topic} as {key}");
+            assert!(!key.contains(['*', '$', '?', '#']), "{topic} as {key}");
+            assert_eq!(unescape(&key).as_deref(), Some(*topic));
+        }
+        assert_eq!(escape("a//b"), "a/%/b");
+        assert_eq!(escape("what?/100%"), "what%3F/100%25");
+        assert_eq!(unescape("bad%4"), None);
+        assert_eq!(unescape("bad%zz"), None);
+        assert_eq!(unescape("bad%+1"), None);
+    }
+
+    #[test]
+    fn round_trips_with_rules() {
+        let remap = Remap::new(&RemapConfig {
+            strip_prefix: Some("TPU/".to_string()),
+            add_prefix: Some("car/tpu/".to_string()),
+            drop: vec!["TPU/Debug/#".to_string()],
+            rules: vec![
+                RewriteRule {
+                    pattern: "^BMS/".to_string(),
+                    replace: "TPU/battery/".to_string(),
+                    to: RewriteTo::Zenoh,
+                },
+                RewriteRule {
+                    pattern: "^TPU/battery/".to_string(),
+                    replace: "BMS/".to_string(),
+                    to: RewriteTo::Mqtt,
+                },
+            ],
+        })
+        .unwrap();
+
+        for topic in TOPICS.iter().map(|topic| format!("TPU/{topic}")) {
+            let key = remap.to_zenoh(&topic).unwrap();
+            assert!(key.starts_with("car/tpu/"));
+            assert!(KeyExpr::try_from(key.clone()).is_ok(), "{topic} as {key}");
+            assert_eq!(remap.to_mqtt(&key), Some(topic));
+        }
+
+        assert_eq!(
+            remap.to_zenoh("BMS/Pack/Voltage").as_deref(),
+            Some("car/tpu/battery/Pack/Voltage")
+        );
+        assert_eq!(
+            remap.to_mqtt("car/tpu/battery/Pack/Voltage").as_deref(),
+            Some("BMS/Pack/Voltage")
+        );
+
+        // outside the prefixes, or dropped
+        assert_eq!(remap.to_zenoh("VCU/State"), None);
+        assert_eq!(remap.to_zenoh("TPU/Debug/Trace"), None);
+        assert_eq!(remap.to_mqtt("other/GPS/Speed"), None);
+        assert_eq!(remap.to_mqtt("car/tpu/Debug/Trace"), None);
+    }
+
+    #[test]
+    fn maps_filters() {
+        let remap = Remap::default();
+        assert_eq!(remap.filter_to_zenoh("TPU/#").as_deref(), Some("TPU/**"));
+        assert_eq!(remap.filter_to_zenoh("+/GPS/+").as_deref(), Some("*/GPS/*"));
+        assert_eq!(
+            remap.filter_to_zenoh("$SYS/#").as_deref(),
+            Some("%24SYS/**")
+        );
+
+        let remap = Remap::new(&RemapConfig {
+            strip_prefix: Some("TPU/".to_string()),
+            add_prefix: Some("car/".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            remap.filter_to_zenoh("TPU/+/Speed").as_deref(),
+            Some("car/*/Speed")
+        );
+        assert_eq!(remap.filter_to_zenoh("#").as_deref(), Some("car/**"));
+        assert_eq!(remap.filter_to_zenoh("+/#").as_deref(), Some("car/**"));
+        assert_eq!(remap.filter_to_zenoh("BMS/#"), None);
+    }
+
+    #[test]
+    fn rejects_bad_config() {
+        let bad = |conf: RemapConfig| Remap::new(&conf).is_err();
+        assert!(bad(RemapConfig {
+            strip_prefix: Some("TPU".to_string()),
+            ..Default::default()
+        }));
+        assert!(bad(RemapConfig {
+            add_prefix: Some("car/*/".to_string()),
+            ..Default::default()
+        }));
+        assert!(bad(RemapConfig {
+            drop: vec!["TPU/#/x".to_string()],
+            ..Default::default()
+        }));
+        assert!(bad(RemapConfig {
+            rules: vec![RewriteRule {
+                pattern: "(".to_string(),
+                replace: String::new(),
+                to: RewriteTo::Zenoh,
+            }],
+            ..Default::default()
+        }));
+    }
+}
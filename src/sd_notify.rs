@@ -0,0 +1,242 @@
+//! HELPER: systemd readiness and watchdog notifications
+//!
+//! Implements the sd_notify protocol, newline separated `KEY=VALUE` datagrams sent to the
+//! unix socket named in `$NOTIFY_SOCKET`.  Nothing is sent when not run by systemd.
+//! - `READY=1` and `STATUS=` once every module has spawned, `STATUS=` again on reload
+//! - `WATCHDOG=1` every half of `$WATCHDOG_USEC`, but only while the transport processor's
+//!   loop is alive, as seen by its `Heartbeat`.  Keep `WatchdogSec` at 2s or more.
+//! - `STOPPING=1` on shutdown
+
+use std::{
+    io,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    },
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+/// How often the transport processor beats when it has nothing else to do
+pub const HEARTBEAT_PERIOD: Duration = Duration::from_millis(500);
+
+/// A sender of notifications to systemd
+#[derive(Clone)]
+pub struct Notifier {
+    socket: Arc<UnixDatagram>,
+    addr: SocketAddr,
+}
+
+impl Notifier {
+    /// Create a notifier sending to the socket at path, `@` prefixed paths are abstract
+    pub fn new(path: &str) -> io::Result<Notifier> {
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(path)?,
+        };
+        let socket = UnixDatagram::unbound()?;
+        // never stall the daemon on a busy systemd
+        socket.set_nonblocking(true)?;
+        Ok(Notifier {
+            socket: Arc::new(socket),
+            addr,
+        })
+    }
+
+    /// Create a notifier from `$NOTIFY_SOCKET`, None if it is not set or invalid
+    pub fn from_env() -> Option<Notifier> {
+        let path = std::env::var("NOTIFY_SOCKET").ok()?;
+        match Notifier::new(&path) {
+            Ok(notifier) => Some(notifier),
+            Err(err) => {
+                warn!("Invalid NOTIFY_SOCKET {}: {}", path, err);
+                None
+            }
+        }
+    }
+
+    /// Send a notification, such as `READY=1`
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        self.socket.send_to_addr(state.as_bytes(), &self.addr)?;
+        Ok(())
+    }
+}
+
+/// The watchdog ping period from `$WATCHDOG_USEC`, None if the watchdog is off or meant for another process
+pub fn watchdog_period() -> Option<Duration> {
+    parse_watchdog(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+    )
+}
+
+fn parse_watchdog(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+    if let Some(pid) = pid
+        && pid.parse::<u32>().ok()? != std::process::id()
+    {
+        return None;
+    }
+    let usec = usec?.parse::<u64>().ok().filter(|usec| *usec > 0)?;
+    // ping twice per timeout, as systemd recommends
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// A counter bumped on every loop of the transport processor
+#[derive(Clone, Default)]
+pub struct Heartbeat(Arc<AtomicU64>);
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn count(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Ping the systemd watchdog every period, as long as the heartbeat moved since the last ping
+pub async fn watchdog(
+    cancel_token: CancellationToken,
+    notifier: Notifier,
+    heartbeat: Heartbeat,
+    period: Duration,
+) {
+    let mut ping = tokio::time::interval_at(Instant::now() + period, period);
+    let mut last_count = heartbeat.count();
+
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => {
+                debug!("Shutting down watchdog");
+                break;
+            },
+            _ = ping.tick() => {
+                let count = heartbeat.count();
+                if count == last_count {
+                    warn!("Transport processor stalled, not pinging watchdog!");
+                    continue;
+                }
+                last_count = count;
+                if let Err(err) = notifier.notify("WATCHDOG=1") {
+                    warn!("Could not ping watchdog: {}", err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// A socket standing in for systemd, in a temporary folder
+    struct FakeSystemd {
+        socket: UnixDatagram,
+        dir: TempDir,
+    }
+
+    impl FakeSystemd {
+        fn new() -> FakeSystemd {
+            let dir = tempfile::tempdir().unwrap();
+            let socket = UnixDatagram::bind(dir.path().join("notify.sock")).unwrap();
+            socket.set_nonblocking(true).unwrap();
+            FakeSystemd { socket, dir }
+        }
+
+        fn notifier(&self) -> Notifier {
+            Notifier::new(self.dir.path().join("notify.sock").to_str().unwrap()).unwrap()
+        }
+
+        /// Every datagram received so far
+        fn received(&self) -> Vec<String> {
+            let mut buf = [0u8; 256];
+            let mut msgs = Vec::new();
+            while let Ok(len) = self.socket.recv(&mut buf) {
+                msgs.push(String::from_utf8_lossy(&buf[..len]).to_string());
+            }
+            msgs
+        }
+    }
+
+    #[test]
+    fn sends_notifications() {
+        let systemd = FakeSystemd::new();
+        let notifier = systemd.notifier();
+        notifier.notify("READY=1\nSTATUS=Running GPS").unwrap();
+        notifier.notify("STOPPING=1").unwrap();
+        assert_eq!(
+            systemd.received(),
+            vec!["READY=1\nSTATUS=Running GPS", "STOPPING=1"]
+        );
+    }
+
+    #[test]
+    fn sends_to_abstract_socket() {
+        let name = format!("ody-notify-abstract-{}", std::process::id());
+        let socket =
+            UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+        Notifier::new(&format!("@{name}"))
+            .unwrap()
+            .notify("READY=1")
+            .unwrap();
+        let mut buf = [0u8; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+
+    #[test]
+    fn parses_watchdog_period() {
+        let pid = std::process::id().to_string();
+        assert_eq!(
+            parse_watchdog(Some("10000000"), None),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            parse_watchdog(Some("4000000"), Some(&pid)),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(parse_watchdog(Some("4000000"), Some("1")), None);
+        assert_eq!(parse_watchdog(Some("0"), None), None);
+        assert_eq!(parse_watchdog(Some("soon"), None), None);
+        assert_eq!(parse_watchdog(None, None), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watchdog_stops_when_heartbeat_stalls() {
+        let systemd = FakeSystemd::new();
+        let heartbeat = Heartbeat::default();
+        let token = CancellationToken::new();
+        let handle = tokio::spawn(watchdog(
+            token.clone(),
+            systemd.notifier(),
+            heartbeat.clone(),
+            Duration::from_millis(50),
+        ));
+
+        // time is paused, so sleeping only advances the clock once the watchdog is idle; beating
+        // between ticks, so it is pinged every period
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        for _ in 0..10 {
+            heartbeat.beat();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(systemd.received(), vec!["WATCHDOG=1"; 4]);
+
+        // stalled, so not pinged
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(systemd.received().is_empty());
+
+        token.cancel();
+        handle.await.unwrap();
+    }
+}
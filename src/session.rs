@@ -0,0 +1,234 @@
+//! HELPER: Event sessions, one per HV on
+//!
+//! Created by the transport processor on HV on and sent to the modules in `HVTransition::TransitionOn`.
+//! The session owns its folder, `<output_folder>/event-<TIME_MS>`, and hands out file paths by
+//! `ArtifactKind`, recording which module asked for which file.  On HV off (or shutdown while on)
+//! it is finalized with a `session.json` manifest of the event.
+//! Once the uploader sends an artifact to Scylla it leaves a `<file>.uploaded` marker next to it.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::UNIX_EPOCH,
+};
+
+use serde::Serialize;
+use tokio::sync::watch::Sender;
+use tracing::{debug, warn};
+
+use crate::HVTransition;
+
+/// The manifest written into the folder on finalize
+pub const MANIFEST_FILE: &str = "session.json";
+
+/// The suffix of the marker left next to an uploaded artifact
+const UPLOADED_SUFFIX: &str = ".uploaded";
+
+/// The files a module can write into an event folder
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArtifactKind {
+    /// Every received message, length delimited protobuf (see `logger`)
+    DataLog,
+    /// The front camera (see `visual`)
+    Video,
+    /// Cerberus serial output (see `lockdown`)
+    CerberusSerial,
+    /// Shepherd serial output (see `lockdown`)
+    ShepherdSerial,
+}
+
+impl ArtifactKind {
+    pub const ALL: [ArtifactKind; 4] = [
+        ArtifactKind::DataLog,
+        ArtifactKind::Video,
+        ArtifactKind::CerberusSerial,
+        ArtifactKind::ShepherdSerial,
+    ];
+
+    /// The file name of the artifact within the event folder
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            ArtifactKind::DataLog => "data_dump.log",
+            ArtifactKind::Video => "ner24-frontcam.mp4",
+            ArtifactKind::CerberusSerial => "cerberus-dump.cap",
+            ArtifactKind::ShepherdSerial => "shepherd-dump.cap",
+        }
+    }
+}
+
+/// A file handed out by the session
+#[derive(Debug, Clone, Serialize)]
+struct Artifact {
+    kind: ArtifactKind,
+    module: &'static str,
+    file: &'static str,
+}
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+    start_ms: u64,
+    end_ms: u64,
+    artifacts: &'a [Artifact],
+}
+
+struct SessionInner {
+    dir: PathBuf,
+    start_ms: u64,
+    artifacts: Mutex<Vec<Artifact>>,
+}
+
+/// The folder and files of one HV on event, cheap to clone
+#[derive(Clone)]
+pub struct EventSession(Arc<SessionInner>);
+
+impl EventSession {
+    /// Create the folder of an event starting at start_ms
+    pub fn create(output_folder: &Path, start_ms: u64) -> io::Result<EventSession> {
+        let dir = output_folder.join(format!("event-{start_ms}"));
+        std::fs::create_dir(&dir)?;
+        debug!("Created event folder {}", dir.display());
+        Ok(EventSession(Arc::new(SessionInner {
+            dir,
+            start_ms,
+            artifacts: Mutex::default(),
+        })))
+    }
+
+    /// The event folder
+    pub fn dir(&self) -> &Path {
+        &self.0.dir
+    }
+
+    /// The time HV enabled
+    pub fn start_ms(&self) -> u64 {
+        self.0.start_ms
+    }
+
+    /// The path module should write the artifact to, recorded in the manifest
+    pub fn artifact(&self, module: &'static str, kind: ArtifactKind) -> PathBuf {
+        let mut artifacts = self.0.artifacts.lock().unwrap();
+        if !artifacts
+            .iter()
+            .any(|artifact| artifact.kind == kind && artifact.module == module)
+        {
+            artifacts.push(Artifact {
+                kind,
+                module,
+                file: kind.file_name(),
+            });
+        }
+        self.0.dir.join(kind.file_name())
+    }
+
+    /// Write the manifest, at HV off
+    pub fn finalize(&self) -> io::Result<()> {
+        let end_ms = UNIX_EPOCH.elapsed().unwrap().as_millis() as u64;
+        let artifacts = self.0.artifacts.lock().unwrap().clone();
+        let manifest = serde_json::to_vec_pretty(&Manifest {
+            start_ms: self.0.start_ms,
+            end_ms,
+            artifacts: &artifacts,
+        })?;
+        std::fs::write(self.0.dir.join(MANIFEST_FILE), manifest)?;
+        debug!("Finalized event folder {}", self.0.dir.display());
+        Ok(())
+    }
+
+    /// Finalize, only warning on failure
+    pub fn finalize_or_warn(&self) {
+        if let Err(err) = self.finalize() {
+            warn!(
+                "Could not write manifest of {}: {}",
+                self.0.dir.display(),
+                err
+            );
+        }
+    }
+}
+
+/// Mark an artifact as uploaded to Scylla
+pub fn mark_uploaded(path: &Path) -> io::Result<()> {
+    let mut marker = path.as_os_str().to_owned();
+    marker.push(UPLOADED_SUFFIX);
+    std::fs::write(marker, [])
+}
+
+/// Whether an event folder has artifacts and every one was uploaded
+pub fn is_uploaded(dir: &Path) -> bool {
+    let mut artifacts = ArtifactKind::ALL
+        .iter()
+        .map(|kind| dir.join(kind.file_name()))
+        .filter(|file| file.exists())
+        .peekable();
+    artifacts.peek().is_some()
+        && artifacts.all(|file| {
+            let mut marker = file.into_os_string();
+            marker.push(UPLOADED_SUFFIX);
+            Path::new(&marker).exists()
+        })
+}
+
+/// Start a session and send HV on, None if its folder could not be created
+pub fn hv_on(
+    output_folder: &Path,
+    time_ms: u64,
+    hv_stat_send: &Sender<HVTransition>,
+) -> Option<EventSession> {
+    match EventSession::create(output_folder, time_ms) {
+        Ok(session) => {
+            hv_stat_send
+                .send(HVTransition::TransitionOn(session.clone()))
+                .expect("HV Stat Channel Closed");
+            Some(session)
+        }
+        Err(err) => {
+            warn!("Could not create folder for data, staying HV off! {}", err);
+            None
+        }
+    }
+}
+
+/// Send HV off and finalize the session
+pub fn hv_off(session: EventSession, hv_stat_send: &Sender<HVTransition>) {
+    hv_stat_send
+        .send(HVTransition::TransitionOff)
+        .expect("HV Stat Channel Closed");
+    session.finalize_or_warn();
+}
+
+impl std::fmt::Debug for EventSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventSession")
+            .field("dir", &self.0.dir)
+            .field("start_ms", &self.0.start_ms)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uploaded_once_every_artifact_is() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join(ArtifactKind::DataLog.file_name());
+        let video = dir.path().join(ArtifactKind::Video.file_name());
+
+        // empty, or only unknown files
+        assert!(!is_uploaded(dir.path()));
+        std::fs::write(dir.path().join("notes.txt"), "").unwrap();
+        assert!(!is_uploaded(dir.path()));
+
+        // partially uploaded
+        std::fs::write(&log, "").unwrap();
+        std::fs::write(&video, "").unwrap();
+        mark_uploaded(&log).unwrap();
+        assert!(!is_uploaded(dir.path()));
+
+        mark_uploaded(&video).unwrap();
+        assert!(is_uploaded(dir.path()));
+    }
+}
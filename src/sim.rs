@@ -0,0 +1,344 @@
+//! HELPER: Simulated hardware for bench development
+//!
+//! With `--sim` every module talks to a simulated backend, so the daemon runs end to end on a
+//! laptop with only an MQTT broker (use `--mock` as well to force HV on).
+//! - The external tools (`cli_app`, `canbusload`, `linphonecsh`, `usbip`, `stty`, `minicom`, `ffmpeg`)
+//!   are replaced by scripts with canned output, see `command`.  Calls with side effects are logged
+//!   to `commands.log`
+//! - `/sys/class/leds` and `/sys/class/net` are fake trees, see `sys_path`.  Net counters keep growing
+//! - The DAQ serial port is a stream of synthetic lines, see `daq_stream`
+//! - gpsd is a fake server driving laps around a circle, see `gpsd_address`
+//! - CAN frames are logged instead of sent
+//!
+//! Everything lives in a fresh temp directory (the sim root), removed on shutdown.
+//!
+//! Simulating a new piece of hardware:
+//! 1. Add a script to `SCRIPTS` for a tool, or a fake backend here
+//! 2. Swap to it in the module with `command`, `sys_path`, or `is_enabled`
+
+use std::{
+    f64::consts::TAU,
+    io,
+    net::SocketAddr,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use socketcan::CanFrame;
+use tokio::{
+    io::{AsyncWriteExt, DuplexStream},
+    net::TcpListener,
+    process::Command,
+    sync::mpsc::Receiver,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, info, trace, warn};
+
+use crate::{color::LED_BANK_WRITE_LISTINGS, config::DaemonConfig};
+
+/// Set when running in simulation mode
+static SIM: OnceLock<Sim> = OnceLock::new();
+
+struct Sim {
+    root: PathBuf,
+    gpsd: SocketAddr,
+}
+
+/// The scripts standing in for external tools, `$SIM_LOG` is replaced with the log path
+const SCRIPTS: [(&str, &str); 7] = [
+    (
+        "cli_app",
+        r#"case "$*" in
+    "show signal") printf 'vif_id : 0\nrssi : -%s\n' $(( $(date +%s) % 20 + 50 )) ;;
+    "show ap 0") printf 'AP 0\nmac : 00:00:00:00:00:00\ntx rate 7800 (MCS 4), rx rate 6500 (MCS 3)\n' ;;
+esac
+"#,
+    ),
+    (
+        "canbusload",
+        r#"# stop along with the daemon
+while kill -0 $PPID 2>/dev/null; do
+    load=$(( $(date +%s) % 30 + 10 ))
+    echo " $1  $(( load * 40 ))  $(( load * 5120 ))  $(( load * 2400 ))  $load%"
+    sleep 1
+done
+"#,
+    ),
+    ("linphonecsh", "echo \"linphonecsh $*\" >> $SIM_LOG\n"),
+    ("usbip", "echo \"usbip $*\" >> $SIM_LOG\n"),
+    ("stty", "echo \"stty $*\" >> $SIM_LOG\n"),
+    (
+        "minicom",
+        r#"echo "minicom $*" >> $SIM_LOG
+while [ $# -gt 0 ]; do
+    [ "$1" = "-C" ] && out="$2"
+    shift
+done
+while kill -0 $PPID 2>/dev/null; do
+    echo "[$(date)] sim serial line" >> "$out"
+    sleep 1
+done
+"#,
+    ),
+    (
+        "ffmpeg",
+        r#"echo "ffmpeg $*" >> $SIM_LOG
+# the tee output is last, as [options]<file>|[options]<stream>
+for last in "$@"; do :; done
+out=$(echo "$last" | sed 's/^\[[^]]*\]\([^|]*\)|.*$/\1/')
+trap 'echo "sim video end" >> "$out"; exit 0' TERM
+echo "sim video start" > "$out"
+while kill -0 $PPID 2>/dev/null; do
+    sleep 1
+done
+"#,
+    ),
+];
+
+/// Whether the daemon is running in simulation mode
+pub fn is_enabled() -> bool {
+    SIM.get().is_some()
+}
+
+/// A command for an external tool, swapped for its canned script in simulation mode
+pub fn command(program: &str) -> Command {
+    match SIM.get() {
+        Some(sim) => Command::new(sim.root.join("bin").join(program)),
+        None => Command::new(program),
+    }
+}
+
+/// A path under /sys, moved into the fake tree in simulation mode
+pub fn sys_path(path: &str) -> PathBuf {
+    match SIM.get() {
+        Some(sim) => sim.root.join(path.trim_start_matches('/')),
+        None => PathBuf::from(path),
+    }
+}
+
+/// The address of the fake gpsd, None if not simulating
+pub fn gpsd_address() -> Option<SocketAddr> {
+    SIM.get().map(|sim| sim.gpsd)
+}
+
+/// Give the modules which need a device a simulated one, if the config does not name one
+pub fn fill_config(conf: &mut DaemonConfig) {
+    conf.video.uri.get_or_insert_with(|| "sim".to_string());
+    conf.daq.device.get_or_insert_with(|| "sim".to_string());
+    if conf.lockdown.usbs.is_empty() {
+        conf.lockdown.usbs = vec!["sim-1".to_string()];
+    }
+    if conf.net.ifaces.is_empty() {
+        conf.net.ifaces = vec!["sim0".to_string()];
+    }
+}
+
+/// Build the sim root and start the fake backends, must be called before any module runs.
+/// The sim root is removed when the returned guard is dropped
+pub async fn start(
+    cancel_token: CancellationToken,
+    task_tracker: &TaskTracker,
+    conf: &DaemonConfig,
+) -> io::Result<SimGuard> {
+    let root = std::env::temp_dir().join(format!("odysseus-sim-{}", std::process::id()));
+    if root.exists() {
+        std::fs::remove_dir_all(&root)?;
+    }
+
+    let bin = root.join("bin");
+    std::fs::create_dir_all(&bin)?;
+    let log = root.join("commands.log");
+    for (name, script) in SCRIPTS {
+        let path = bin.join(name);
+        std::fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\n{}",
+                script.replace("$SIM_LOG", &format!("\"{}\"", log.display()))
+            ),
+        )?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+    }
+
+    for (dir, _) in LED_BANK_WRITE_LISTINGS {
+        std::fs::create_dir_all(root.join(dir.trim_start_matches('/')))?;
+    }
+    let net_dirs = conf
+        .net
+        .ifaces
+        .iter()
+        .map(|iface| root.join(format!("sys/class/net/{iface}/statistics")))
+        .collect::<Vec<_>>();
+    for dir in &net_dirs {
+        std::fs::create_dir_all(dir)?;
+        for stat in ["tx_bytes", "rx_bytes", "rx_errors", "tx_errors"] {
+            std::fs::write(dir.join(stat), "0\n")?;
+        }
+    }
+
+    let gpsd = TcpListener::bind("127.0.0.1:0").await?;
+    let gpsd_addr = gpsd.local_addr()?;
+
+    info!("Simulating hardware in {}", root.display());
+    if SIM
+        .set(Sim {
+            root,
+            gpsd: gpsd_addr,
+        })
+        .is_err()
+    {
+        return Err(io::Error::other("Simulation already started"));
+    }
+
+    task_tracker.spawn(fake_gpsd(cancel_token.clone(), gpsd));
+    task_tracker.spawn(fake_net_stats(cancel_token, net_dirs));
+    Ok(SimGuard)
+}
+
+/// Removes the sim root when dropped, see `start`
+pub struct SimGuard;
+
+impl Drop for SimGuard {
+    fn drop(&mut self) {
+        if let Some(sim) = SIM.get()
+            && let Err(err) = std::fs::remove_dir_all(&sim.root)
+        {
+            warn!("Could not remove sim root {}: {}", sim.root.display(), err);
+        }
+    }
+}
+
+/// A stream of DAQ serial lines, `$<time>,<10 bit ADC values...>` every 10ms
+pub fn daq_stream(cancel_token: CancellationToken) -> DuplexStream {
+    let (reader, mut writer) = tokio::io::duplex(4096);
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Duration::from_millis(10));
+        let mut cnt: u64 = 0;
+        loop {
+            tokio::select! {
+                _ = cancel_token.cancelled() => break,
+                _ = tick.tick() => {
+                    cnt += 1;
+                    let phase = cnt as f64 / 100.0;
+                    // shocks bounce around mid travel, the wheel sweeps slowly
+                    let shock = |offset: f64| (2048.0 + 600.0 * (phase * 3.0 + offset).sin()) as u64;
+                    let wheel = (2048.0 + 1500.0 * (phase / 4.0).sin()) as u64;
+                    let line = format!(
+                        "${},{},{},{},0,0,{},{},0,0\n",
+                        cnt,
+                        shock(0.0),
+                        shock(1.0),
+                        shock(2.0),
+                        wheel,
+                        shock(3.0)
+                    );
+                    // the DAQ reader was dropped
+                    if writer.write_all(line.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    reader
+}
+
+/// Log CAN frames instead of putting them on a bus
+pub async fn can_logger(cancel_token: CancellationToken, mut can_recv: Receiver<CanFrame>) {
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => {
+                debug!("Shutting down sim CAN logger");
+                break;
+            },
+            Some(frame) = can_recv.recv() => {
+                trace!("Sim CAN frame: {:?}", frame);
+            }
+        }
+    }
+}
+
+/// Serve gpsd clients with a fix driving a lap every minute
+async fn fake_gpsd(cancel_token: CancellationToken, listener: TcpListener) {
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            res = listener.accept() => match res {
+                Ok((stream, addr)) => {
+                    debug!("Sim gpsd client {}", addr);
+                    tokio::spawn(gpsd_client(cancel_token.clone(), stream));
+                }
+                Err(err) => warn!("Sim gpsd could not accept: {}", err),
+            }
+        }
+    }
+}
+
+async fn gpsd_client(cancel_token: CancellationToken, mut stream: tokio::net::TcpStream) {
+    const CENTER: (f64, f64) = (42.3398, -71.0892);
+    const RADIUS_DEG: f64 = 0.001;
+
+    let version = "{\"class\":\"VERSION\",\"release\":\"sim\",\"rev\":\"sim\",\"proto_major\":3,\"proto_minor\":15}\n";
+    if stream.write_all(version.as_bytes()).await.is_err() {
+        return;
+    }
+
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            _ = tick.tick() => {
+                let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+                let angle = (secs % 60.0) / 60.0 * TAU;
+                let tpv = format!(
+                    "{{\"class\":\"TPV\",\"device\":\"/dev/sim\",\"mode\":3,\"lat\":{:.6},\"lon\":{:.6},\"altHAE\":10.0,\"speed\":{:.2}}}\n",
+                    CENTER.0 + RADIUS_DEG * angle.sin(),
+                    CENTER.1 + RADIUS_DEG * angle.cos(),
+                    // a 700m lap each minute, in knots
+                    700.0 / 60.0 * 1.944,
+                );
+                if stream.write_all(tpv.as_bytes()).await.is_err() {
+                    debug!("Sim gpsd client left");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Keep the fake interface counters growing
+async fn fake_net_stats(cancel_token: CancellationToken, dirs: Vec<PathBuf>) {
+    let mut tick = tokio::time::interval(Duration::from_millis(100));
+    let mut cnt: u64 = 0;
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            _ = tick.tick() => {
+                cnt += 1;
+                for dir in &dirs {
+                    if let Err(err) = write_net_stats(dir, cnt).await {
+                        warn!("Could not write sim net stats: {}", err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn write_net_stats(dir: &Path, cnt: u64) -> io::Result<()> {
+    // about 1.2 MB/s up and 300 kB/s down, wobbling
+    let wobble = (cnt % 20) * 500;
+    write_stat(dir, "tx_bytes", cnt * 120_000 + wobble).await?;
+    write_stat(dir, "rx_bytes", cnt * 30_000 + wobble).await?;
+    write_stat(dir, "rx_errors", cnt / 600).await?;
+    write_stat(dir, "tx_errors", cnt / 900).await
+}
+
+/// Replace a counter file whole, so the net module never reads it half written
+async fn write_stat(dir: &Path, stat: &str, val: u64) -> io::Result<()> {
+    let tmp = dir.join(format!(".{stat}"));
+    tokio::fs::write(&tmp, format!("{val}\n")).await?;
+    tokio::fs::rename(tmp, dir.join(stat)).await
+}
@@ -0,0 +1,423 @@
+//! HELPER: On-disk spool of outgoing messages while the transport is disconnected
+//!
+//! With `[spool] enable`, the publisher (see `transport`) writes messages to the spool instead of
+//! publishing them while the transport is down, or when a publish fails:
+//! - Messages are appended to segment files in `<output_folder>/spool`, as length-delimited
+//!   `playback_data::PlaybackData` like the logger writes
+//! - Past `max_mb` on disk, the oldest segment is deleted
+//! - Once connected again, `replayer` publishes them at up to `replay_rate` messages a second,
+//!   flagged `backfilled` in `serverdata::ServerData`, deleting each segment once replayed
+//!
+//! Segments left over from a previous run are replayed as well.  The disk IO runs off the runtime,
+//! through `blocking`.
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use protobuf::{CodedInputStream, Message, SpecialFields};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::{
+    PublishableMessage, config::SpoolConfig, metrics::METRICS, playback_data::PlaybackData,
+    serverdata, transport::Transport,
+};
+
+/// The folder under the output folder holding the spool
+pub const SPOOL_FOLDER: &str = "spool";
+
+const SEGMENT_EXT: &str = "spool";
+
+/// How often the replayer publishes a batch, and checks for something to replay
+const REPLAY_TICK: Duration = Duration::from_millis(100);
+
+/// The segment being written
+struct Segment {
+    seq: u64,
+    file: BufWriter<File>,
+    size: u64,
+}
+
+struct Inner {
+    /// Segments ready to replay, oldest first, with their size
+    sealed: VecDeque<(u64, u64)>,
+    writing: Option<Segment>,
+    next_seq: u64,
+    /// Bytes of every segment, except one being replayed
+    bytes: u64,
+}
+
+/// Outgoing messages kept on disk, see `spool`
+pub struct Spool {
+    folder: PathBuf,
+    max_bytes: u64,
+    replay_rate: u32,
+    inner: Mutex<Inner>,
+}
+
+impl Spool {
+    /// Open the spool in the folder, picking up segments left by a previous run
+    pub fn open(folder: &Path, conf: &SpoolConfig) -> io::Result<Spool> {
+        fs::create_dir_all(folder)?;
+        let mut sealed = Vec::new();
+        for entry in fs::read_dir(folder)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == SEGMENT_EXT)
+                && let Some(seq) = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                sealed.push((seq, fs::metadata(&path)?.len()));
+            }
+        }
+        sealed.sort_unstable();
+        let bytes = sealed.iter().map(|(_, size)| size).sum();
+        if !sealed.is_empty() {
+            info!("Found {} spooled bytes to replay", bytes);
+        }
+        METRICS.set_spool_bytes(bytes);
+
+        Ok(Spool {
+            folder: folder.to_path_buf(),
+            max_bytes: conf.max_mb.saturating_mul(1_000_000),
+            replay_rate: conf.replay_rate,
+            inner: Mutex::new(Inner {
+                next_seq: sealed.last().map_or(0, |(seq, _)| seq + 1),
+                sealed: sealed.into(),
+                writing: None,
+                bytes,
+            }),
+        })
+    }
+
+    fn path(&self, seq: u64) -> PathBuf {
+        self.folder.join(format!("{seq:010}.{SEGMENT_EXT}"))
+    }
+
+    /// Segments are sealed at this size, so the oldest data goes first when full
+    fn segment_bytes(&self) -> u64 {
+        (self.max_bytes / 8).max(64 * 1024)
+    }
+
+    /// Append a message to the spool
+    pub fn push(&self, msg: &PublishableMessage) -> io::Result<()> {
+        let bytes = PlaybackData {
+            topic: msg.topic.clone(),
+            unit: msg.unit.clone(),
+            time_us: msg.time,
+            values: msg.data.clone(),
+            backfilled: false,
+            special_fields: SpecialFields::new(),
+        }
+        .write_length_delimited_to_bytes()?;
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.writing.is_none() {
+            let seq = inner.next_seq;
+            inner.next_seq += 1;
+            inner.writing = Some(Segment {
+                seq,
+                file: BufWriter::new(File::create(self.path(seq))?),
+                size: 0,
+            });
+        }
+        let segment = inner.writing.as_mut().unwrap();
+        segment.file.write_all(&bytes)?;
+        segment.size += bytes.len() as u64;
+        let full = segment.size >= self.segment_bytes();
+        inner.bytes += bytes.len() as u64;
+        if full {
+            self.seal_locked(&mut inner);
+        }
+
+        while inner.bytes > self.max_bytes
+            && let Some((seq, size)) = inner.sealed.pop_front()
+        {
+            warn!("Spool full, deleting its oldest {} bytes", size);
+            inner.bytes -= size;
+            if let Err(err) = fs::remove_file(self.path(seq)) {
+                warn!("Could not delete spool segment {}: {}", seq, err);
+            }
+        }
+        METRICS.spooled();
+        METRICS.set_spool_bytes(inner.bytes);
+        Ok(())
+    }
+
+    /// Finish the segment being written, so it can be replayed
+    pub fn seal(&self) {
+        self.seal_locked(&mut self.inner.lock().unwrap());
+    }
+
+    fn seal_locked(&self, inner: &mut Inner) {
+        if let Some(mut segment) = inner.writing.take() {
+            if let Err(err) = segment.file.flush() {
+                warn!("Could not flush spool segment {}: {}", segment.seq, err);
+            }
+            inner.sealed.push_back((segment.seq, segment.size));
+        }
+    }
+
+    /// Take the oldest sealed segment to replay, with its messages
+    fn take_oldest(&self) -> Option<(u64, Vec<PlaybackData>)> {
+        let (seq, size) = {
+            let mut inner = self.inner.lock().unwrap();
+            let (seq, size) = inner.sealed.pop_front()?;
+            inner.bytes -= size;
+            METRICS.set_spool_bytes(inner.bytes);
+            (seq, size)
+        };
+        let bytes = match fs::read(self.path(seq)) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                warn!("Could not read spool segment {}: {}", seq, err);
+                return Some((seq, Vec::new()));
+            }
+        };
+        let mut msgs = Vec::new();
+        let mut input = CodedInputStream::from_bytes(&bytes);
+        while let Ok(false) = input.eof() {
+            match input.read_message::<PlaybackData>() {
+                Ok(msg) => msgs.push(msg),
+                Err(err) => {
+                    // such as a message cut off by a crash
+                    warn!(
+                        "Spool segment {} of {} bytes is corrupt: {}",
+                        seq, size, err
+                    );
+                    break;
+                }
+            }
+        }
+        Some((seq, msgs))
+    }
+
+    /// Delete a replayed segment
+    fn remove(&self, seq: u64) {
+        if let Err(err) = fs::remove_file(self.path(seq)) {
+            warn!("Could not delete spool segment {}: {}", seq, err);
+        }
+    }
+
+    /// Rewrite a segment with the messages left to replay, to replay them first next time
+    fn put_back(&self, seq: u64, msgs: &[PlaybackData]) -> io::Result<()> {
+        let tmp = self.path(seq).with_extension("tmp");
+        let mut file = BufWriter::new(File::create(&tmp)?);
+        let mut size = 0;
+        for msg in msgs {
+            let bytes = msg.write_length_delimited_to_bytes()?;
+            file.write_all(&bytes)?;
+            size += bytes.len() as u64;
+        }
+        file.flush()?;
+        fs::rename(&tmp, self.path(seq))?;
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.sealed.push_front((seq, size));
+        inner.bytes += size;
+        METRICS.set_spool_bytes(inner.bytes);
+        Ok(())
+    }
+}
+
+/// Run a call doing disk IO on the spool off the runtime
+pub async fn blocking<T: Send + 'static>(
+    spool: &Arc<Spool>,
+    f: impl FnOnce(&Spool) -> T + Send + 'static,
+) -> io::Result<T> {
+    let spool = spool.clone();
+    tokio::task::spawn_blocking(move || f(&spool))
+        .await
+        .map_err(io::Error::other)
+}
+
+/// Serialize a spooled message to `serverdata::ServerData`, flagged as backfilled
+fn backfill(msg: &PlaybackData) -> protobuf::Result<Vec<u8>> {
+    let mut payload = serverdata::ServerData::new();
+    payload.unit = msg.unit.clone();
+    payload.values = msg.values.clone();
+    payload.time_us = msg.time_us;
+    payload.backfilled = true;
+    payload.write_to_bytes()
+}
+
+/// Replay the spool while connected, at the rate of the spool
+pub async fn replayer(
+    cancel_token: CancellationToken,
+    spool: Arc<Spool>,
+    transport: Arc<dyn Transport>,
+    connected: watch::Receiver<bool>,
+) {
+    let per_tick = (spool.replay_rate as u64 * REPLAY_TICK.as_millis() as u64 / 1000).max(1);
+    let mut tick = tokio::time::interval(REPLAY_TICK);
+    // the segment being replayed, its messages, and the next to publish
+    let mut replaying: Option<(u64, Vec<PlaybackData>, usize)> = None;
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            _ = tick.tick() => {}
+        }
+        if !*connected.borrow() {
+            if let Some((seq, msgs, next)) = replaying.take() {
+                debug!("Disconnected, pausing spool replay");
+                put_back_or_warn(&spool, seq, msgs, next).await;
+            }
+            continue;
+        }
+        if replaying.is_none() {
+            let oldest = blocking(&spool, |spool| {
+                spool.seal();
+                spool.take_oldest()
+            })
+            .await;
+            let (seq, msgs) = match oldest {
+                Ok(Some(oldest)) => oldest,
+                Ok(None) => continue,
+                Err(err) => {
+                    warn!("Could not read the spool: {}", err);
+                    continue;
+                }
+            };
+            debug!("Replaying {} spooled messages", msgs.len());
+            replaying = Some((seq, msgs, 0));
+        }
+
+        let (seq, msgs, next) = replaying.as_mut().unwrap();
+        let end = (*next + per_tick as usize).min(msgs.len());
+        while *next < end {
+            let msg = &msgs[*next];
+            let Ok(bytes) = backfill(msg) else {
+                warn!("Failed to serialize spooled message!");
+                *next += 1;
+                continue;
+            };
+            let published = tokio::select! {
+                // kept below, as it was not replayed
+                _ = cancel_token.cancelled() => break,
+                published = transport.publish(&msg.topic, bytes, None) => published,
+            };
+            if let Err(err) = published {
+                debug!("Could not replay {}: {}", msg.topic, err);
+                break;
+            }
+            METRICS.published(&msg.topic);
+            METRICS.replayed();
+            *next += 1;
+        }
+        if *next == msgs.len() {
+            let seq = *seq;
+            replaying = None;
+            if let Err(err) = blocking(&spool, move |spool| spool.remove(seq)).await {
+                warn!("Could not delete spool segment {}: {}", seq, err);
+            }
+        }
+    }
+
+    if let Some((seq, msgs, next)) = replaying.take() {
+        put_back_or_warn(&spool, seq, msgs, next).await;
+    }
+    if let Err(err) = blocking(&spool, Spool::seal).await {
+        warn!("Could not seal the spool: {}", err);
+    }
+    debug!("Shutting down spool replayer");
+}
+
+/// Keep the messages of a segment from `next` on, to replay them first next time
+async fn put_back_or_warn(spool: &Arc<Spool>, seq: u64, msgs: Vec<PlaybackData>, next: usize) {
+    let left = msgs.len() - next;
+    let kept = blocking(spool, move |spool| {
+        if next == msgs.len() {
+            spool.remove(seq);
+            return Ok(());
+        }
+        spool.put_back(seq, &msgs[next..])
+    })
+    .await;
+    if let Err(err) = kept.and_then(|kept| kept) {
+        warn!("Could not keep {} spooled messages: {}", left, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(time: u64) -> PublishableMessage {
+        PublishableMessage {
+            topic: "TPU/GPS/Speed".to_string(),
+            data: vec![time as f32; 16],
+            unit: "m/s".to_string(),
+            time,
+            options: None,
+        }
+    }
+
+    fn times(msgs: &[PlaybackData]) -> Vec<u64> {
+        msgs.iter().map(|msg| msg.time_us).collect()
+    }
+
+    #[test]
+    fn keeps_replays_and_drops_oldest() {
+        let dir = tempfile::tempdir().unwrap();
+        let folder = dir.path().join("spool");
+        let conf = SpoolConfig {
+            enable: true,
+            max_mb: 1,
+            replay_rate: 1,
+        };
+
+        let spool = Spool::open(&folder, &conf).unwrap();
+        for time in 0..3 {
+            spool.push(&msg(time)).unwrap();
+        }
+        spool.seal();
+        spool.push(&msg(3)).unwrap();
+        drop(spool);
+
+        // left over segments are picked up, oldest first
+        let spool = Spool::open(&folder, &conf).unwrap();
+        let (seq, msgs) = spool.take_oldest().unwrap();
+        assert_eq!(times(&msgs), vec![0, 1, 2]);
+        assert!(msgs.iter().all(|msg| !msg.backfilled));
+
+        // an interrupted replay resumes where it stopped
+        spool.put_back(seq, &msgs[1..]).unwrap();
+        let (seq, msgs) = spool.take_oldest().unwrap();
+        assert_eq!(times(&msgs), vec![1, 2]);
+        spool.remove(seq);
+        let (seq, msgs) = spool.take_oldest().unwrap();
+        assert_eq!(times(&msgs), vec![3]);
+        spool.remove(seq);
+        assert!(spool.take_oldest().is_none());
+
+        // past max_mb the oldest segments go
+        let mut time = 0;
+        while spool.inner.lock().unwrap().bytes < 900_000 {
+            spool.push(&msg(time)).unwrap();
+            time += 1;
+        }
+        // as much again, which does not fit
+        let end = time * 2;
+        while time < end {
+            spool.push(&msg(time)).unwrap();
+            time += 1;
+        }
+        spool.seal();
+        let mut kept = Vec::new();
+        while let Some((seq, msgs)) = spool.take_oldest() {
+            kept.extend(times(&msgs));
+            spool.remove(seq);
+        }
+        assert!(kept.len() < time as usize);
+        assert_eq!(kept.last(), Some(&(time - 1)));
+        assert!(kept.windows(2).all(|pair| pair[0] + 1 == pair[1]));
+    }
+}
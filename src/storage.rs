@@ -0,0 +1,313 @@
+//! Keeps the output folder from filling the disk, by deleting old event folders.
+//!
+//! Beta
+//!
+//! Every check:
+//!  - Deletes uploaded events older than `max_age_hours`, and with `max_age_unuploaded` the others too
+//!  - While free space is under `min_free_mb`, deletes the oldest uploaded events, then the oldest others
+//!  - Publishes the free space, the space used by events, and the events deleted
+//!
+//! The active session is never touched.  An event counts as uploaded once it has artifacts and all were uploaded, see `session`.
+//! An event which cannot be deleted is counted as a module error and skipped.
+//!
+//! Requires:
+//!  - Sending MQTT messages
+//!  - HV state, for the active session
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use futures_util::future::BoxFuture;
+use sysinfo::Disks;
+use tokio::sync::watch::Receiver;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+use crate::{
+    HVTransition, PublishableMessage,
+    config::StorageConfig,
+    health::ModuleHealth,
+    module::{Module, ModuleContext, ModuleInputs, ModuleResult},
+    publish::Publisher,
+    session::is_uploaded,
+};
+
+/// The storage module, see `storage_manager`
+pub struct StorageModule {
+    base_name: String,
+    output_folder: PathBuf,
+    conf: StorageConfig,
+}
+
+impl StorageModule {
+    pub fn new(base_name: String, output_folder: PathBuf, conf: StorageConfig) -> Self {
+        Self {
+            base_name,
+            output_folder,
+            conf,
+        }
+    }
+}
+
+impl Module for StorageModule {
+    fn name(&self) -> &'static str {
+        "Storage"
+    }
+
+    fn inputs(&self) -> ModuleInputs {
+        ModuleInputs {
+            publish: true,
+            hv: true,
+            ..Default::default()
+        }
+    }
+
+    fn run(&self, mut ctx: ModuleContext) -> BoxFuture<'static, ModuleResult> {
+        let base_name = self.base_name.clone();
+        let output_folder = self.output_folder.clone();
+        let conf = self.conf.clone();
+        Box::pin(async move {
+            storage_manager(
+                ctx.cancel_token.clone(),
+                base_name,
+                output_folder,
+                conf,
+                ctx.publisher()?,
+                ctx.hv()?,
+                ctx.health,
+            )
+            .await;
+            Ok(())
+        })
+    }
+}
+
+/// An event folder on disk
+#[derive(Debug)]
+struct Event {
+    dir: PathBuf,
+    start_ms: u64,
+    uploaded: bool,
+}
+
+/// Check the output folder every `check_secs`, deleting events and publishing usage
+pub async fn storage_manager(
+    cancel_token: CancellationToken,
+    base_name: String,
+    output_folder: PathBuf,
+    conf: StorageConfig,
+    mqtt_sender_tx: Publisher,
+    hv_stat_recv: Receiver<HVTransition>,
+    health: ModuleHealth,
+) {
+    let free_topic = format!("{base_name}/Storage/Free");
+    let used_topic = format!("{base_name}/Storage/Used");
+    let deleted_topic = format!("{base_name}/Storage/Deleted");
+
+    let mut check = tokio::time::interval(Duration::from_secs(conf.check_secs.max(1)));
+
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => {
+                debug!("Shutting down storage manager");
+                break;
+            },
+            _ = check.tick() => {
+                let deleted = match enforce(&output_folder, &hv_stat_recv, &conf, &health).await {
+                    Ok(deleted) => deleted,
+                    Err(err) => {
+                        warn!("Could not clean up {}: {}", output_folder.display(), err);
+                        health.error();
+                        continue;
+                    }
+                };
+
+                let time = UNIX_EPOCH.elapsed().unwrap().as_micros() as u64;
+                let mut msgs = vec![PublishableMessage {
+                    topic: deleted_topic.clone(),
+                    data: vec![deleted as f32],
+                    unit: "events".to_string(),
+                    time,
+                    options: None,
+                }];
+                match blocking(free_space, &output_folder).await.ok().flatten() {
+                    Some(free) => msgs.push(PublishableMessage {
+                        topic: free_topic.clone(),
+                        data: vec![free as f32 / 1e6],
+                        unit: "MB".to_string(),
+                        time,
+                        options: None,
+                    }),
+                    None => warn!("Could not find the disk of {}", output_folder.display()),
+                }
+                match blocking(dir_size, &output_folder).await.and_then(|size| size) {
+                    Ok(used) => msgs.push(PublishableMessage {
+                        topic: used_topic.clone(),
+                        data: vec![used as f32 / 1e6],
+                        unit: "MB".to_string(),
+                        time,
+                        options: None,
+                    }),
+                    Err(err) => warn!("Could not size {}: {}", output_folder.display(), err),
+                }
+
+                for msg in msgs {
+                    if let Err(err) = mqtt_sender_tx.send(msg).await {
+                        warn!("Could not send storage message: {}", err);
+                        health.error();
+                    }
+                }
+                health.work();
+            }
+        }
+    }
+}
+
+/// Delete events past the max age, then until the free space floor is met, returning how many were deleted
+async fn enforce(
+    output_folder: &Path,
+    hv_stat_recv: &Receiver<HVTransition>,
+    conf: &StorageConfig,
+    health: &ModuleHealth,
+) -> io::Result<usize> {
+    let mut events = blocking(list_events, output_folder).await??;
+    let mut deleted = 0;
+
+    if let Some(max_age_hours) = conf.max_age_hours {
+        let now_ms = UNIX_EPOCH.elapsed().unwrap().as_millis() as u64;
+        let max_age_ms = max_age_hours.saturating_mul(3_600_000);
+        let (old, young) = events.into_iter().partition(|event| {
+            now_ms.saturating_sub(event.start_ms) > max_age_ms
+                && (event.uploaded || conf.max_age_unuploaded)
+        });
+        events = young;
+        for event in old {
+            info!("Deleting {}, past the max age", event.dir.display());
+            if remove_event(&event, hv_stat_recv, health).await {
+                deleted += 1;
+            }
+        }
+    }
+
+    // uploaded first, oldest first within each
+    events.sort_by_key(|event| (!event.uploaded, event.start_ms));
+    let floor = conf.min_free_mb.saturating_mul(1_000_000);
+    for event in events {
+        match blocking(free_space, output_folder).await.ok().flatten() {
+            Some(free) if free < floor => {}
+            _ => break,
+        }
+        if is_active(&event, hv_stat_recv) {
+            continue;
+        }
+        if event.uploaded {
+            info!("Deleting {} for free space", event.dir.display());
+        } else {
+            warn!(
+                "Deleting {} for free space, it was never uploaded!",
+                event.dir.display()
+            );
+        }
+        if remove_event(&event, hv_stat_recv, health).await {
+            deleted += 1;
+        }
+    }
+
+    Ok(deleted)
+}
+
+/// Whether an event is the session of the current HV on, checked again before each delete as HV
+/// can turn on during a pass
+fn is_active(event: &Event, hv_stat_recv: &Receiver<HVTransition>) -> bool {
+    match &*hv_stat_recv.borrow() {
+        HVTransition::TransitionOn(session) => session.dir() == event.dir,
+        HVTransition::TransitionOff => false,
+    }
+}
+
+/// Delete an event folder unless active, returning whether it was, else warning and counting an error
+async fn remove_event(
+    event: &Event,
+    hv_stat_recv: &Receiver<HVTransition>,
+    health: &ModuleHealth,
+) -> bool {
+    if is_active(event, hv_stat_recv) {
+        debug!(
+            "Not deleting {}, it is the active session",
+            event.dir.display()
+        );
+        return false;
+    }
+    match tokio::fs::remove_dir_all(&event.dir).await {
+        Ok(()) => true,
+        Err(err) => {
+            warn!("Could not delete {}: {}", event.dir.display(), err);
+            health.error();
+            false
+        }
+    }
+}
+
+/// Run a blocking filesystem call on a path off the runtime
+async fn blocking<T: Send + 'static>(f: fn(&Path) -> T, path: &Path) -> io::Result<T> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || f(&path))
+        .await
+        .map_err(io::Error::other)
+}
+
+/// The event folders in the output folder
+fn list_events(output_folder: &Path) -> io::Result<Vec<Event>> {
+    let mut events = Vec::new();
+    for entry in std::fs::read_dir(output_folder)? {
+        let entry = entry?;
+        let dir = entry.path();
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let Some(start_ms) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix("event-"))
+            .and_then(|ms| ms.parse::<u64>().ok())
+        else {
+            continue;
+        };
+        events.push(Event {
+            uploaded: is_uploaded(&dir),
+            dir,
+            start_ms,
+        });
+    }
+    Ok(events)
+}
+
+/// The free space in bytes of the disk holding path
+fn free_space(path: &Path) -> Option<u64> {
+    let path = path.canonicalize().ok()?;
+    let disks = Disks::new_with_refreshed_list();
+    disks
+        .list()
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| disk.available_space())
+}
+
+/// The size in bytes of every file under path
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else if file_type.is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
@@ -0,0 +1,155 @@
+//! HELPER: Supervises modules, restarting them after they return or panic
+//!
+//! Each module is run in its own task.  When it stops, its `RestartPolicy` decides
+//! whether it is started again, after an exponential backoff.  A module restarting more
+//! than `max_restarts` times inside `restart_window_secs` is given up on.
+//! Every restart is published to `<base_node>/Daemon/<Module>/Restart`.
+//! The supervisor also drives the module's health state, see `health`.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use tokio::time::Instant;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, info, warn};
+
+use crate::{
+    PublishableMessage,
+    config::{RestartConfig, RestartPolicy},
+    health::{ModuleHealth, ModuleState, health_reporter},
+    module::{Module, ModuleChannels},
+};
+
+/// Run a module until the token is cancelled, restarting it per its restart config.
+/// Its tasks are spawned on the tracker
+pub async fn supervise(
+    task_tracker: TaskTracker,
+    cancel_token: CancellationToken,
+    module: Box<dyn Module>,
+    restart: RestartConfig,
+    channels: ModuleChannels,
+    base_node: String,
+    health: ModuleHealth,
+) {
+    // stopped only once the module is down, so its final state is reported
+    let report_token = CancellationToken::new();
+    task_tracker.spawn(health_reporter(
+        report_token.clone(),
+        module.name(),
+        health.clone(),
+        base_node.clone(),
+        channels.publisher(),
+    ));
+
+    let restart_topic = format!("{}/Daemon/{}/Restart", base_node, module.name());
+    let window = Duration::from_secs(restart.restart_window_secs);
+    let mut backoff = Duration::from_millis(restart.backoff_initial_ms);
+    let mut restarts: VecDeque<Instant> = VecDeque::new();
+    let mut restart_cnt: u32 = 0;
+
+    loop {
+        let run_token = cancel_token.child_token();
+        let started = Instant::now();
+        health.set_state(ModuleState::Starting);
+        let res = task_tracker
+            .spawn(module.run(channels.context(module.as_ref(), run_token.clone(), health.clone())))
+            .await;
+        // make sure anything the module spawned goes down with it
+        run_token.cancel();
+
+        if cancel_token.is_cancelled() {
+            debug!("Module {} stopped", module.name());
+            health.set_state(ModuleState::Stopped);
+            break;
+        }
+
+        let failed = match res {
+            Ok(Ok(())) => {
+                warn!("Module {} returned unexpectedly", module.name());
+                false
+            }
+            Ok(Err(err)) => {
+                warn!("Module {} exited with error: {}", module.name(), err);
+                true
+            }
+            Err(err) => {
+                warn!("Module {} panicked: {}", module.name(), err);
+                true
+            }
+        };
+
+        if failed {
+            health.error();
+        }
+
+        let should_restart = match restart.policy {
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure => failed,
+            RestartPolicy::Never => false,
+        };
+        if !should_restart {
+            info!("Not restarting module {}", module.name());
+            health.set_state(if failed {
+                ModuleState::Failed
+            } else {
+                ModuleState::Stopped
+            });
+            break;
+        }
+
+        let now = Instant::now();
+        while restarts
+            .front()
+            .is_some_and(|time| now.duration_since(*time) > window)
+        {
+            restarts.pop_front();
+        }
+        if restarts.len() >= restart.max_restarts as usize {
+            warn!(
+                "Module {} restarted {} times in {:?}, giving up!",
+                module.name(),
+                restarts.len(),
+                window
+            );
+            health.set_state(ModuleState::Failed);
+            break;
+        }
+        restarts.push_back(now);
+
+        // a module which ran for a while is considered healthy again
+        if started.elapsed() > Duration::from_millis(restart.backoff_max_ms) {
+            backoff = Duration::from_millis(restart.backoff_initial_ms);
+        }
+
+        restart_cnt += 1;
+        if let Err(err) = channels
+            .publisher()
+            .send(PublishableMessage {
+                topic: restart_topic.clone(),
+                data: vec![restart_cnt as f32, failed as u8 as f32],
+                unit: "count".to_string(),
+                time: UNIX_EPOCH.elapsed().unwrap().as_micros() as u64,
+                options: None,
+            })
+            .await
+        {
+            warn!("Could not publish restart of {}: {}", module.name(), err);
+        }
+
+        info!("Restarting module {} in {:?}", module.name(), backoff);
+        health.set_state(ModuleState::Degraded);
+        tokio::select! {
+            _ = cancel_token.cancelled() => {
+                health.set_state(ModuleState::Stopped);
+                break;
+            },
+            _ = tokio::time::sleep(backoff) => {}
+        }
+        backoff = (backoff * 2).min(Duration::from_millis(restart.backoff_max_ms));
+    }
+
+    module.shutdown().await;
+    report_token.cancel();
+}
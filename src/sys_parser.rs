@@ -8,20 +8,56 @@
 
 use std::time::UNIX_EPOCH;
 
+use futures_util::future::BoxFuture;
 use regex::Regex;
 use rumqttc::v5::mqttbytes::v5::Publish;
-use tokio::sync::mpsc::Receiver;
-use tokio::sync::mpsc::Sender;
+use tokio::sync::broadcast::Receiver;
 use tokio_util::sync::CancellationToken;
 use tracing::warn;
 
-use crate::PublishableMessage;
+use crate::{
+    PublishableMessage,
+    health::ModuleHealth,
+    module::{Module, ModuleContext, ModuleInputs, ModuleResult},
+    publish::Publisher,
+};
+
+/// The SYS translator module, see `sys_parser`
+pub struct SysModule;
+
+impl Module for SysModule {
+    fn name(&self) -> &'static str {
+        "Sys"
+    }
+
+    fn inputs(&self) -> ModuleInputs {
+        ModuleInputs {
+            publish: true,
+            sys: true,
+            ..Default::default()
+        }
+    }
+
+    fn run(&self, mut ctx: ModuleContext) -> BoxFuture<'static, ModuleResult> {
+        Box::pin(async move {
+            sys_parser(
+                ctx.cancel_token.clone(),
+                ctx.sys()?,
+                ctx.publisher()?,
+                ctx.health,
+            )
+            .await;
+            Ok(())
+        })
+    }
+}
 
 /// Parse the SYS module.  Requires all SYS messages.
 pub async fn sys_parser(
     cancel_token: CancellationToken,
     mut mqtt_recv_rx: Receiver<Publish>,
-    mqtt_send_tx: Sender<PublishableMessage>,
+    mqtt_send_tx: Publisher,
+    health: ModuleHealth,
 ) {
     let num_regex = Regex::new(r"\d+(\.\d+)?").unwrap();
 
@@ -30,7 +66,7 @@ pub async fn sys_parser(
             _ = cancel_token.cancelled() => {
                 break;
             },
-            Some(msg) = mqtt_recv_rx.recv() => {
+            Ok(msg) = mqtt_recv_rx.recv() => {
                 let Ok(topic) = String::from_utf8(msg.topic.to_vec()) else {
                             warn!("Could not parse topic, topic: {:?}", msg.topic);
                             continue;
@@ -53,16 +89,18 @@ pub async fn sys_parser(
 
                 if send_data.is_empty() {
                     warn!("Could not parse {}", topic);
+                    health.error();
                     continue;
                 }
 
                 let topic_sendable = topic.replace("$SYS", "SYS_tpu");
 
-                let sendable = PublishableMessage { topic: topic_sendable, data: send_data, unit: "".to_string(), time: UNIX_EPOCH.elapsed().unwrap().as_micros() as u64 };
+                let sendable = PublishableMessage { topic: topic_sendable, data: send_data, unit: "".to_string(), time: UNIX_EPOCH.elapsed().unwrap().as_micros() as u64, options: None };
                 if let Err(err) = mqtt_send_tx.send(sendable).await {
                     warn!("Could not send SYS message out: {}", err);
                     continue;
                 }
+                health.work();
             }
         }
     }
@@ -0,0 +1,123 @@
+//! HELPER: MQTT style topic filters, used by the config rules on topics and the subscriptions
+
+use std::collections::BTreeSet;
+
+/// Whether a topic matches a MQTT style filter, `+` matching one level and `#` every level after.
+/// As in MQTT, a wildcard first level does not match `$` topics like `$SYS`
+pub fn matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        if level == "#" {
+            return true;
+        }
+        match topic_levels.next() {
+            Some(topic_level) if level == "+" || level == topic_level => {}
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+/// Whether a MQTT style filter is valid, with `#` only as the last level and wildcards alone in their level
+pub fn valid_filter(filter: &str) -> bool {
+    let levels: Vec<&str> = filter.split('/').collect();
+    !filter.is_empty()
+        && levels.iter().enumerate().all(|(idx, level)| match *level {
+            "#" => idx == levels.len() - 1,
+            "+" => true,
+            level => !level.contains(['#', '+']),
+        })
+}
+
+/// Whether every topic matched by `other` is matched by `filter`
+pub fn covers(filter: &str, other: &str) -> bool {
+    if other.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut other_levels = other.split('/');
+    for level in filter.split('/') {
+        if level == "#" {
+            return true;
+        }
+        match other_levels.next() {
+            Some("#") => return false,
+            Some(other_level) if level == "+" || level == other_level => {}
+            _ => return false,
+        }
+    }
+    other_levels.next().is_none()
+}
+
+/// The filters without those covered by another, to subscribe to each topic only once
+pub fn reduce<'a>(filters: impl IntoIterator<Item = &'a str>) -> BTreeSet<String> {
+    let filters: BTreeSet<&str> = filters.into_iter().collect();
+    filters
+        .iter()
+        .filter(|filter| {
+            !filters
+                .iter()
+                .any(|other| other != *filter && covers(other, filter))
+        })
+        .map(|filter| filter.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(matches("TPU/GPS/Speed", "TPU/GPS/Speed"));
+        assert!(!matches("TPU/GPS/Speed", "TPU/GPS"));
+        assert!(!matches("TPU/GPS", "TPU/GPS/Speed"));
+        assert!(matches("TPU/+/Speed", "TPU/GPS/Speed"));
+        assert!(!matches("TPU/+", "TPU/GPS/Speed"));
+        assert!(matches("TPU/#", "TPU/GPS/Speed"));
+        assert!(matches("TPU/#", "TPU"));
+        assert!(matches("#", "TPU/GPS/Speed"));
+        assert!(matches("+/+/", "TPU/GPS/"));
+        assert!(!matches("#", "$SYS/broker/uptime"));
+        assert!(!matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(matches("$SYS/#", "$SYS/broker/uptime"));
+    }
+
+    #[test]
+    fn covers_and_reduces_filters() {
+        assert!(covers("#", "TPU/GPS/+"));
+        assert!(covers("TPU/#", "TPU/GPS/#"));
+        assert!(covers("TPU/+/Speed", "TPU/GPS/Speed"));
+        assert!(covers("TPU/+/+", "TPU/+/Speed"));
+        assert!(!covers("TPU/+/Speed", "TPU/+/+"));
+        assert!(!covers("TPU/+", "TPU/#"));
+        assert!(!covers("TPU/GPS", "TPU/GPS/Speed"));
+        assert!(!covers("#", "$SYS/#"));
+
+        assert_eq!(
+            reduce(["BMS/Shutdown/State", "BMS/#", "$SYS/#", "#", "WHEEL/+/Mute"]),
+            BTreeSet::from(["#".to_string(), "$SYS/#".to_string()])
+        );
+        assert_eq!(
+            reduce([
+                "BMS/Shutdown/State",
+                "BMS/#",
+                "WHEEL/+/Mute",
+                "WHEEL/Buttons/Mute"
+            ]),
+            BTreeSet::from(["BMS/#".to_string(), "WHEEL/+/Mute".to_string()])
+        );
+    }
+
+    #[test]
+    fn validates_filters() {
+        assert!(valid_filter("TPU/#"));
+        assert!(valid_filter("+/GPS/+"));
+        assert!(!valid_filter(""));
+        assert!(!valid_filter("TPU/#/Speed"));
+        assert!(!valid_filter("TPU/GPS#"));
+        assert!(!valid_filter("TPU/G+/Speed"));
+    }
+}
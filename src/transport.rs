@@ -0,0 +1,816 @@
+//! HELPER: The transport agnostic side of receiving and sending messages
+//!
+//! A `Transport` (MQTT in `mqtt_handler`, Zenoh in `zenoh_handler`, or `Loopback`) only moves
+//! serialized payloads.  The `Dispatcher` does everything else for any transport:
+//! - publishes the publish queue, serialized to `serverdata::ServerData`,
+//!   or spools it to disk while disconnected (see `spool`)
+//! - runs the HV state machine, creating an event session on HV on (see `session`)
+//! - the mute button state
+//! - the Scylla upload triggers
+//! - forwarding every received message to the modules
+//! - subscribing to the topics of the running modules (see `module`) and its own
+
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    path::PathBuf,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::future::BoxFuture;
+use protobuf::{Message, SpecialFields};
+use rumqttc::v5::mqttbytes::v5::Publish;
+use tokio::sync::{Mutex, broadcast, mpsc, watch};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    HV_EN_TOPIC, HVTransition, MUTE_EN_TOPIC, PublishableMessage, SEND_LOGGER_DATA,
+    SEND_SERIAL_DATA, SEND_VIDEO_DATA,
+    config::PublishOptions,
+    metrics::METRICS,
+    module::TransportChannels,
+    playback_data,
+    publish::PublishQueue,
+    sd_notify::{HEARTBEAT_PERIOD, Heartbeat},
+    serverdata,
+    session::{EventSession, hv_off, hv_on},
+    spool::{self, Spool, replayer},
+    topic,
+    uploader::upload_files,
+};
+
+pub type TransportResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+/// The MQTT user property or Zenoh attachment key tagging a bridged message with its origin
+pub const ORIGIN_KEY: &str = "odysseus-origin";
+
+/// Something received by a transport
+#[derive(Debug)]
+pub enum Incoming {
+    /// A message, its payload still serialized
+    Message {
+        topic: String,
+        payload: Vec<u8>,
+        /// The origin tag of a bridged message, see `dual`
+        origin: Option<String>,
+    },
+    /// A MQTT $SYS message, passed on as is
+    Sys(Publish),
+}
+
+/// A way of receiving and sending messages
+pub trait Transport: Send + Sync {
+    /// Connect, must be called once before anything else
+    fn connect(&self) -> BoxFuture<'_, TransportResult>;
+
+    /// Subscribe to a MQTT style filter, with `+` and `#` wildcards
+    fn subscribe<'a>(&'a self, filter: &'a str) -> BoxFuture<'a, TransportResult>;
+
+    /// Unsubscribe from a filter given to `subscribe`
+    fn unsubscribe<'a>(&'a self, filter: &'a str) -> BoxFuture<'a, TransportResult>;
+
+    /// Publish a serialized payload, with options overriding those of the topic
+    fn publish<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: Vec<u8>,
+        options: Option<PublishOptions>,
+    ) -> BoxFuture<'a, TransportResult>;
+
+    /// Publish a message bridged from another transport, tagged with its origin so no bridge
+    /// forwards it again. Transports which cannot tag publish it as is
+    fn forward<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: Vec<u8>,
+        _origin: &'a str,
+    ) -> BoxFuture<'a, TransportResult> {
+        self.publish(topic, payload, None)
+    }
+
+    /// Wait for the next message, None once the transport is closed.
+    /// Must be cancel safe, as it is raced against other work
+    fn recv(&self) -> BoxFuture<'_, Option<Incoming>>;
+
+    /// Whether a broker, router, or peer is connected
+    fn connected(&self) -> BoxFuture<'_, bool>;
+}
+
+/// The chief processor of incoming data for any transport, see `transport`
+pub struct Dispatcher {
+    cancel_token: CancellationToken,
+    /// Taken by the publisher when run
+    mqtt_sender_rx: Option<PublishQueue>,
+    hv_stat_send: watch::Sender<HVTransition>,
+    mute_stat_send: watch::Sender<bool>,
+    connected_send: watch::Sender<bool>,
+    mqtt_recv_tx: broadcast::Sender<playback_data::PlaybackData>,
+    mqtt_sys_tx: broadcast::Sender<Publish>,
+    /// Where live messages matching the filter also go, see `ModuleRegistry::control`
+    control: Option<(String, mpsc::Sender<playback_data::PlaybackData>)>,
+    subscriptions: watch::Receiver<BTreeSet<String>>,
+    /// Sent HV on at start and never changed by HV messages
+    augment_hv_on: bool,
+    scylla_url: Option<String>,
+    /// Where event sessions are created on HV on, see `session`
+    output_folder: PathBuf,
+    /// Beaten every loop, see `sd_notify`
+    heartbeat: Heartbeat,
+    /// Holds messages while disconnected, see `spool`
+    spool: Option<Arc<Spool>>,
+}
+
+impl Dispatcher {
+    pub fn new(
+        cancel_token: CancellationToken,
+        channels: TransportChannels,
+        augment_hv_on: bool,
+        scylla_url: Option<String>,
+        output_folder: PathBuf,
+        heartbeat: Heartbeat,
+        spool: Option<Arc<Spool>>,
+    ) -> Dispatcher {
+        Dispatcher {
+            cancel_token,
+            mqtt_sender_rx: Some(channels.mqtt_sender_rx),
+            hv_stat_send: channels.hv_stat_send,
+            mute_stat_send: channels.mute_stat_send,
+            connected_send: channels.connected_send,
+            mqtt_recv_tx: channels.mqtt_recv_tx,
+            mqtt_sys_tx: channels.mqtt_sys_tx,
+            control: channels.control,
+            subscriptions: channels.subscriptions,
+            augment_hv_on,
+            scylla_url,
+            output_folder,
+            heartbeat,
+            spool,
+        }
+    }
+
+    /// Handle a received message
+    fn handle_recv(&self, topic: &str, payload: &[u8], event: &mut Option<EventSession>) {
+        METRICS.received(topic);
+        let Ok(res) = serverdata::ServerData::parse_from_bytes(payload) else {
+            warn!("Recieved unparsable message on {}", topic);
+            return;
+        };
+
+        let val = *res.values.first().unwrap_or(&-1f32) as u8;
+        match topic {
+            // late, so must not act on the state now
+            _ if res.backfilled => {}
+            HV_EN_TOPIC if !self.augment_hv_on => {
+                // ensure only triggering upon change from previous loop
+                if val == 1 && event.is_none() {
+                    debug!("Transitioning states to HV on, creating folder!");
+                    *event = hv_on(&self.output_folder, res.time_us / 1000, &self.hv_stat_send);
+                } else if val == 0
+                    && let Some(ended) = event.take()
+                {
+                    debug!("Transitioning states to HV off");
+                    hv_off(ended, &self.hv_stat_send);
+                } else if val != 0 && val != 1 {
+                    warn!("Received bad HV message!");
+                }
+            }
+            MUTE_EN_TOPIC => {
+                // mute button messages should be single shot
+                if val == 1 {
+                    self.mute_stat_send.send_replace(true);
+                } else if val == 0 {
+                    self.mute_stat_send.send_replace(false);
+                } else {
+                    warn!("Received bad mute message!");
+                }
+            }
+            SEND_LOGGER_DATA | SEND_SERIAL_DATA | SEND_VIDEO_DATA => {
+                if event.is_none()
+                    && let Some(url) = &self.scylla_url
+                {
+                    info!("Sending {} data, {}", topic, val);
+                    upload_files(
+                        &self.output_folder,
+                        url,
+                        topic == SEND_LOGGER_DATA,
+                        topic == SEND_VIDEO_DATA,
+                        topic == SEND_SERIAL_DATA,
+                    );
+                }
+            }
+            _ => {}
+        }
+        let data = playback_data::PlaybackData {
+            topic: topic.to_string(),
+            values: res.values,
+            unit: res.unit,
+            time_us: res.time_us,
+            backfilled: res.backfilled,
+            special_fields: SpecialFields::new(),
+        };
+        if let Some((filter, control_tx)) = &self.control
+            && !data.backfilled
+            && topic::matches(filter, topic)
+            && let Err(err) = control_tx.try_send(data.clone())
+        {
+            warn!("Could not pass on control message {}: {}", topic, err);
+        }
+        // to the data logger and others, if running
+        if self.mqtt_recv_tx.receiver_count() > 0
+            && let Err(err) = self.mqtt_recv_tx.send(data)
+        {
+            warn!("Error sending message received! {}", err);
+        }
+    }
+
+    /// The filters to subscribe to, those of the modules and the topics handled here
+    fn filters(&mut self) -> BTreeSet<String> {
+        let modules = self.subscriptions.borrow_and_update().clone();
+        topic::reduce(modules.iter().map(String::as_str).chain([
+            HV_EN_TOPIC,
+            MUTE_EN_TOPIC,
+            SEND_LOGGER_DATA,
+            SEND_SERIAL_DATA,
+            SEND_VIDEO_DATA,
+        ]))
+    }
+
+    /// Subscribe, then receive and send until cancelled
+    pub async fn run(mut self, transport: Arc<dyn Transport>) {
+        let mut subscribed = BTreeSet::new();
+        // polled alongside `recv`, as a transport may need receiving to make room for requests
+        let mut resubscribing: Option<BoxFuture<'_, BTreeSet<String>>> = Some(Box::pin(
+            resubscribe(transport.as_ref(), BTreeSet::new(), self.filters()),
+        ));
+
+        // if augment HV on, send as such, otherwise start default off
+        let mut event = if self.augment_hv_on {
+            let time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64;
+            warn!("HV status permanently set on!!");
+            hv_on(&self.output_folder, time, &self.hv_stat_send)
+        } else {
+            None
+        };
+
+        // in its own task, so a slow publish never holds up receiving, stopped and awaited on return
+        let tasks = TaskTracker::new();
+        let tasks_token = self.cancel_token.child_token();
+        if let Some(queue) = self.mqtt_sender_rx.take() {
+            info!("Spawning publisher!");
+            tasks.spawn(pub_handle(
+                tasks_token.clone(),
+                queue,
+                transport.clone(),
+                self.spool.clone(),
+                self.connected_send.subscribe(),
+            ));
+        }
+        if let Some(spool) = &self.spool {
+            info!("Spawning spool replayer!");
+            tasks.spawn(replayer(
+                tasks_token.clone(),
+                spool.clone(),
+                transport.clone(),
+                self.connected_send.subscribe(),
+            ));
+        }
+
+        let mut heartbeat_tick = tokio::time::interval(HEARTBEAT_PERIOD);
+        let mut subscriptions_open = true;
+        loop {
+            self.heartbeat.beat();
+            tokio::select! {
+                _ = self.cancel_token.cancelled() => {
+                    debug!("Shutting down transport dispatcher!");
+                    if let Some(event) = event.take() {
+                        event.finalize_or_warn();
+                    }
+                    break;
+                },
+                _ = heartbeat_tick.tick() => {
+                    self.connected_send.send_replace(transport.connected().await);
+                },
+                // the next change is picked up once the last one is done
+                changed = self.subscriptions.changed(), if subscriptions_open && resubscribing.is_none() => match changed {
+                    Ok(()) => {
+                        resubscribing = Some(Box::pin(resubscribe(
+                            transport.as_ref(),
+                            std::mem::take(&mut subscribed),
+                            self.filters(),
+                        )));
+                    }
+                    Err(_) => subscriptions_open = false,
+                },
+                done = async { resubscribing.as_mut().unwrap().await }, if resubscribing.is_some() => {
+                    subscribed = done;
+                    resubscribing = None;
+                },
+                incoming = transport.recv() => match incoming {
+                    Some(Incoming::Message { topic, payload, .. }) => {
+                        self.handle_recv(&topic, &payload, &mut event);
+                    }
+                    Some(Incoming::Sys(msg)) => {
+                        if self.mqtt_sys_tx.receiver_count() > 0
+                            && let Err(err) = self.mqtt_sys_tx.send(msg)
+                        {
+                            warn!("Could not send to SYS processor: {}", err);
+                        }
+                    }
+                    None => {
+                        warn!("Transport closed!");
+                        break;
+                    }
+                },
+            }
+        }
+        tasks_token.cancel();
+        tasks.close();
+        tasks.wait().await;
+    }
+}
+
+/// Subscribe to the filters not yet subscribed, and unsubscribe from those no longer needed.
+/// Returns the filters now subscribed
+async fn resubscribe(
+    transport: &dyn Transport,
+    subscribed: BTreeSet<String>,
+    filters: BTreeSet<String>,
+) -> BTreeSet<String> {
+    // subscribe first, so nothing covered by both is missed in between
+    for filter in filters.difference(&subscribed) {
+        debug!("Subscribing to {}", filter);
+        if let Err(err) = transport.subscribe(filter).await {
+            warn!("Could not subscribe to {}: {}", filter, err);
+        }
+    }
+    for filter in subscribed.difference(&filters) {
+        debug!("Unsubscribing from {}", filter);
+        if let Err(err) = transport.unsubscribe(filter).await {
+            warn!("Could not unsubscribe from {}: {}", filter, err);
+        }
+    }
+    filters
+}
+
+/// Serialize and publish everything in the publish queue, spooling it while disconnected
+async fn pub_handle(
+    cancel_token: CancellationToken,
+    mut queue: PublishQueue,
+    transport: Arc<dyn Transport>,
+    spool: Option<Arc<Spool>>,
+    connected: watch::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => {
+                debug!("Shutting down publisher!");
+                break;
+            },
+            sendable = queue.recv() => {
+                if let Some(spool) = &spool
+                    && !*connected.borrow()
+                {
+                    trace!("Spooling {:?}", sendable);
+                    spool_or_warn(spool, sendable).await;
+                    continue;
+                }
+                trace!("Sending {:?}", sendable);
+                let Ok(bytes) = encode(sendable.clone()) else {
+                    warn!("Failed to serialize protobuf message!");
+                    continue;
+                };
+                // a transport backed up with no connection can take forever
+                let published = tokio::select! {
+                    _ = cancel_token.cancelled() => break,
+                    published = transport.publish(&sendable.topic, bytes, sendable.options) => published,
+                };
+                match published {
+                    Ok(()) => METRICS.published(&sendable.topic),
+                    Err(err) => {
+                        warn!("Failed to send message: {}", err);
+                        if let Some(spool) = &spool {
+                            spool_or_warn(spool, sendable).await;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if let Some(spool) = &spool
+        && let Err(err) = spool::blocking(spool, Spool::seal).await
+    {
+        warn!("Could not seal the spool: {}", err);
+    }
+}
+
+async fn spool_or_warn(spool: &Arc<Spool>, msg: PublishableMessage) {
+    let spooled = spool::blocking(spool, move |spool| spool.push(&msg)).await;
+    if let Err(err) = spooled.and_then(|spooled| spooled) {
+        warn!("Could not spool message: {}", err);
+    }
+}
+
+/// Serialize a message to `serverdata::ServerData`
+pub fn encode(msg: PublishableMessage) -> protobuf::Result<Vec<u8>> {
+    let mut payload = serverdata::ServerData::new();
+    payload.unit = msg.unit;
+    payload.values = msg.data;
+    payload.time_us = msg.time;
+    payload.write_to_bytes()
+}
+
+/// A transport delivering everything published straight back to itself, ignoring subscriptions.
+/// For running the daemon without a broker, such as in tests
+pub struct Loopback {
+    send: mpsc::UnboundedSender<Incoming>,
+    recv: Mutex<mpsc::UnboundedReceiver<Incoming>>,
+}
+
+impl Default for Loopback {
+    fn default() -> Self {
+        let (send, recv) = mpsc::unbounded_channel();
+        Loopback {
+            send,
+            recv: Mutex::new(recv),
+        }
+    }
+}
+
+impl Loopback {
+    /// Deliver a message as if it was received from elsewhere
+    pub fn inject(&self, msg: PublishableMessage) -> TransportResult {
+        let topic = msg.topic.clone();
+        let payload = encode(msg)?;
+        self.send.send(Incoming::Message {
+            topic,
+            payload,
+            origin: None,
+        })?;
+        Ok(())
+    }
+}
+
+impl Transport for Loopback {
+    fn connect(&self) -> BoxFuture<'_, TransportResult> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn subscribe<'a>(&'a self, _filter: &'a str) -> BoxFuture<'a, TransportResult> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn unsubscribe<'a>(&'a self, _filter: &'a str) -> BoxFuture<'a, TransportResult> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn publish<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: Vec<u8>,
+        _options: Option<PublishOptions>,
+    ) -> BoxFuture<'a, TransportResult> {
+        Box::pin(async move {
+            self.send.send(Incoming::Message {
+                topic: topic.to_string(),
+                payload,
+                origin: None,
+            })?;
+            Ok(())
+        })
+    }
+
+    fn forward<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: Vec<u8>,
+        origin: &'a str,
+    ) -> BoxFuture<'a, TransportResult> {
+        Box::pin(async move {
+            self.send.send(Incoming::Message {
+                topic: topic.to_string(),
+                payload,
+                origin: Some(origin.to_string()),
+            })?;
+            Ok(())
+        })
+    }
+
+    fn recv(&self) -> BoxFuture<'_, Option<Incoming>> {
+        Box::pin(async { self.recv.lock().await.recv().await })
+    }
+
+    fn connected(&self) -> BoxFuture<'_, bool> {
+        Box::pin(async { true })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        config::DaemonConfig,
+        module::{ModuleChannels, ModuleRegistry},
+        session::MANIFEST_FILE,
+    };
+
+    /// A dispatcher running on a loopback transport, in a temporary output folder
+    struct LoopbackDaemon {
+        transport: Arc<Loopback>,
+        channels: ModuleChannels,
+        token: CancellationToken,
+        output_folder: TempDir,
+    }
+
+    impl LoopbackDaemon {
+        fn new() -> LoopbackDaemon {
+            let output_folder = tempfile::tempdir().unwrap();
+
+            let (channels, transport_channels, _) =
+                ModuleRegistry::from_config(&DaemonConfig::default()).channels();
+            let token = CancellationToken::new();
+            let transport = Arc::new(Loopback::default());
+            tokio::spawn(
+                Dispatcher::new(
+                    token.clone(),
+                    transport_channels,
+                    false,
+                    None,
+                    output_folder.path().to_path_buf(),
+                    Heartbeat::default(),
+                    None,
+                )
+                .run(transport.clone()),
+            );
+            LoopbackDaemon {
+                transport,
+                channels,
+                token,
+                output_folder,
+            }
+        }
+
+        fn inject(&self, topic: &str, val: f32, time: u64) {
+            self.transport
+                .inject(PublishableMessage {
+                    topic: topic.to_string(),
+                    data: vec![val],
+                    unit: String::new(),
+                    time,
+                    options: None,
+                })
+                .unwrap();
+        }
+    }
+
+    impl Drop for LoopbackDaemon {
+        fn drop(&mut self) {
+            self.token.cancel();
+        }
+    }
+
+    /// A request queued to a `Backlogged` transport
+    enum Request {
+        Publish(Incoming),
+        Subscribe(String),
+        Unsubscribe(String),
+    }
+
+    /// A transport like the MQTT client: requests wait in a small queue only drained by `recv`,
+    /// and only while `draining`
+    struct Backlogged {
+        requests: mpsc::Sender<Request>,
+        queue: Mutex<mpsc::Receiver<Request>>,
+        draining: watch::Sender<bool>,
+        subscribed: std::sync::Mutex<BTreeSet<String>>,
+    }
+
+    impl Backlogged {
+        fn new(capacity: usize) -> Backlogged {
+            let (requests, queue) = mpsc::channel(capacity);
+            Backlogged {
+                requests,
+                queue: Mutex::new(queue),
+                draining: watch::Sender::new(true),
+                subscribed: std::sync::Mutex::new(BTreeSet::new()),
+            }
+        }
+
+        fn subscribed(&self, filter: &str) -> bool {
+            self.subscribed.lock().unwrap().contains(filter)
+        }
+    }
+
+    impl Transport for Backlogged {
+        fn connect(&self) -> BoxFuture<'_, TransportResult> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn subscribe<'a>(&'a self, filter: &'a str) -> BoxFuture<'a, TransportResult> {
+            Box::pin(async move {
+                self.requests
+                    .send(Request::Subscribe(filter.to_string()))
+                    .await
+                    .map_err(|_| "closed")?;
+                Ok(())
+            })
+        }
+
+        fn unsubscribe<'a>(&'a self, filter: &'a str) -> BoxFuture<'a, TransportResult> {
+            Box::pin(async move {
+                self.requests
+                    .send(Request::Unsubscribe(filter.to_string()))
+                    .await
+                    .map_err(|_| "closed")?;
+                Ok(())
+            })
+        }
+
+        fn publish<'a>(
+            &'a self,
+            topic: &'a str,
+            payload: Vec<u8>,
+            _options: Option<PublishOptions>,
+        ) -> BoxFuture<'a, TransportResult> {
+            Box::pin(async move {
+                self.requests
+                    .send(Request::Publish(Incoming::Message {
+                        topic: topic.to_string(),
+                        payload,
+                        origin: None,
+                    }))
+                    .await
+                    .map_err(|_| "closed")?;
+                Ok(())
+            })
+        }
+
+        fn recv(&self) -> BoxFuture<'_, Option<Incoming>> {
+            Box::pin(async {
+                let mut draining = self.draining.subscribe();
+                let mut queue = self.queue.lock().await;
+                loop {
+                    draining.wait_for(|draining| *draining).await.ok()?;
+                    let request = tokio::select! {
+                        biased;
+                        _ = draining.wait_for(|draining| !*draining) => continue,
+                        request = queue.recv() => request?,
+                    };
+                    match request {
+                        Request::Publish(msg) => return Some(msg),
+                        Request::Subscribe(filter) => {
+                            self.subscribed.lock().unwrap().insert(filter);
+                        }
+                        Request::Unsubscribe(filter) => {
+                            self.subscribed.lock().unwrap().remove(&filter);
+                        }
+                    }
+                }
+            })
+        }
+
+        fn connected(&self) -> BoxFuture<'_, bool> {
+            Box::pin(async { true })
+        }
+    }
+
+    /// Wait up to a second for a condition
+    async fn eventually(condition: impl Fn() -> bool) -> bool {
+        for _ in 0..100 {
+            if condition() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn subscribes_with_full_request_queue() {
+        let registry = ModuleRegistry::from_config(&DaemonConfig::default());
+        let (_channels, mut transport_channels, _) = registry.channels();
+        let (subscriptions, subscriptions_rx) = watch::channel(BTreeSet::new());
+        transport_channels.subscriptions = subscriptions_rx;
+        let token = CancellationToken::new();
+        let transport = Arc::new(Backlogged::new(4));
+        let output_folder = tempfile::tempdir().unwrap();
+        tokio::spawn(
+            Dispatcher::new(
+                token.clone(),
+                transport_channels,
+                false,
+                None,
+                output_folder.path().to_path_buf(),
+                Heartbeat::default(),
+                None,
+            )
+            .run(transport.clone()),
+        );
+        assert!(eventually(|| transport.subscribed(HV_EN_TOPIC)).await);
+
+        // fill the queue while nothing drains it, then change subscriptions
+        transport.draining.send_replace(false);
+        while transport
+            .requests
+            .try_send(Request::Publish(Incoming::Message {
+                topic: "TPU/Filler".to_string(),
+                payload: Vec::new(),
+                origin: None,
+            }))
+            .is_ok()
+        {}
+        subscriptions.send_replace(BTreeSet::from(["BMS/#".to_string()]));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // the dispatcher must keep receiving for the subscribe to get through
+        transport.draining.send_replace(true);
+        assert!(eventually(|| transport.subscribed("BMS/#")).await);
+        token.cancel();
+    }
+
+    #[tokio::test]
+    async fn hv_creates_and_ends_session() {
+        let daemon = LoopbackDaemon::new();
+        let mut hv = daemon.channels.hv();
+
+        daemon.inject(HV_EN_TOPIC, 1.0, 1_000_000);
+        tokio::time::timeout(Duration::from_secs(1), hv.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        let dir = match &*hv.borrow_and_update() {
+            HVTransition::TransitionOn(session) => session.dir().to_path_buf(),
+            HVTransition::TransitionOff => panic!("HV not on"),
+        };
+        assert_eq!(dir, daemon.output_folder.path().join("event-1000"));
+
+        // repeated HV on is ignored
+        daemon.inject(HV_EN_TOPIC, 1.0, 2_000_000);
+        daemon.inject(HV_EN_TOPIC, 0.0, 3_000_000);
+        tokio::time::timeout(Duration::from_secs(1), hv.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            *hv.borrow_and_update(),
+            HVTransition::TransitionOff
+        ));
+        assert!(dir.join(MANIFEST_FILE).exists());
+        assert!(!daemon.output_folder.path().join("event-2000").exists());
+    }
+
+    #[tokio::test]
+    async fn mute_follows_button() {
+        let daemon = LoopbackDaemon::new();
+        let mut mute = daemon.channels.mute();
+
+        daemon.inject(MUTE_EN_TOPIC, 1.0, 0);
+        tokio::time::timeout(Duration::from_secs(1), mute.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(*mute.borrow_and_update());
+
+        daemon.inject(MUTE_EN_TOPIC, 0.0, 0);
+        tokio::time::timeout(Duration::from_secs(1), mute.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!*mute.borrow_and_update());
+    }
+
+    #[tokio::test]
+    async fn published_messages_are_received() {
+        let daemon = LoopbackDaemon::new();
+        let mut received = daemon.channels.received();
+
+        daemon
+            .channels
+            .publisher()
+            .send(PublishableMessage {
+                topic: "TPU/GPS/Speed".to_string(),
+                data: vec![1.5, 2.5],
+                unit: "m/s".to_string(),
+                time: 42,
+                options: None,
+            })
+            .await
+            .unwrap();
+        let msg = tokio::time::timeout(Duration::from_secs(1), received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.topic, "TPU/GPS/Speed");
+        assert_eq!(msg.values, vec![1.5, 2.5]);
+        assert_eq!(msg.unit, "m/s");
+        assert_eq!(msg.time_us, 42);
+    }
+}
@@ -5,6 +5,11 @@ use std::{fs, path::Path, time::Duration};
 use chrono::{DateTime, TimeZone, Utc};
 use reqwest::{Client, multipart};
 
+use crate::{
+    metrics::METRICS,
+    session::{ArtifactKind, mark_uploaded},
+};
+
 async fn upload_file(
     filepath: &Path,
     timestamp: String,
@@ -33,6 +38,18 @@ async fn upload_file(
     Ok(())
 }
 
+/// Count and mark a file as uploaded, so the storage module may delete it
+fn uploaded(path: &Path) {
+    METRICS.uploaded(
+        fs::metadata(path)
+            .map(|meta| meta.len())
+            .unwrap_or_default(),
+    );
+    if let Err(err) = mark_uploaded(path) {
+        eprintln!("Could not mark {path:?} as uploaded: {err}");
+    }
+}
+
 fn extract_timestamp(input: &str) -> Option<String> {
     // Split on the first '-' and parse the timestamp
     let raw_ts = input.split_once('-')?.1.trim();
@@ -46,19 +63,19 @@ fn extract_timestamp(input: &str) -> Option<String> {
 }
 
 pub fn upload_files(
-    output_folder: &str,
+    output_folder: &Path,
     scylla_url: &str,
     upload_logs: bool,
     upload_video: bool,
     upload_serial: bool,
 ) -> tokio::task::JoinHandle<()> {
-    let output_folder = output_folder.to_string();
+    let output_folder = output_folder.to_path_buf();
     let scylla_url = scylla_url.to_string();
 
     tokio::spawn(async move {
         let client = Client::new();
 
-        let entries = fs::read_dir(Path::new(&output_folder)).expect("Invalid data output folder!");
+        let entries = fs::read_dir(&output_folder).expect("Invalid data output folder!");
 
         for entry in entries {
             match entry {
@@ -86,11 +103,14 @@ pub fn upload_files(
                                     let file_name = file.file_name();
                                     let path = file.path();
 
-                                    if is_file && upload_logs && file_name == "data_dump.log" {
+                                    if is_file
+                                        && upload_logs
+                                        && file_name == ArtifactKind::DataLog.file_name()
+                                    {
                                         println!("Uploading file: {path:?}");
                                         let client = client.clone();
                                         let scylla_url = scylla_url.clone();
-                                        if let Err(err) = upload_file(
+                                        match upload_file(
                                             &path,
                                             "".to_string(),
                                             "",
@@ -99,13 +119,21 @@ pub fn upload_files(
                                         )
                                         .await
                                         {
-                                            eprintln!("Failed to send file to scylla: {err}");
+                                            Ok(()) => uploaded(&path),
+                                            Err(err) => {
+                                                METRICS.upload_failed();
+                                                eprintln!("Failed to send file to scylla: {err}")
+                                            }
                                         }
                                     } else if is_file
-                                        && ((upload_video && file_name == "ner24-frontcam.mp4")
+                                        && ((upload_video
+                                            && file_name == ArtifactKind::Video.file_name())
                                             || (upload_serial
-                                                && (file_name == "cerberus-dump.cap"
-                                                    || file_name == "shepherd-dump.cap")))
+                                                && (file_name
+                                                    == ArtifactKind::CerberusSerial.file_name()
+                                                    || file_name
+                                                        == ArtifactKind::ShepherdSerial
+                                                            .file_name())))
                                     {
                                         let client = client.clone();
                                         let scylla_url = scylla_url.clone();
@@ -115,7 +143,7 @@ pub fn upload_files(
                                                 if let Some(timestamp) =
                                                     extract_timestamp(directory_name)
                                                 {
-                                                    if let Err(err) = upload_file(
+                                                    match upload_file(
                                                         &path,
                                                         timestamp,
                                                         file_name,
@@ -125,9 +153,13 @@ pub fn upload_files(
                                                     )
                                                     .await
                                                     {
-                                                        eprintln!(
-                                                            "Failed to send file to scylla: {err}"
-                                                        );
+                                                        Ok(()) => uploaded(&path),
+                                                        Err(err) => {
+                                                            METRICS.upload_failed();
+                                                            eprintln!(
+                                                                "Failed to send file to scylla: {err}"
+                                                            )
+                                                        }
                                                     }
                                                 } else {
                                                     eprintln!("Could not extract timestamp");
@@ -8,6 +8,8 @@
 
 use std::{error::Error, process::Stdio, time::Duration};
 
+use futures_util::future::BoxFuture;
+
 use tokio::{
     process::{Child, Command},
     sync::watch::Receiver,
@@ -15,23 +17,64 @@ use tokio::{
 use tokio_util::sync::CancellationToken;
 use tracing::{info, warn};
 
-use crate::{HVTransition, SAVE_LOCATION};
+use crate::{
+    HVTransition,
+    health::{ModuleHealth, ModuleState},
+    module::{Module, ModuleContext, ModuleInputs, ModuleResult},
+    session::ArtifactKind,
+    sim,
+};
 
 pub struct SavePipelineOpts {
     /// the dev to read for video
     pub video: String,
 }
 
+/// The video module, see `run_save_pipeline`
+pub struct VideoModule {
+    video: String,
+}
+
+impl VideoModule {
+    pub fn new(video: String) -> Self {
+        Self { video }
+    }
+}
+
+impl Module for VideoModule {
+    fn name(&self) -> &'static str {
+        "Video"
+    }
+
+    fn inputs(&self) -> ModuleInputs {
+        ModuleInputs {
+            hv: true,
+            ..Default::default()
+        }
+    }
+
+    fn run(&self, mut ctx: ModuleContext) -> BoxFuture<'static, ModuleResult> {
+        let vid_opts = SavePipelineOpts {
+            video: self.video.clone(),
+        };
+        Box::pin(async move {
+            run_save_pipeline(ctx.cancel_token.clone(), ctx.hv()?, vid_opts, ctx.health).await
+        })
+    }
+}
+
 /// Run a save pipeline on the items
 pub async fn run_save_pipeline(
     cancel_token: CancellationToken,
     mut hv_stat_recv: Receiver<HVTransition>,
     vid_opts: SavePipelineOpts,
+    health: ModuleHealth,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     // ffmpeg -f video4linux2 -input_format mjpeg -s 1280x720 -i /dev/video0 -vf "drawtext=fontfile=FreeSerif.tff: \
     //text='%{localtime\:%T}': fontcolor=white@0.8: x=7: y=700" -vcodec libx264 -preset veryfast -f mp4 -pix_fmt yuv420p -y output.mp4
 
     let mut cmd: Option<Child> = None;
+    health.set_state(ModuleState::Running);
 
     loop {
         tokio::select! {
@@ -45,16 +88,12 @@ pub async fn run_save_pipeline(
             new = hv_stat_recv.changed() => {
                 new?;
 
-                let curr_data = *hv_stat_recv.borrow_and_update();
+                let curr_data = hv_stat_recv.borrow_and_update().clone();
             match curr_data {
-                HVTransition::TransitionOn(hvon_data) => {
-                    let save_location = format!(
-                        "{}/event-{}/ner24-frontcam.mp4",
-                        SAVE_LOCATION.get().unwrap(),
-                        hvon_data.time_ms
-                    );
+                HVTransition::TransitionOn(session) => {
+                    let save_location = session.artifact("Video", ArtifactKind::Video);
                     info!("Creating and launching ffmpeg...");
-                    let cmd_new = Command::new("ffmpeg").args([
+                    let cmd_new = sim::command("ffmpeg").args([
                         "-nostdin", "-y",
                         "-f", "v4l2",
                         "-framerate", "25",
@@ -70,10 +109,11 @@ pub async fn run_save_pipeline(
                         "-f", "tee", "-map", "0:v",
                         &format!(
                             "[f=mp4:onfail=ignore:use_fifo=1:fifo_format=rawvideo:fifo_options=drop=1]{}|[f=mpegts:onfail=ignore:use_fifo=1:fifo_format=rawvideo:fifo_options=drop=1]udp://192.168.100.11:7998?pkt_size=1316",
-                            save_location
+                            save_location.display()
                         ),
                     ]).stdin(Stdio::null()).spawn()?;
                     cmd = Some(cmd_new);
+                    health.work();
 
                 },
                     HVTransition::TransitionOff => {
@@ -1,229 +1,443 @@
-//! HELPER: Receive and send MQTT
+//! HELPER: Receive and send Zenoh
+//!
+//! The daemon declares the liveliness token `odysseus/<base_node>/alive` while connected, and
+//! `odysseus/<base_node>/alive/<module>` for each running module.  With `[zenoh] peer_alive`,
+//! `<base_node>/Link/PeerAlive/<node>` is published as 1 or 0 as other daemons come and go.
+//!
+//! Keys are put through declared publishers with the options of their `[[zenoh.rules]]`, the
+//! `MAX_PUBLISHERS` most recently used kept declared.  A message with `PublishableMessage::options`
+//! is put directly instead, with congestion control from its QoS: drop for 0, block otherwise.
+//!
+//! Up to `SAMPLES_CAPACITY` received samples wait to be handled, the rest dropped and counted in
+//! `metrics`.
 
 use std::{
-    path::PathBuf,
-    time::{SystemTime, UNIX_EPOCH},
+    collections::{BTreeSet, HashMap},
+    error::Error,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Instant, UNIX_EPOCH},
 };
 
-use protobuf::{Message, SpecialFields};
-use tokio::sync::{broadcast, mpsc::Receiver, watch::Sender};
-use tokio_util::sync::CancellationToken;
+use futures_util::future::BoxFuture;
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    watch,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tracing::{debug, info, trace, warn};
-use zenoh::{Config, Session, bytes::Encoding, sample::Sample};
+use zenoh::{
+    Config, Session,
+    bytes::Encoding,
+    liveliness::LivelinessToken,
+    pubsub::{Publisher, Subscriber},
+    qos,
+    sample::{Sample, SampleKind},
+};
 
 use crate::{
-    HV_EN_TOPIC, HVTransition, MUTE_EN_TOPIC, PublishableMessage, SAVE_LOCATION, SEND_LOGGER_DATA,
-    SEND_SERIAL_DATA, SEND_VIDEO_DATA, playback_data, serverdata, uploader::upload_files,
+    PublishableMessage,
+    config::{CongestionControl, Priority, PublishOptions, Qos, ZenohConfig, ZenohOptions},
+    lvc::{self, Lvc},
+    metrics::METRICS,
+    publish,
+    remap::{self, Remap},
+    transport::{Incoming, ORIGIN_KEY, Transport, TransportResult},
 };
 
-/// The chief processor of incoming zenoh data, this handles
-/// - zenoh state
-/// - reception via mqtt and subsequent parsing
-///   Takes in many channels:
-/// - zenoh_sender_rx: A receiver of any messages, it then publishes them
-/// - hv_stat_send: A sender of the current HV state (only if it changes!), will be set to ON if augment_hv_on is true
-/// - mute_stat_send: A sender of the current mute button state
-/// - zenoh_recv_tx: Optional, a sender of all zenoh messages, if None no messages sent
-pub struct ZenohProcessor {
+/// How many declared publishers are kept, the least recently used undeclared past it
+const MAX_PUBLISHERS: usize = 1024;
+
+/// How many received samples may wait to be handled
+const SAMPLES_CAPACITY: usize = 1000;
+
+/// The Zenoh priority and congestion control of options
+fn zenoh_qos(options: ZenohOptions) -> (qos::Priority, qos::CongestionControl) {
+    let priority = match options.priority {
+        Priority::RealTime => qos::Priority::RealTime,
+        Priority::InteractiveHigh => qos::Priority::InteractiveHigh,
+        Priority::InteractiveLow => qos::Priority::InteractiveLow,
+        Priority::DataHigh => qos::Priority::DataHigh,
+        Priority::Data => qos::Priority::Data,
+        Priority::DataLow => qos::Priority::DataLow,
+        Priority::Background => qos::Priority::Background,
+    };
+    let congestion_control = match options.congestion_control {
+        CongestionControl::Drop => qos::CongestionControl::Drop,
+        CongestionControl::Block => qos::CongestionControl::Block,
+    };
+    (priority, congestion_control)
+}
+
+/// The Zenoh transport, see `transport`
+pub struct ZenohTransport {
+    /// For the config file and the publish rules
+    conf: ZenohConfig,
+    session: OnceLock<Session>,
+    remap: Remap,
+    /// The last-value cache of the keys put, if enabled
+    lvc: Option<Arc<Lvc>>,
+    /// Declared on the first put of each key, with the options of its topic, and when last used
+    publishers: Mutex<HashMap<String, (Arc<Publisher<'static>>, Instant)>>,
+    /// Kept to stay subscribed, by filter, every subscriber sends to `samples`.
+    /// None for filters matching nothing sent to Zenoh
+    subscribers: Mutex<HashMap<String, Option<Subscriber<()>>>>,
+    samples_tx: mpsc::Sender<Sample>,
+    samples_rx: tokio::sync::Mutex<mpsc::Receiver<Sample>>,
+    /// Where the LVC queryable is served, until the daemon shuts down
+    task_tracker: TaskTracker,
     cancel_token: CancellationToken,
-    zenoh_sender_rx: Receiver<PublishableMessage>,
-    hv_stat_send: Sender<HVTransition>,
-    augment_hv_on: bool,
-    mute_stat_send: Sender<bool>,
-    zenoh_recv_tx: Option<broadcast::Sender<playback_data::PlaybackData>>,
-    scylla_url: Option<String>,
-    session: Session,
 }
 
-#[allow(clippy::too_many_arguments)]
-impl ZenohProcessor {
-    /// Creates a new mqtt receiver and sender
-    pub async fn new(
-        cancel_token: CancellationToken,
-        mqtt_sender_rx: Receiver<PublishableMessage>,
-        hv_stat_send: Sender<HVTransition>,
-        augment_hv_on: bool,
-        mute_stat_send: Sender<bool>,
-        mqtt_recv_tx: Option<broadcast::Sender<playback_data::PlaybackData>>,
-        conf_path: PathBuf,
-        scylla_url: Option<String>,
-    ) -> ZenohProcessor {
-        zenoh::init_log_from_env_or("info");
-
-        let session = zenoh::open(Config::from_file(conf_path).expect("Could not find Zenoh conf"))
-            .await
-            .expect("Invalid zenoh conf");
-
-        ZenohProcessor {
-            cancel_token,
-            zenoh_sender_rx: mqtt_sender_rx,
-            hv_stat_send,
-            augment_hv_on,
-            mute_stat_send,
-            zenoh_recv_tx: mqtt_recv_tx,
-            scylla_url,
-            session,
-        }
+impl ZenohTransport {
+    /// Creates a new zenoh transport, mapping topics per `[zenoh.remap]`
+    pub fn new(
+        conf: &ZenohConfig,
+        task_tracker: &TaskTracker,
+        cancel_token: &CancellationToken,
+    ) -> Result<ZenohTransport, String> {
+        let (samples_tx, samples_rx) = mpsc::channel(SAMPLES_CAPACITY);
+        Ok(ZenohTransport {
+            conf: conf.clone(),
+            session: OnceLock::new(),
+            remap: Remap::new(&conf.remap)?,
+            lvc: conf.lvc.then(|| Arc::new(Lvc::new(conf.lvc_key.clone()))),
+            publishers: Mutex::new(HashMap::new()),
+            subscribers: Mutex::new(HashMap::new()),
+            samples_tx,
+            samples_rx: tokio::sync::Mutex::new(samples_rx),
+            task_tracker: task_tracker.clone(),
+            cancel_token: cancel_token.clone(),
+        })
     }
 
-    fn convert_to_mqtt(sample: zenoh::sample::Sample) -> Option<PublishableMessage> {
-        let res = serverdata::ServerData::parse_from_reader(&mut sample.payload().reader()).ok()?;
+    fn session(&self) -> Result<&Session, String> {
+        self.session
+            .get()
+            .ok_or_else(|| "Zenoh not connected".to_string())
+    }
 
-        Some(PublishableMessage {
-            topic: sample.key_expr().to_string(),
-            data: res.values,
-            unit: res.unit,
-            time: res.time_us,
-        })
+    /// Put a payload on the key of the topic, unless it is not remapped
+    async fn put(
+        &self,
+        topic: &str,
+        payload: Vec<u8>,
+        overrides: Option<PublishOptions>,
+        attachment: Option<String>,
+    ) -> TransportResult {
+        let Some(key) = self.remap.to_zenoh(topic) else {
+            trace!("Not publishing {} on Zenoh, as it is not remapped", topic);
+            return Ok(());
+        };
+        if let Some(lvc) = &self.lvc {
+            lvc.update(&key, &payload);
+        }
+        let Some(overrides) = overrides else {
+            self.publisher(topic, key)
+                .await?
+                .put(payload)
+                .attachment(attachment)
+                .await?;
+            return Ok(());
+        };
+        // rare enough to not need a publisher of its own
+        let mut options = self.conf.options(topic);
+        options.congestion_control = match overrides.qos {
+            Qos::AtMostOnce => CongestionControl::Drop,
+            Qos::AtLeastOnce | Qos::ExactlyOnce => CongestionControl::Block,
+        };
+        let (priority, congestion_control) = zenoh_qos(options);
+        self.session()?
+            .put(key, payload)
+            .encoding(Encoding::APPLICATION_PROTOBUF)
+            .priority(priority)
+            .congestion_control(congestion_control)
+            .express(options.express)
+            .attachment(attachment)
+            .await?;
+        Ok(())
     }
 
-    async fn handle_recv(&self, sample: Sample, last_stat: &mut bool) {
-        let Some(msg) = Self::convert_to_mqtt(sample) else {
-            warn!("Could not deserialize Zenoh incoming!");
+    /// The publisher of a key, declared with the options of its topic if new
+    async fn publisher(
+        &self,
+        topic: &str,
+        key: String,
+    ) -> Result<Arc<Publisher<'static>>, Box<dyn Error + Send + Sync>> {
+        if let Some((publisher, used)) = self.publishers.lock().unwrap().get_mut(&key) {
+            *used = Instant::now();
+            return Ok(publisher.clone());
+        }
+        let options = self.conf.options(topic);
+        let (priority, congestion_control) = zenoh_qos(options);
+        let publisher = self
+            .session()?
+            .declare_publisher(key.clone())
+            .encoding(Encoding::APPLICATION_PROTOBUF)
+            .priority(priority)
+            .congestion_control(congestion_control)
+            .express(options.express)
+            .await?;
+        let mut publishers = self.publishers.lock().unwrap();
+        if publishers.len() >= MAX_PUBLISHERS
+            && !publishers.contains_key(&key)
+            && let Some(oldest) = publishers
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| key.clone())
+        {
+            trace!(
+                "Undeclaring the publisher of {}, least recently used",
+                oldest
+            );
+            publishers.remove(&oldest);
+        }
+        // another put may have declared it meanwhile, keep only one
+        Ok(publishers
+            .entry(key)
+            .or_insert_with(|| (Arc::new(publisher), Instant::now()))
+            .0
+            .clone())
+    }
+}
+
+/// The liveliness key of a daemon
+fn alive_key(node: &str) -> String {
+    format!("odysseus/{}/alive", remap::escape(node))
+}
+
+/// Declare the liveliness tokens of the daemon and its running modules until cancelled
+pub async fn liveliness(
+    cancel_token: CancellationToken,
+    zenoh: Arc<ZenohTransport>,
+    base_node: String,
+    mut modules: watch::Receiver<BTreeSet<String>>,
+) {
+    let Ok(session) = zenoh.session() else {
+        warn!("Cannot declare liveliness, Zenoh is not connected");
+        return;
+    };
+    let node_key = alive_key(&base_node);
+    // undeclared on drop
+    let _node_token = match session.liveliness().declare_token(&node_key).await {
+        Ok(token) => token,
+        Err(err) => {
+            warn!("Could not declare liveliness token {}: {}", node_key, err);
             return;
-        };
-        let val = *msg.data.first().unwrap_or(&-1f32) as u8;
-        match msg.topic.as_str() {
-            HV_EN_TOPIC => {
-                if !self.augment_hv_on {
-                    // ensure only triggering upon change from previous loop
-                    if val == 1 && !*last_stat {
-                        debug!("Transitioning states to HV on, creating folder!");
-                        if let Err(err) = std::fs::create_dir(format!(
-                            "{}/event-{}",
-                            SAVE_LOCATION.get().unwrap(),
-                            msg.time / 1000
-                        )) {
-                            warn!(
-                                "Could not create folder for data, bailing out of this loop! {}",
-                                err
-                            );
-                            return;
-                        }
-                        self.hv_stat_send
-                            .send(HVTransition::TransitionOn(crate::HVOnData {
-                                time_ms: msg.time / 1000,
-                            }))
-                            .expect("HV Stat Channel Closed");
-                        *last_stat = true;
-                    } else if val == 0 && *last_stat {
-                        debug!("Transitioning states to HV off");
-                        self.hv_stat_send
-                            .send(HVTransition::TransitionOff)
-                            .expect("HV Stat Channel Closed");
-                        *last_stat = false;
-                    } else if val != 0 && val != 1 {
-                        warn!("Received bad HV message!");
-                    }
-                }
+        }
+    };
+    let mut tokens: HashMap<String, LivelinessToken> = HashMap::new();
+    loop {
+        let names = modules.borrow_and_update().clone();
+        tokens.retain(|name, _| names.contains(name));
+        for name in names {
+            if tokens.contains_key(&name) {
+                continue;
             }
-            MUTE_EN_TOPIC => {
-                // mute button messages should be single shot
-                if val == 1 {
-                    self.mute_stat_send
-                        .send(true)
-                        .expect("Mute Stat Channel Closed");
-                } else if val == 0 {
-                    self.mute_stat_send
-                        .send(false)
-                        .expect("Mute Stat Channel Closed");
-                } else {
-                    warn!("Received bad mute message!");
+            let key = format!("{}/{}", node_key, remap::escape(&name));
+            match session.liveliness().declare_token(&key).await {
+                Ok(token) => {
+                    tokens.insert(name, token);
                 }
+                Err(err) => warn!("Could not declare liveliness token {}: {}", key, err),
             }
-            SEND_LOGGER_DATA => {
-                if !*last_stat && let Some(url) = &self.scylla_url {
-                    info!("Sending Logger Data, {}", val);
+        }
+        tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            changed = modules.changed() => if changed.is_err() {
+                cancel_token.cancelled().await;
+                break;
+            },
+        }
+    }
+    debug!("Shutting down liveliness");
+}
 
-                    upload_files(SAVE_LOCATION.get().unwrap(), url, true, false, false);
-                }
-            }
-            SEND_SERIAL_DATA => {
-                if !*last_stat && let Some(url) = &self.scylla_url {
-                    info!("Sending Serial Data, {}", val);
+/// Publish `<base_node>/Link/PeerAlive/<node>` as other daemons come and go, until cancelled
+pub async fn peer_alive(
+    cancel_token: CancellationToken,
+    zenoh: Arc<ZenohTransport>,
+    base_node: String,
+    publisher: publish::Publisher,
+) {
+    let Ok(session) = zenoh.session() else {
+        warn!("Cannot watch peers, Zenoh is not connected");
+        return;
+    };
+    // with history, so the peers already alive are published too
+    let subscriber = match session
+        .liveliness()
+        .declare_subscriber("odysseus/*/alive")
+        .history(true)
+        .await
+    {
+        Ok(subscriber) => subscriber,
+        Err(err) => {
+            warn!("Could not watch peers: {}", err);
+            return;
+        }
+    };
+    loop {
+        let sample = tokio::select! {
+            _ = cancel_token.cancelled() => break,
+            sample = subscriber.recv_async() => match sample {
+                Ok(sample) => sample,
+                Err(_) => break,
+            },
+        };
+        let Some(node) = sample
+            .key_expr()
+            .as_str()
+            .split('/')
+            .nth(1)
+            .and_then(remap::unescape)
+        else {
+            continue;
+        };
+        if node == base_node {
+            continue;
+        }
+        let alive = sample.kind() == SampleKind::Put;
+        info!(
+            "Peer {} {}",
+            node,
+            if alive { "came up" } else { "went away" }
+        );
+        let msg = PublishableMessage {
+            topic: format!("{base_node}/Link/PeerAlive/{node}"),
+            data: vec![if alive { 1.0 } else { 0.0 }],
+            unit: "bool".to_string(),
+            time: UNIX_EPOCH.elapsed().unwrap().as_micros() as u64,
+            options: None,
+        };
+        if let Err(err) = publisher.send(msg).await {
+            warn!("Could not publish peer {}: {}", node, err);
+        }
+    }
+    debug!("Shutting down peer watch");
+}
 
-                    upload_files(SAVE_LOCATION.get().unwrap(), url, false, false, true);
-                }
+impl Transport for ZenohTransport {
+    fn connect(&self) -> BoxFuture<'_, TransportResult> {
+        Box::pin(async {
+            zenoh::init_log_from_env_or("info");
+            let session = zenoh::open(Config::from_file(&self.conf.conf)?).await?;
+            if let Some(lvc) = &self.lvc {
+                let queryable = session.declare_queryable(lvc.key_expr()).await?;
+                self.task_tracker.spawn(lvc::serve(
+                    self.cancel_token.clone(),
+                    queryable,
+                    lvc.clone(),
+                ));
             }
-            SEND_VIDEO_DATA => {
-                if !*last_stat && let Some(url) = &self.scylla_url {
-                    info!("Sending Video Data, {}", val);
+            self.session
+                .set(session)
+                .map_err(|_| "Zenoh already connected")?;
+            Ok(())
+        })
+    }
 
-                    upload_files(SAVE_LOCATION.get().unwrap(), url, false, true, false);
+    fn subscribe<'a>(&'a self, filter: &'a str) -> BoxFuture<'a, TransportResult> {
+        Box::pin(async move {
+            let subscriber = match self.remap.filter_to_zenoh(filter) {
+                Some(key_expr) => {
+                    let samples_tx = self.samples_tx.clone();
+                    let subscriber = self
+                        .session()?
+                        .declare_subscriber(key_expr)
+                        .callback(move |sample| {
+                            // closed only once the transport is dropped
+                            if let Err(TrySendError::Full(sample)) = samples_tx.try_send(sample) {
+                                METRICS.zenoh_dropped();
+                                trace!(
+                                    "Dropped Zenoh sample {}, too many waiting",
+                                    sample.key_expr()
+                                );
+                            }
+                        })
+                        .await?;
+                    Some(subscriber)
                 }
-            }
-            _ => {}
-        }
-        // if using it, send all mqtt messages to data logger
-        if let Some(ref recv) = self.zenoh_recv_tx
-            && let Err(err) = recv.send(playback_data::PlaybackData {
-                topic: msg.topic.to_string(),
-                values: msg.data,
-                unit: msg.unit,
-                time_us: msg.time,
-                special_fields: SpecialFields::new(),
-            })
-        {
-            warn!("Error sending message received! {}", err);
-        }
+                None => {
+                    debug!(
+                        "Not subscribing to {} on Zenoh, as it is not remapped",
+                        filter
+                    );
+                    None
+                }
+            };
+            self.subscribers
+                .lock()
+                .unwrap()
+                .insert(filter.to_string(), subscriber);
+            Ok(())
+        })
     }
 
-    /// This handles the reception of mqtt messages, will not return
-    pub async fn process_zenoh(mut self) {
-        debug!("Subscribing to siren, all topics");
-        let subscriber = self
-            .session
-            .declare_subscriber("**")
-            .await
-            .expect("Could not subscribe to MQTT");
-
-        // if augment HV on, send as such, otherwise start default off
-        let mut last_stat = if self.augment_hv_on {
-            let time = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as u64;
-            warn!("HV status permanently set on!!");
-            if let Err(err) =
-                std::fs::create_dir(format!("{}/event-{}", SAVE_LOCATION.get().unwrap(), time))
-            {
-                panic!("Could not create folder for data, bailing out of this loop! {err}");
+    fn unsubscribe<'a>(&'a self, filter: &'a str) -> BoxFuture<'a, TransportResult> {
+        Box::pin(async move {
+            let subscriber = self.subscribers.lock().unwrap().remove(filter);
+            match subscriber {
+                Some(Some(subscriber)) => Ok(subscriber.undeclare().await?),
+                Some(None) => Ok(()),
+                None => Err(format!("Not subscribed to {filter}").into()),
             }
-            self.hv_stat_send
-                .send(HVTransition::TransitionOn(crate::HVOnData {
-                    time_ms: time,
-                }))
-                .expect("HV Stat Channel Closed");
-            true
-        } else {
-            false
-        };
+        })
+    }
+
+    fn publish<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: Vec<u8>,
+        options: Option<PublishOptions>,
+    ) -> BoxFuture<'a, TransportResult> {
+        Box::pin(self.put(topic, payload, options, None))
+    }
+
+    fn forward<'a>(
+        &'a self,
+        topic: &'a str,
+        payload: Vec<u8>,
+        origin: &'a str,
+    ) -> BoxFuture<'a, TransportResult> {
+        Box::pin(self.put(topic, payload, None, Some(format!("{ORIGIN_KEY}={origin}"))))
+    }
 
-        loop {
-            tokio::select! {
-                _ = self.cancel_token.cancelled() => {
-                    debug!("Shutting down Zenoh processor!");
-                    break;
-                },
-                Ok(msg) = subscriber.recv_async() => {
-                        self.handle_recv(msg, &mut last_stat).await;
-                },
-                Some(sendable) = self.zenoh_sender_rx.recv() => {
-                    trace!("Sending {:?}", sendable);
-                    let mut payload = serverdata::ServerData::new();
-                    payload.unit = sendable.unit.to_string();
-                    payload.values = sendable.data;
-                    payload.time_us =  sendable.time;
-                    let Ok(bytes) = protobuf::Message::write_to_bytes(&payload) else {
-                        warn!("Failed to serialize protobuf message!");
-                        continue;
-                    };
-
-                    if let Err(err)= self.session.put(sendable.topic, bytes).encoding(Encoding::APPLICATION_PROTOBUF).await {
-                        warn!("Error sending zenoh message: {}", err);
+    fn recv(&self) -> BoxFuture<'_, Option<Incoming>> {
+        Box::pin(async {
+            let mut samples_rx = self.samples_rx.lock().await;
+            loop {
+                let sample = samples_rx.recv().await?;
+                let key = sample.key_expr().as_str();
+                match self.remap.to_mqtt(key) {
+                    Some(topic) => {
+                        let origin = sample
+                            .attachment()
+                            .and_then(|attachment| attachment.try_to_string().ok())
+                            .and_then(|attachment| {
+                                attachment
+                                    .strip_prefix(ORIGIN_KEY)?
+                                    .strip_prefix('=')
+                                    .map(str::to_string)
+                            });
+                        return Some(Incoming::Message {
+                            topic,
+                            payload: sample.payload().to_bytes().to_vec(),
+                            origin,
+                        });
                     }
+                    None => trace!("Dropping {} from Zenoh, as it is not remapped", key),
                 }
             }
-        }
+        })
+    }
+
+    fn connected(&self) -> BoxFuture<'_, bool> {
+        Box::pin(async {
+            let Ok(session) = self.session() else {
+                return false;
+            };
+            let info = session.info();
+            info.routers_zid().await.next().is_some() || info.peers_zid().await.next().is_some()
+        })
     }
 }